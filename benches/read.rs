@@ -0,0 +1,36 @@
+use std::fs;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use tempfile::TempDir;
+
+use psi::{Psi, PsiKind, PsiReader};
+
+const CONTENTS: &str = "some avg10=0.16 avg60=0.05 avg300=0.01 total=27787674\n\
+                        full avg10=0.02 avg60=0.00 avg300=0.00 total=1234567\n";
+
+fn read_benchmark(c: &mut Criterion) {
+    let dir = TempDir::new().unwrap();
+    fs::write(PsiKind::Memory.cgroup_file_path(dir.path()), CONTENTS).unwrap();
+
+    let mut group = c.benchmark_group("read");
+    group.bench_function("read_cgroup_psi", |b| {
+        b.iter(|| {
+            PsiKind::Memory
+                .read_cgroup_psi(black_box(dir.path()))
+                .unwrap()
+        })
+    });
+    let reader = PsiReader::cgroup(PsiKind::Memory, dir.path()).unwrap();
+    group.bench_function("psi_reader", |b| b.iter(|| reader.read().unwrap()));
+    group.finish();
+}
+
+fn parse_benchmark(c: &mut Criterion) {
+    let line = CONTENTS.lines().next().unwrap();
+    c.bench_function("parse_line", |b| {
+        b.iter(|| black_box(line).parse::<Psi>().unwrap())
+    });
+}
+
+criterion_group!(benches, read_benchmark, parse_benchmark);
+criterion_main!(benches);
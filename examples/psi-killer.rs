@@ -2,56 +2,79 @@ extern crate log;
 extern crate psi;
 extern crate simplelog;
 
-use std::fs::OpenOptions;
-use std::io::Write;
+use std::env;
+use std::path::PathBuf;
+use std::process;
 use std::time::Duration;
 
 use log::*;
 use simplelog::*;
 
-use psi::error::*;
-use psi::*;
+use psi::killer::*;
+use psi::trigger::TriggerThreshold;
 
-const SYSRQ_TRIGGER_PATH: &'static str = "/proc/sysrq-trigger";
+const USAGE: &str = "usage: psi-killer [--dry-run] [--protect CGROUP]... \
+                     [--stall MS] [--window MS] [--cooldown SECS] [CGROUP]...";
 
-pub fn low_mem_handler() -> Result<()> {
-    Ok(())
+fn parse_args() -> std::result::Result<OomKillerConfig, String> {
+    let mut config = OomKillerConfig::default();
+    let mut watched = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("{} requires a value", name));
+        match arg.as_str() {
+            "--dry-run" => config.dry_run = true,
+            "--protect" => config.protected.push(PathBuf::from(value(&arg)?)),
+            "--stall" => {
+                config.threshold.stall = parse_millis(&value(&arg)?)?;
+            }
+            "--window" => {
+                config.threshold.window = parse_millis(&value(&arg)?)?;
+            }
+            "--cooldown" => {
+                let secs = value(&arg)?;
+                let secs = secs
+                    .parse()
+                    .map_err(|_| format!("invalid seconds '{}'", secs))?;
+                config.kill_cooldown = Duration::from_secs(secs);
+            }
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}\n{}", arg, USAGE)),
+            _ => watched.push(PathBuf::from(arg)),
+        }
+    }
+    if !watched.is_empty() {
+        config.watched = watched;
+    }
+    Ok(config)
 }
 
-pub fn trigger_oom_killer() -> Result<()> {
-    warn!("triggering oom killer");
-    let mut sysrq = OpenOptions::new().write(true).open(SYSRQ_TRIGGER_PATH)?;
-    Ok(sysrq.write_all(&['f' as u8])?)
+fn parse_millis(s: &str) -> std::result::Result<Duration, String> {
+    s.parse()
+        .map(Duration::from_millis)
+        .map_err(|_| format!("invalid milliseconds '{}'", s))
 }
 
-fn main() -> Result<()> {
-    SimpleLogger::init(LevelFilter::Trace, Config::default())?;
-    let mut monitor = PsiMonitor::new()?;
-    let low_mem_id = monitor.add_trigger(
-        Trigger::new_builder()
-            .memory()
-            .full()
-            .stall(Duration::from_millis(50))
-            .window(Duration::from_millis(500))
-            .build(),
-    )?;
-    let oom_id = monitor.add_trigger(
-        Trigger::new_builder()
-            .memory()
-            .full()
-            .stall(Duration::from_millis(100))
-            .window(Duration::from_millis(500))
-            .build(),
-    )?;
-    loop {
-        let psi_event = monitor.wait_single()?;
-        debug!("{:?}", psi_event.stats);
-        if oom_id == psi_event.id {
-            info!("out of memory threshold reached");
-            trigger_oom_killer()?;
-        } else if low_mem_id == psi_event.id {
-            info!("low memory threshold reached");
-            low_mem_handler()?;
+fn main() {
+    let config = match parse_args() {
+        Ok(config) => config,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            process::exit(2);
         }
+    };
+    SimpleLogger::init(LevelFilter::Info, Config::default()).expect("logger already set");
+
+    let TriggerThreshold { stall, window } = &config.threshold;
+    info!(
+        "watching {:?} for {}ms memory full stall in {}ms{}",
+        config.watched,
+        stall.as_millis(),
+        window.as_millis(),
+        if config.dry_run { " (dry run)" } else { "" }
+    );
+    if let Err(e) = OomKiller::new(config).run() {
+        error!("{}", e);
+        process::exit(1);
     }
 }
@@ -0,0 +1,13 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use psi::{AllPsiStats, PsiParser};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = AllPsiStats::from_reader(data);
+    if let Ok(s) = std::str::from_utf8(data) {
+        let _ = s.parse::<AllPsiStats>();
+        let _ = PsiParser::strict().parse_all(s);
+        let _ = PsiParser::lenient().parse_all(s);
+    }
+});
@@ -0,0 +1,12 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use psi::{Psi, PsiParser};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(s) = std::str::from_utf8(data) {
+        let _ = s.parse::<Psi>();
+        let _ = PsiParser::strict().parse_line(s);
+        let _ = PsiParser::lenient().parse_line(s);
+    }
+});
@@ -0,0 +1,558 @@
+//! Pressure-aware backoff for batch work
+//!
+//! Batch workers can yield to latency-sensitive work while the host is under
+//! pressure. [`wait_until_pressure_below`] blocks until pressure drops, and
+//! [`PressureBackoff`] yields how long to back off before each unit of work:
+//! nothing while pressure is below the threshold, growing exponentially for
+//! as long as it stays above.
+//!
+//! With the `monitor` feature a trigger can stand in for reading pressure
+//! before every unit of work; pressure is only read once the trigger fires.
+//! [`wait_until_pressure_below`] likewise waits for its trigger to go quiet
+//! rather than re-reading pressure every couple of seconds.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::*;
+
+use crate::error::*;
+use crate::history::{percent, PsiSample};
+#[cfg(feature = "monitor")]
+use crate::monitor::{PsiMonitor, TriggerMonitor};
+use crate::psi::*;
+use crate::source::{PsiSource, SystemPsi};
+#[cfg(feature = "monitor")]
+use crate::trigger::{stall_for, Trigger};
+
+/// How often pressure is re-read while waiting; `avg10` is only updated by
+/// the kernel every two seconds
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Window of the trigger [`wait_until_pressure_below`] waits on, matching
+/// `avg10`
+#[cfg(feature = "monitor")]
+const WAIT_TRIGGER_WINDOW: Duration = Duration::from_secs(10);
+
+/// Block until `avg10` of a pressure line drops below `threshold`
+///
+/// Returns `false` if pressure was still at or above the threshold when
+/// `timeout` elapsed. With the `monitor` feature a trigger is registered at
+/// the threshold and pressure is only re-read once a window passes without
+/// it firing; if the kernel refuses the trigger, pressure is polled.
+pub fn wait_until_pressure_below(
+    kind: PsiKind,
+    line: PsiLine,
+    threshold: PsiPercent,
+    timeout: Duration,
+) -> Result<bool> {
+    #[cfg(feature = "monitor")]
+    {
+        let trigger = trigger(kind, line, threshold, WAIT_TRIGGER_WINDOW);
+        if let Some(mut monitor) = register_trigger(trigger) {
+            let wait = wait_quiet(&mut monitor, WAIT_TRIGGER_WINDOW);
+            return wait_below(&mut SystemPsi, wait, kind, line, threshold, timeout);
+        }
+    }
+    wait_below(&mut SystemPsi, poll, kind, line, threshold, timeout)
+}
+
+/// Read pressure until it is below `threshold`, calling `wait` with the time
+/// remaining in between
+fn wait_below<S, W>(
+    source: &mut S,
+    mut wait: W,
+    kind: PsiKind,
+    line: PsiLine,
+    threshold: PsiPercent,
+    timeout: Duration,
+) -> Result<bool>
+where
+    S: PsiSource,
+    W: FnMut(Duration) -> Result<()>,
+{
+    let deadline = Instant::now() + timeout;
+    loop {
+        let avg10 = source.read_psi_line(kind, line)?.avg10;
+        if avg10 < threshold {
+            return Ok(true);
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining == Duration::from_secs(0) {
+            return Ok(false);
+        }
+        debug!("{} {} pressure at {}%; waiting", kind, line, avg10);
+        wait(remaining)?;
+    }
+}
+
+fn poll(remaining: Duration) -> Result<()> {
+    thread::sleep(remaining.min(POLL_INTERVAL));
+    Ok(())
+}
+
+/// Wait for a trigger to stay quiet for a whole `window`, then poll
+///
+/// `avg10` lags behind the stall time, so it may still be above the threshold
+/// once the trigger goes quiet.
+#[cfg(feature = "monitor")]
+fn wait_quiet<M: TriggerMonitor>(
+    monitor: &mut M,
+    window: Duration,
+) -> impl FnMut(Duration) -> Result<()> + '_ {
+    let mut quiet = false;
+    move |remaining| {
+        if quiet {
+            return poll(remaining);
+        }
+        let deadline = Instant::now() + remaining;
+        loop {
+            let timeout = deadline
+                .saturating_duration_since(Instant::now())
+                .min(window);
+            if monitor.wait_timeout(timeout)?.is_none() {
+                quiet = timeout == window;
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// What backoff is computed from
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BackoffSignal {
+    /// The kernel's 10 second average
+    Avg10,
+    /// Share of time stalled since the previous read, which reacts faster
+    /// than `avg10`
+    StallDelta,
+}
+
+/// Pressure backoff configuration
+#[derive(Debug, Clone)]
+pub struct PressureBackoffConfig {
+    pub kind: PsiKind,
+    pub line: PsiLine,
+    /// `avg10` or stall delta at and above which work backs off
+    pub threshold: PsiPercent,
+    pub signal: BackoffSignal,
+    /// Backoff on first reaching the threshold; doubles each consecutive time
+    pub base: Duration,
+    /// Longest backoff
+    pub max: Duration,
+    /// Trigger window used to detect pressure instead of reading it before
+    /// every unit of work
+    ///
+    /// The trigger stall is derived from `threshold`. If the kernel refuses
+    /// the trigger, pressure is read every time.
+    pub trigger_window: Option<Duration>,
+}
+
+impl Default for PressureBackoffConfig {
+    fn default() -> Self {
+        PressureBackoffConfig {
+            kind: PsiKind::Memory,
+            line: PsiLine::Some,
+            threshold: PsiPercent::from_hundredths(1000),
+            signal: BackoffSignal::Avg10,
+            base: Duration::from_millis(100),
+            max: Duration::from_secs(30),
+            trigger_window: None,
+        }
+    }
+}
+
+impl PressureBackoffConfig {
+    pub fn new(kind: PsiKind, line: PsiLine, threshold: PsiPercent) -> Self {
+        PressureBackoffConfig {
+            kind,
+            line,
+            threshold,
+            ..Default::default()
+        }
+    }
+
+    pub fn signal(mut self, signal: BackoffSignal) -> Self {
+        self.signal = signal;
+        self
+    }
+
+    pub fn base(mut self, base: Duration) -> Self {
+        self.base = base;
+        self
+    }
+
+    pub fn max(mut self, max: Duration) -> Self {
+        self.max = max;
+        self
+    }
+
+    pub fn trigger_window(mut self, trigger_window: Duration) -> Self {
+        self.trigger_window = Some(trigger_window);
+        self
+    }
+}
+
+/// Iterator of backoff durations, one per unit of work
+///
+/// Yields zero while pressure is below the threshold. [`wait`](Self::wait)
+/// sleeps for the next backoff.
+pub struct PressureBackoff<S = SystemPsi> {
+    config: PressureBackoffConfig,
+    source: S,
+    previous: Option<PsiSample>,
+    /// Consecutive reads at or above the threshold
+    consecutive: u32,
+    #[cfg(feature = "monitor")]
+    monitor: Option<Box<dyn TriggerMonitor + Send + Sync>>,
+    #[cfg(feature = "monitor")]
+    last_event: Option<Instant>,
+}
+
+impl PressureBackoff<SystemPsi> {
+    /// Back off on system-wide pressure, registering a trigger if configured
+    pub fn new(config: PressureBackoffConfig) -> Self {
+        let backoff = Self::with_source(config, SystemPsi);
+        #[cfg(feature = "monitor")]
+        let backoff = match backoff.config.trigger_window {
+            None => backoff,
+            Some(_) => match PsiMonitor::new() {
+                Ok(monitor) => backoff.with_monitor(monitor),
+                Err(e) => {
+                    warn!("unable to create backoff monitor: {}", e);
+                    backoff
+                }
+            },
+        };
+        backoff
+    }
+}
+
+impl<S: PsiSource> PressureBackoff<S> {
+    /// Back off on pressure read from any source
+    pub fn with_source(config: PressureBackoffConfig, source: S) -> Self {
+        PressureBackoff {
+            config,
+            source,
+            previous: None,
+            consecutive: 0,
+            #[cfg(feature = "monitor")]
+            monitor: None,
+            #[cfg(feature = "monitor")]
+            last_event: None,
+        }
+    }
+
+    pub fn config(&self) -> &PressureBackoffConfig {
+        &self.config
+    }
+
+    /// Register the configured trigger with `monitor` and only read pressure
+    /// once it fires
+    ///
+    /// Pressure is read every time if no `trigger_window` is configured or
+    /// the trigger is refused.
+    #[cfg(feature = "monitor")]
+    pub fn with_monitor<M: TriggerMonitor + Send + Sync + 'static>(
+        mut self,
+        mut monitor: M,
+    ) -> Self {
+        let config = &self.config;
+        let trigger = match config.trigger_window {
+            Some(window) => trigger(config.kind, config.line, config.threshold, window),
+            None => return self,
+        };
+        match monitor.add_trigger(trigger) {
+            Ok(_) => self.monitor = Some(Box::new(monitor)),
+            Err(e) => warn!(
+                "unable to register backoff trigger, reading pressure instead: {}",
+                e
+            ),
+        }
+        self
+    }
+
+    /// Sleep for the next backoff, returning how long was slept
+    pub fn wait(&mut self) -> Result<Duration> {
+        let backoff = self.next_at(Instant::now())?;
+        if backoff > Duration::from_secs(0) {
+            debug!("backing off for {:?}", backoff);
+            thread::sleep(backoff);
+        }
+        Ok(backoff)
+    }
+
+    /// The next backoff, as if read at `now`
+    pub fn next_at(&mut self, now: Instant) -> Result<Duration> {
+        if !self.may_be_pressured(now)? {
+            self.consecutive = 0;
+            return Ok(Duration::from_secs(0));
+        }
+        match self.pressure_at(now)? {
+            Some(pressure) if pressure >= self.config.threshold => {
+                self.consecutive = self.consecutive.saturating_add(1);
+                Ok(self.backoff(pressure))
+            }
+            _ => {
+                self.consecutive = 0;
+                Ok(Duration::from_secs(0))
+            }
+        }
+    }
+
+    /// `base`, doubled per consecutive time above the threshold and scaled by
+    /// how far above it pressure is
+    fn backoff(&self, pressure: PsiPercent) -> Duration {
+        let threshold = self.config.threshold.as_f64().max(0.01);
+        let doublings = (self.consecutive - 1).min(32) as i32;
+        let factor = 2f64.powi(doublings) * (pressure.as_f64() / threshold).max(1.0);
+        let backoff = self.config.base.as_secs_f64() * factor;
+        if backoff >= self.config.max.as_secs_f64() {
+            self.config.max
+        } else {
+            Duration::from_secs_f64(backoff)
+        }
+    }
+
+    /// Pressure by the configured signal; `None` for the first stall delta
+    fn pressure_at(&mut self, now: Instant) -> Result<Option<PsiPercent>> {
+        let psi = self
+            .source
+            .read_psi_line(self.config.kind, self.config.line)?;
+        match self.config.signal {
+            BackoffSignal::Avg10 => Ok(Some(psi.avg10)),
+            BackoffSignal::StallDelta => {
+                let sample = PsiSample {
+                    time: now,
+                    total: psi.total,
+                };
+                Ok(match self.previous.replace(sample) {
+                    Some(previous) if previous.time < now && previous.total <= psi.total => {
+                        Some(percent(&previous, &sample))
+                    }
+                    _ => None,
+                })
+            }
+        }
+    }
+
+    /// `false` if a registered trigger shows pressure is below the threshold
+    #[cfg(feature = "monitor")]
+    fn may_be_pressured(&mut self, now: Instant) -> Result<bool> {
+        let monitor = match self.monitor.as_mut() {
+            Some(monitor) => monitor,
+            None => return Ok(true),
+        };
+        while monitor.wait_timeout(Duration::from_secs(0))?.is_some() {
+            self.last_event = Some(now);
+        }
+        // triggers fire at most once per window, so pressure is only known to
+        // have cleared once a window passes without an event
+        let window = self.config.trigger_window.unwrap_or_default();
+        let recent = matches!(self.last_event, Some(last) if now.duration_since(last) < window * 2);
+        Ok(recent || self.consecutive > 0)
+    }
+
+    #[cfg(not(feature = "monitor"))]
+    fn may_be_pressured(&mut self, _now: Instant) -> Result<bool> {
+        Ok(true)
+    }
+}
+
+impl<S: PsiSource> Iterator for PressureBackoff<S> {
+    type Item = Result<Duration>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.next_at(Instant::now()))
+    }
+}
+
+/// Trigger firing when stall time within `window` reaches `threshold`
+#[cfg(feature = "monitor")]
+fn trigger(kind: PsiKind, line: PsiLine, threshold: PsiPercent, window: Duration) -> Trigger {
+    Trigger::new_builder()
+        .kind(kind)
+        .line(line)
+        .stall(stall_for(threshold, window))
+        .window(window)
+        .build()
+}
+
+#[cfg(feature = "monitor")]
+fn register_trigger(trigger: Trigger) -> Option<PsiMonitor> {
+    let mut monitor = match PsiMonitor::new() {
+        Ok(monitor) => monitor,
+        Err(e) => {
+            debug!("unable to create monitor, polling instead: {}", e);
+            return None;
+        }
+    };
+    match monitor.add_trigger(trigger) {
+        Ok(_) => Some(monitor),
+        Err(e) => {
+            debug!("unable to register trigger, polling instead: {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::{MockClock, ReplaySource};
+
+    fn ten_percent() -> PsiPercent {
+        PsiPercent::from_hundredths(1000)
+    }
+
+    fn source(avg10s: &[u32]) -> ReplaySource {
+        let clock = MockClock::new();
+        let mut source = ReplaySource::new(clock);
+        for (sec, avg10) in avg10s.iter().enumerate() {
+            source.push(
+                Duration::from_secs(sec as u64),
+                PsiKind::IO,
+                Psi {
+                    line: PsiLine::Some,
+                    avg10: PsiPercent::from_hundredths(avg10 * 100),
+                    avg60: PsiPercent::ZERO,
+                    avg300: PsiPercent::ZERO,
+                    total: Duration::from_millis(sec as u64 * 200),
+                },
+            );
+        }
+        source
+    }
+
+    #[test]
+    fn should_back_off_exponentially_on_avg10() {
+        let config = PressureBackoffConfig::new(PsiKind::IO, PsiLine::Some, ten_percent())
+            .base(Duration::from_millis(100))
+            .max(Duration::from_millis(500));
+        let clock_source = source(&[5, 10, 20, 20, 20, 5]);
+        let clock = clock_source.clock().clone();
+        let mut backoff = PressureBackoff::with_source(config, clock_source);
+        let start = Instant::now();
+        let mut next = || {
+            let now = start + clock.now();
+            let backoff = backoff.next_at(now).unwrap().as_millis();
+            clock.advance(Duration::from_secs(1));
+            backoff
+        };
+        assert_eq!(next(), 0);
+        assert_eq!(next(), 100);
+        // double for being above the threshold again, and double again for
+        // being twice the threshold
+        assert_eq!(next(), 400);
+        assert_eq!(next(), 500);
+        assert_eq!(next(), 500);
+        assert_eq!(next(), 0);
+    }
+
+    #[test]
+    fn should_back_off_on_stall_delta() {
+        let config = PressureBackoffConfig::new(PsiKind::IO, PsiLine::Some, ten_percent())
+            .signal(BackoffSignal::StallDelta);
+        let replay = source(&[0, 0, 0]);
+        let clock = replay.clock().clone();
+        let mut backoff = PressureBackoff::with_source(config, replay);
+        let start = Instant::now();
+        // first read has nothing to compare against
+        assert_eq!(backoff.next_at(start).unwrap(), Duration::from_secs(0));
+        clock.advance(Duration::from_secs(1));
+        // 200ms stalled in a second is 20%
+        assert_eq!(
+            backoff.next_at(start + clock.now()).unwrap(),
+            Duration::from_millis(200)
+        );
+    }
+
+    #[test]
+    fn should_wait_until_below() {
+        let mut replay = source(&[20]);
+        let wait = |source: &mut ReplaySource, threshold| {
+            wait_below(
+                source,
+                poll,
+                PsiKind::IO,
+                PsiLine::Some,
+                PsiPercent::from_hundredths(threshold),
+                Duration::from_secs(0),
+            )
+            .unwrap()
+        };
+        assert!(!wait(&mut replay, 1000));
+        assert!(wait(&mut replay, 2500));
+    }
+
+    #[cfg(feature = "monitor")]
+    #[test]
+    fn should_wait_for_trigger_to_go_quiet() {
+        use crate::simulate::SimulatedMonitor;
+
+        // above the threshold for the first 12 seconds
+        let mut replay = source(&[20; 13]);
+        replay.push(
+            Duration::from_secs(12),
+            PsiKind::IO,
+            Psi {
+                line: PsiLine::Some,
+                avg10: PsiPercent::from_hundredths(500),
+                avg60: PsiPercent::ZERO,
+                avg300: PsiPercent::ZERO,
+                total: Duration::from_millis(2400),
+            },
+        );
+        let clock = replay.clock().clone();
+        let mut monitor = SimulatedMonitor::new(replay.clone(), clock.clone());
+        let window = Duration::from_secs(2);
+        monitor
+            .add_trigger(trigger(PsiKind::IO, PsiLine::Some, ten_percent(), window))
+            .unwrap();
+
+        let below = wait_below(
+            &mut replay,
+            wait_quiet(&mut monitor, window),
+            PsiKind::IO,
+            PsiLine::Some,
+            ten_percent(),
+            Duration::from_secs(60),
+        )
+        .unwrap();
+        assert!(below);
+        // stall time stops growing at 12 seconds, after which the trigger
+        // is quiet for a whole window
+        assert!(clock.now() >= Duration::from_secs(14));
+    }
+
+    #[cfg(feature = "monitor")]
+    #[test]
+    fn should_only_read_pressure_once_trigger_fires() {
+        use crate::simulate::SimulatedMonitor;
+
+        let config = PressureBackoffConfig::new(PsiKind::IO, PsiLine::Some, ten_percent())
+            .trigger_window(Duration::from_secs(1));
+        let replay = source(&[20, 20, 20, 20]);
+        let clock = replay.clock().clone();
+        let mut stalls = ReplaySource::new(clock.clone());
+        for (sec, total) in &[(0, 0), (2, 0), (3, 500)] {
+            stalls.push_total(
+                Duration::from_secs(*sec),
+                PsiKind::IO,
+                PsiLine::Some,
+                Duration::from_millis(*total),
+            );
+        }
+        let monitor = SimulatedMonitor::new(stalls, clock.clone());
+        let mut backoff = PressureBackoff::with_source(config, replay).with_monitor(monitor);
+        let start = Instant::now();
+
+        // avg10 is above the threshold, but the trigger has not fired
+        assert_eq!(backoff.next_at(start).unwrap(), Duration::from_secs(0));
+        clock.advance(Duration::from_secs(3));
+        assert_eq!(
+            backoff.next_at(start + clock.now()).unwrap(),
+            Duration::from_millis(200)
+        );
+    }
+}
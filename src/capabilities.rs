@@ -0,0 +1,341 @@
+//! Kernel feature detection
+//!
+//! PSI may be compiled out (`CONFIG_PSI=n`), disabled at boot (`psi=0`, or
+//! `CONFIG_PSI_DEFAULT_DISABLED` without `psi=1`) or only partially available
+//! depending on the kernel version. [`PsiCapabilities::probe`] reports what
+//! the running kernel offers so callers can decide up front rather than
+//! interpreting I/O errors.
+
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use crate::error::*;
+use crate::parse::parse_line;
+use crate::psi::*;
+
+pub(crate) const PROC_ROOT: &str = "/proc";
+const BOOT_ROOT: &str = "/boot";
+pub(crate) const EOPNOTSUPP: i32 = 95;
+
+/// Whether PSI accounting is available
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PsiStatus {
+    Enabled,
+    /// Built into the kernel but turned off at boot
+    Disabled,
+    /// Not built into the kernel
+    Unsupported,
+}
+
+impl fmt::Display for PsiStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PsiStatus::Enabled => write!(f, "enabled"),
+            PsiStatus::Disabled => write!(f, "disabled"),
+            PsiStatus::Unsupported => write!(f, "unsupported"),
+        }
+    }
+}
+
+/// Whether the current process can register triggers
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TriggerSupport {
+    /// A trigger was registered successfully
+    Allowed,
+    /// The kernel supports triggers but refused this process
+    PermissionDenied,
+    /// The kernel predates triggers (Linux 5.2) or PSI is unavailable
+    Unsupported,
+}
+
+/// What PSI features the running kernel offers
+#[derive(Debug, Clone, PartialEq)]
+pub struct PsiCapabilities {
+    pub status: PsiStatus,
+    /// Kinds with a system-wide pressure file
+    pub kinds: Vec<PsiKind>,
+    /// Whether CPU pressure has a `full` line (Linux 5.13+)
+    pub cpu_full: bool,
+    /// Whether IRQ pressure is available (Linux 6.1+ with IRQ time accounting)
+    pub irq: bool,
+    /// Result of registering a test trigger from this process
+    pub triggers: TriggerSupport,
+    /// Whether the kernel lets unprivileged processes register triggers
+    /// (Linux 6.5+, with windows that are multiples of 2s)
+    pub unprivileged_triggers: bool,
+    /// Where cgroup2 is mounted, if anywhere
+    pub cgroup2_mount: Option<PathBuf>,
+    /// Kernel release, e.g. `6.1.0-13-amd64`
+    pub kernel_release: Option<String>,
+}
+
+impl PsiCapabilities {
+    /// Probe the running kernel
+    pub fn probe() -> Result<Self> {
+        Self::probe_in(Path::new(PROC_ROOT), Path::new(BOOT_ROOT))
+    }
+
+    fn probe_in(proc: &Path, boot: &Path) -> Result<Self> {
+        let kernel_release = read_optional(&proc.join("sys/kernel/osrelease"))?
+            .map(|release| release.trim().to_string());
+        let status = psi_status(proc, boot, kernel_release.as_deref())?;
+        let pressure = proc.join("pressure");
+
+        let mut kinds = Vec::new();
+        let mut cpu_full = false;
+        if status == PsiStatus::Enabled {
+            for kind in &[PsiKind::CPU, PsiKind::IO, PsiKind::Memory, PsiKind::IRQ] {
+                let contents = read_optional(&pressure.join(kind.to_string()))?;
+                if let Some(contents) = contents {
+                    if *kind == PsiKind::CPU {
+                        cpu_full = parse_line(&contents, PsiLine::Full).is_ok();
+                    }
+                    kinds.push(*kind);
+                }
+            }
+        }
+        let irq = kinds.contains(&PsiKind::IRQ);
+        let triggers = if kinds.contains(&PsiKind::Memory) {
+            probe_trigger(&pressure.join(PsiKind::Memory.to_string()))?
+        } else {
+            TriggerSupport::Unsupported
+        };
+        let unprivileged_triggers = triggers != TriggerSupport::Unsupported
+            && kernel_release
+                .as_deref()
+                .and_then(kernel_version)
+                .is_some_and(|version| version >= (6, 5));
+
+        Ok(PsiCapabilities {
+            status,
+            kinds,
+            cpu_full,
+            irq,
+            triggers,
+            unprivileged_triggers,
+            cgroup2_mount: find_cgroup2_mount_in(proc)?,
+            kernel_release,
+        })
+    }
+
+    /// Fail with [`PsiError::PsiUnsupported`] or [`PsiError::PsiDisabled`]
+    /// unless PSI is enabled
+    pub fn require_enabled(&self) -> Result<()> {
+        match self.status {
+            PsiStatus::Enabled => Ok(()),
+            PsiStatus::Disabled => Err(PsiDisabled),
+            PsiStatus::Unsupported => Err(PsiUnsupported),
+        }
+    }
+
+    pub fn has_kind(&self, kind: PsiKind) -> bool {
+        self.kinds.contains(&kind)
+    }
+}
+
+/// Find where cgroup2 is mounted, from `/proc/self/mountinfo`
+pub fn find_cgroup2_mount() -> Result<Option<PathBuf>> {
+    find_cgroup2_mount_in(Path::new(PROC_ROOT))
+}
+
+pub(crate) fn find_cgroup2_mount_in(proc: &Path) -> Result<Option<PathBuf>> {
+    let mountinfo = match read_optional(&proc.join("self/mountinfo"))? {
+        Some(mountinfo) => mountinfo,
+        None => return Ok(None),
+    };
+    for line in mountinfo.lines() {
+        // id parent major:minor root mount-point options [optional...] - fstype source super-options
+        let (fields, fs) = match line.split_once(" - ") {
+            Some(split) => split,
+            None => continue,
+        };
+        if fs.split(' ').next() != Some("cgroup2") {
+            continue;
+        }
+        if let Some(mount_point) = fields.split(' ').nth(4) {
+            return Ok(Some(PathBuf::from(unescape_mountinfo(mount_point))));
+        }
+    }
+    Ok(None)
+}
+
+/// Decode the octal escapes (`\040` etc.) mountinfo uses for whitespace
+fn unescape_mountinfo(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escape = bytes
+            .get(i + 1..i + 4)
+            .filter(|digits| bytes[i] == b'\\' && digits.iter().all(|d| (b'0'..=b'7').contains(d)));
+        match escape {
+            Some(digits) => {
+                let value = digits.iter().fold(0u32, |v, d| v * 8 + u32::from(d - b'0'));
+                out.push(value as u8);
+                i += 4;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Tell apart PSI being disabled from it being unsupported when the
+/// `/proc/pressure` files are missing
+pub(crate) fn unavailable_error() -> PsiError {
+    match psi_status(Path::new(PROC_ROOT), Path::new(BOOT_ROOT), None) {
+        Ok(PsiStatus::Disabled) => PsiDisabled,
+        _ => PsiUnsupported,
+    }
+}
+
+fn psi_status(proc: &Path, boot: &Path, release: Option<&str>) -> Result<PsiStatus> {
+    if proc.join("pressure").is_dir() {
+        return Ok(PsiStatus::Enabled);
+    }
+    let cmdline = read_optional(&proc.join("cmdline"))?.unwrap_or_default();
+    let psi_arg = cmdline
+        .split_ascii_whitespace()
+        .filter_map(|arg| arg.strip_prefix("psi="))
+        .next_back();
+    if psi_arg == Some("0") {
+        return Ok(PsiStatus::Disabled);
+    }
+    let release = match release {
+        Some(release) => Some(release.to_string()),
+        None => read_optional(&proc.join("sys/kernel/osrelease"))?.map(|r| r.trim().to_string()),
+    };
+    let config = match release {
+        Some(release) => read_optional(&boot.join(format!("config-{}", release)))?,
+        None => None,
+    };
+    match config {
+        Some(config) if config.lines().any(|l| l == "CONFIG_PSI=y") => Ok(PsiStatus::Disabled),
+        _ => Ok(PsiStatus::Unsupported),
+    }
+}
+
+fn probe_trigger(path: &Path) -> Result<TriggerSupport> {
+    // a 2s window is the smallest accepted from unprivileged processes
+    let trigger = b"some 500000 2000000\0";
+    let result = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(trigger));
+    match result {
+        Ok(()) => Ok(TriggerSupport::Allowed),
+        Err(ref e) if e.kind() == ErrorKind::PermissionDenied => {
+            Ok(TriggerSupport::PermissionDenied)
+        }
+        Err(ref e)
+            if e.kind() == ErrorKind::InvalidInput || e.raw_os_error() == Some(EOPNOTSUPP) =>
+        {
+            Ok(TriggerSupport::Unsupported)
+        }
+        Err(e) => Err(PsiError::io(Operation::WriteTrigger, path, e)),
+    }
+}
+
+/// Parse the major and minor version out of a kernel release string
+fn kernel_version(release: &str) -> Option<(u32, u32)> {
+    let mut parts = release.split(|c: char| !c.is_ascii_digit());
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+fn read_optional(path: &Path) -> Result<Option<String>> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(PsiError::io(Operation::Read, path, e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const CPU_SOME_ONLY: &str = "some avg10=0.00 avg60=0.00 avg300=0.00 total=0\n";
+    const BOTH_LINES: &str = "some avg10=0.00 avg60=0.00 avg300=0.00 total=0\n\
+                              full avg10=0.00 avg60=0.00 avg300=0.00 total=0\n";
+
+    fn fake_proc(release: &str, cmdline: &str) -> TempDir {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir_all(dir.path().join("sys/kernel")).unwrap();
+        fs::create_dir_all(dir.path().join("self")).unwrap();
+        fs::write(dir.path().join("sys/kernel/osrelease"), release).unwrap();
+        fs::write(dir.path().join("cmdline"), cmdline).unwrap();
+        fs::write(
+            dir.path().join("self/mountinfo"),
+            "25 1 0:23 / /sys/fs/cgroup rw - tmpfs tmpfs rw\n\
+             42 25 0:38 / /sys/fs/cgroup/uni\\040fied rw,relatime - cgroup2 cgroup2 rw\n",
+        )
+        .unwrap();
+        dir
+    }
+
+    #[test]
+    fn should_probe_old_kernel() {
+        let proc = fake_proc("5.4.0-42-generic\n", "ro quiet");
+        let pressure = proc.path().join("pressure");
+        fs::create_dir(&pressure).unwrap();
+        fs::write(pressure.join("cpu"), CPU_SOME_ONLY).unwrap();
+        fs::write(pressure.join("io"), BOTH_LINES).unwrap();
+        fs::write(pressure.join("memory"), BOTH_LINES).unwrap();
+
+        let caps = PsiCapabilities::probe_in(proc.path(), proc.path()).unwrap();
+        assert_eq!(caps.status, PsiStatus::Enabled);
+        assert!(caps.require_enabled().is_ok());
+        assert_eq!(caps.kinds, vec![PsiKind::CPU, PsiKind::IO, PsiKind::Memory]);
+        assert!(!caps.cpu_full);
+        assert!(!caps.irq);
+        // a regular file accepts the test trigger
+        assert_eq!(caps.triggers, TriggerSupport::Allowed);
+        assert!(!caps.unprivileged_triggers);
+        assert_eq!(
+            caps.cgroup2_mount,
+            Some(PathBuf::from("/sys/fs/cgroup/uni fied"))
+        );
+    }
+
+    #[test]
+    fn should_detect_disabled_psi() {
+        let proc = fake_proc("6.6.0\n", "ro psi=0");
+        let caps = PsiCapabilities::probe_in(proc.path(), proc.path()).unwrap();
+        assert_eq!(caps.status, PsiStatus::Disabled);
+        assert!(caps.kinds.is_empty());
+        assert_eq!(caps.triggers, TriggerSupport::Unsupported);
+        match caps.require_enabled() {
+            Err(PsiDisabled) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn should_detect_default_disabled_config() {
+        let proc = fake_proc("6.6.0\n", "ro");
+        fs::write(
+            proc.path().join("config-6.6.0"),
+            "CONFIG_PSI=y\nCONFIG_PSI_DEFAULT_DISABLED=y\n",
+        )
+        .unwrap();
+        let caps = PsiCapabilities::probe_in(proc.path(), proc.path()).unwrap();
+        assert_eq!(caps.status, PsiStatus::Disabled);
+    }
+
+    #[test]
+    fn should_detect_unsupported_psi() {
+        let proc = fake_proc("4.19.0\n", "ro");
+        let caps = PsiCapabilities::probe_in(proc.path(), proc.path()).unwrap();
+        assert_eq!(caps.status, PsiStatus::Unsupported);
+        assert_eq!(kernel_version("6.12.3-arch1"), Some((6, 12)));
+    }
+}
@@ -0,0 +1,258 @@
+//! cgroup2 PSI accounting control
+//!
+//! Linux 6.1 added `cgroup.pressure`, which turns PSI accounting for a single
+//! cgroup on or off. While it is off the cgroup's `*.pressure` files are
+//! hidden, so reads fail with [`PsiError::CgroupPressureDisabled`] rather than
+//! a bare I/O error.
+//!
+//! The cgroup of a process can be resolved from `/proc/<pid>/cgroup`, walking
+//! up to the nearest ancestor with accounting enabled if need be.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use log::*;
+
+use crate::capabilities::{find_cgroup2_mount_in, EOPNOTSUPP, PROC_ROOT};
+use crate::error::*;
+use crate::psi::PsiKind;
+
+const CGROUP_PRESSURE_FILE: &str = "cgroup.pressure";
+const CGROUP_PROCS_FILE: &str = "cgroup.procs";
+
+/// Whether PSI accounting is enabled for a cgroup
+///
+/// Returns `None` on kernels without `cgroup.pressure`, where accounting is
+/// always on.
+pub fn pressure_enabled<P: AsRef<Path>>(cgroup: P) -> Result<Option<bool>> {
+    let path = cgroup.as_ref().join(CGROUP_PRESSURE_FILE);
+    match fs::read_to_string(&path) {
+        Ok(contents) => match contents.trim() {
+            "1" => Ok(Some(true)),
+            "0" => Ok(Some(false)),
+            other => Err(UnexpectedTerm {
+                offset: 0,
+                term: other.to_string(),
+            }
+            .into()),
+        },
+        Err(ref e) if e.kind() == ErrorKind::NotFound && cgroup.as_ref().is_dir() => Ok(None),
+        Err(e) => Err(PsiError::io(Operation::Read, &path, e)),
+    }
+}
+
+/// Turn PSI accounting for a cgroup on or off
+pub fn set_pressure_enabled<P: AsRef<Path>>(cgroup: P, enabled: bool) -> Result<()> {
+    let cgroup = cgroup.as_ref();
+    info!(
+        "{} psi accounting for {}",
+        if enabled { "enabling" } else { "disabling" },
+        cgroup.display()
+    );
+    let path = cgroup.join(CGROUP_PRESSURE_FILE);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(&path)
+        .context(Operation::Open, &path)?;
+    file.write_all(if enabled { b"1" } else { b"0" })
+        .context(Operation::Write, &path)?;
+    Ok(())
+}
+
+/// Turn PSI accounting on or off for a cgroup and all of its descendants
+///
+/// Descendants which disappear while walking are skipped.
+pub fn set_pressure_enabled_recursive<P: AsRef<Path>>(cgroup: P, enabled: bool) -> Result<()> {
+    let cgroup = cgroup.as_ref();
+    set_pressure_enabled(cgroup, enabled)?;
+    let entries = match fs::read_dir(cgroup) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(PsiError::io(Operation::ReadDir, cgroup, e)),
+    };
+    for entry in entries {
+        let entry = entry.context(Operation::ReadDir, cgroup)?;
+        if !entry
+            .file_type()
+            .context(Operation::ReadDir, &entry.path())?
+            .is_dir()
+        {
+            continue;
+        }
+        match set_pressure_enabled_recursive(entry.path(), enabled) {
+            Err(ref e) if e.is_not_found() => {
+                debug!("cgroup {} went away", entry.path().display());
+            }
+            result => result?,
+        }
+    }
+    Ok(())
+}
+
+/// cgroup2 directory of a process, from `/proc/<pid>/cgroup`
+pub fn pid_cgroup(pid: u32) -> Result<PathBuf> {
+    proc_cgroup_in(Path::new(PROC_ROOT), &pid.to_string())
+}
+
+/// cgroup2 directory of the current process
+pub fn self_cgroup() -> Result<PathBuf> {
+    proc_cgroup_in(Path::new(PROC_ROOT), "self")
+}
+
+fn proc_cgroup_in(proc: &Path, pid: &str) -> Result<PathBuf> {
+    let path = proc.join(pid).join("cgroup");
+    let not_found = |what| {
+        PsiError::io(
+            Operation::Read,
+            &path,
+            io::Error::new(ErrorKind::NotFound, what),
+        )
+    };
+    let mount = find_cgroup2_mount_in(proc)?.ok_or_else(|| not_found("cgroup2 is not mounted"))?;
+    let contents = fs::read_to_string(&path).context(Operation::Read, &path)?;
+    // cgroup2 is hierarchy 0 with no controllers listed: `0::/some/path`
+    let path = contents
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .ok_or_else(|| not_found("process is not in a cgroup2 hierarchy"))?;
+    match path.trim_start_matches('/') {
+        "" => Ok(mount),
+        path => Ok(mount.join(path)),
+    }
+}
+
+/// The closest of `cgroup` and its ancestors with PSI accounting enabled
+///
+/// Fails with [`PsiError::CgroupPressureDisabled`] for `cgroup` if no
+/// ancestor up to the cgroup2 root has accounting enabled.
+pub fn nearest_pressure_cgroup<P: AsRef<Path>>(cgroup: P) -> Result<PathBuf> {
+    let cgroup = cgroup.as_ref();
+    for ancestor in cgroup.ancestors() {
+        if !ancestor.join(CGROUP_PROCS_FILE).exists() {
+            break;
+        }
+        if pressure_enabled(ancestor)? == Some(false) {
+            continue;
+        }
+        if PsiKind::CPU.cgroup_file_path(ancestor).exists() {
+            return Ok(ancestor.to_path_buf());
+        }
+    }
+    Err(CgroupPressureDisabled(cgroup.to_path_buf()))
+}
+
+/// Replace a failed read of a cgroup's pressure file with
+/// [`PsiError::CgroupPressureDisabled`] if accounting is turned off there
+pub(crate) fn classify_read_error(cgroup: &Path, e: PsiError) -> PsiError {
+    if e.is_not_found() || e.raw_os_error() == Some(EOPNOTSUPP) {
+        if let Ok(Some(false)) = pressure_enabled(cgroup) {
+            return CgroupPressureDisabled(cgroup.to_path_buf());
+        }
+    }
+    e
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::psi::*;
+    use crate::reader::PsiReader;
+    use crate::snapshot::PsiSnapshot;
+    use tempfile::TempDir;
+
+    #[test]
+    fn should_toggle_pressure() {
+        let dir = TempDir::new().unwrap();
+        assert_eq!(pressure_enabled(dir.path()).unwrap(), None);
+
+        fs::write(dir.path().join(CGROUP_PRESSURE_FILE), "1\n").unwrap();
+        assert_eq!(pressure_enabled(dir.path()).unwrap(), Some(true));
+
+        set_pressure_enabled(dir.path(), false).unwrap();
+        assert_eq!(pressure_enabled(dir.path()).unwrap(), Some(false));
+    }
+
+    #[test]
+    fn should_toggle_subtree() {
+        let dir = TempDir::new().unwrap();
+        let child = dir.path().join("batch.slice/job.scope");
+        fs::create_dir_all(&child).unwrap();
+        for cgroup in &[dir.path(), &dir.path().join("batch.slice"), &child] {
+            fs::write(cgroup.join(CGROUP_PRESSURE_FILE), "1\n").unwrap();
+        }
+        set_pressure_enabled_recursive(dir.path(), false).unwrap();
+        assert_eq!(pressure_enabled(&child).unwrap(), Some(false));
+    }
+
+    #[test]
+    fn should_report_disabled_accounting() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join(CGROUP_PRESSURE_FILE), "0\n").unwrap();
+
+        let is_disabled = |result: Result<_>| match result {
+            Err(CgroupPressureDisabled(path)) => path == dir.path(),
+            _ => false,
+        };
+        assert!(is_disabled(
+            PsiKind::Memory.read_cgroup_psi(dir.path()).map(|_| ())
+        ));
+        assert!(is_disabled(
+            PsiKind::IO
+                .read_cgroup_psi_line(dir.path(), PsiLine::Some)
+                .map(|_| ())
+        ));
+        assert!(is_disabled(
+            PsiReader::cgroup(PsiKind::CPU, dir.path()).map(|_| ())
+        ));
+        assert!(is_disabled(
+            PsiSnapshot::read_cgroup(dir.path()).map(|_| ())
+        ));
+    }
+
+    #[test]
+    fn should_resolve_pid_cgroup() {
+        let proc = TempDir::new().unwrap();
+        let mount = TempDir::new().unwrap();
+        fs::create_dir_all(proc.path().join("self")).unwrap();
+        fs::write(
+            proc.path().join("self/mountinfo"),
+            format!(
+                "30 24 0:26 / {} rw,nosuid - cgroup2 cgroup2 rw\n",
+                mount.path().display()
+            ),
+        )
+        .unwrap();
+        fs::create_dir_all(proc.path().join("42")).unwrap();
+        fs::write(
+            proc.path().join("42/cgroup"),
+            "4:memory:/legacy\n0::/system.slice/app.service\n",
+        )
+        .unwrap();
+        assert_eq!(
+            proc_cgroup_in(proc.path(), "42").unwrap(),
+            mount.path().join("system.slice/app.service")
+        );
+    }
+
+    #[test]
+    fn should_find_nearest_pressure_cgroup() {
+        let root = TempDir::new().unwrap();
+        let slice = root.path().join("batch.slice");
+        let job = slice.join("job.scope");
+        fs::create_dir_all(&job).unwrap();
+        for cgroup in &[root.path(), &slice, &job] {
+            fs::write(cgroup.join(CGROUP_PROCS_FILE), "").unwrap();
+            fs::write(cgroup.join(CGROUP_PRESSURE_FILE), "0\n").unwrap();
+        }
+        fs::write(slice.join(CGROUP_PRESSURE_FILE), "1\n").unwrap();
+        fs::write(PsiKind::CPU.cgroup_file_path(&slice), "").unwrap();
+        assert_eq!(nearest_pressure_cgroup(&job).unwrap(), slice);
+
+        fs::write(slice.join(CGROUP_PRESSURE_FILE), "0\n").unwrap();
+        match nearest_pressure_cgroup(&job) {
+            Err(CgroupPressureDisabled(path)) => assert_eq!(path, job),
+            _ => panic!("expected accounting to be disabled"),
+        }
+    }
+}
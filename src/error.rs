@@ -1,56 +1,417 @@
 use std::error::Error;
 use std::fmt;
-use std::io::ErrorKind;
-use std::num::{ParseFloatError, ParseIntError};
+use std::io::{self, ErrorKind};
+use std::num::ParseIntError;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
 
 pub(crate) use std::result::Result as StdResult;
 pub(crate) use ParseError::*;
 pub(crate) use PsiError::*;
 
+use crate::capabilities::EOPNOTSUPP;
+#[cfg(feature = "monitor")]
+use crate::trigger::Trigger;
+
 pub type Result<T> = StdResult<T, PsiError>;
 
+const ENODEV: i32 = 19;
+
+/// What was being done when an I/O error occurred
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Operation {
+    Open,
+    Read,
+    Write,
+    /// Writing a trigger threshold to a pressure file
+    WriteTrigger,
+    Epoll,
+    Inotify,
+    ReadDir,
+    /// An operation without more specific context
+    Other,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operation::Open => write!(f, "open"),
+            Operation::Read => write!(f, "read"),
+            Operation::Write => write!(f, "write"),
+            Operation::WriteTrigger => write!(f, "write trigger to"),
+            Operation::Epoll => write!(f, "poll"),
+            Operation::Inotify => write!(f, "watch"),
+            Operation::ReadDir => write!(f, "list"),
+            Operation::Other => write!(f, "access"),
+        }
+    }
+}
+
+/// Broad classification of an error, for deciding how to react to it
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PsiErrorKind {
+    /// The kernel lacks PSI or the requested feature, or it is disabled
+    NotSupported,
+    PermissionDenied,
+    /// The kernel rejected a trigger threshold
+    InvalidThreshold,
+    /// The cgroup was removed
+    CgroupGone,
+    /// A file other than one in a removed cgroup doesn't exist
+    NotFound,
+    /// Pressure data couldn't be parsed
+    Parse,
+    Other,
+}
+
 /// Error type for PSI
+///
+/// Marked `#[non_exhaustive]` because some variants only exist with the
+/// `monitor` or `systemd` features, and any crate in a build may enable
+/// those; matches on it need a wildcard arm.
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum PsiError {
-    IoError(std::io::Error),
+    /// An I/O operation failed
+    IoError {
+        op: Operation,
+        /// The file involved, if known
+        path: Option<PathBuf>,
+        source: io::Error,
+        /// Decided when the error is created, as telling whether a cgroup
+        /// was removed needs the filesystem
+        kind: PsiErrorKind,
+    },
+    /// An I/O operation on a trigger failed
+    #[cfg(feature = "monitor")]
+    TriggerError {
+        op: Operation,
+        trigger: Box<Trigger>,
+        source: io::Error,
+        kind: PsiErrorKind,
+    },
     PsiParseError(ParseError),
-    InvalidThreshold(std::io::Error),
     UnexpectedTriggerEvent {
         expected_kind: crate::PsiKind,
         expected_line: crate::PsiLine,
     },
-    UnregisteredEvent,
-    PsiTriggerFileError,
-    LoggingInitError(log::SetLoggerError),
+    /// epoll reported an event for a file descriptor with no trigger
+    UnregisteredEvent {
+        fd: RawFd,
+    },
+    /// epoll reported an event without the expected flags, or with data
+    /// that isn't a file descriptor
+    UnexpectedEpollEvent {
+        data: u64,
+        events: u32,
+    },
+    /// No trigger is registered with the given ID
+    UnknownTrigger,
+    /// epoll reported an error condition on a trigger's file, e.g. because
+    /// its cgroup was removed
+    #[cfg(feature = "monitor")]
+    PsiTriggerFileError {
+        trigger: Box<Trigger>,
+        kind: PsiErrorKind,
+    },
+    /// The kernel was built without PSI, or lacks the requested pressure kind
+    PsiUnsupported,
+    /// PSI is built in but disabled, e.g. booted with `psi=0`
+    PsiDisabled,
+    /// The pressure kind never has the requested line, e.g. IRQ `some`
+    UnsupportedLine {
+        kind: crate::PsiKind,
+        line: crate::PsiLine,
+    },
+    /// PSI accounting is turned off for the cgroup via `cgroup.pressure`
+    CgroupPressureDisabled(PathBuf),
+    /// A cgroup path pattern is not a valid glob
+    InvalidPattern(String),
+    /// No cgroup was found for a systemd unit
+    #[cfg(feature = "systemd")]
+    UnknownUnit(String),
+}
+
+impl PsiError {
+    pub(crate) fn io(op: Operation, path: &Path, source: io::Error) -> Self {
+        PsiError::io_at(op, Some(path.to_path_buf()), source)
+    }
+
+    /// An [`IoError`](PsiError::IoError) for `path` if there is one
+    pub(crate) fn io_at(op: Operation, path: Option<PathBuf>, source: io::Error) -> Self {
+        let kind = classify_io(&source, path.as_deref());
+        IoError {
+            op,
+            path,
+            source,
+            kind,
+        }
+    }
+
+    #[cfg(feature = "monitor")]
+    pub(crate) fn trigger(op: Operation, trigger: &Trigger, source: io::Error) -> Self {
+        let kind = if op == Operation::WriteTrigger && source.kind() == ErrorKind::InvalidInput {
+            PsiErrorKind::InvalidThreshold
+        } else {
+            classify_io(&source, Some(&trigger.target_file_path))
+        };
+        TriggerError {
+            op,
+            trigger: Box::new(trigger.clone()),
+            source,
+            kind,
+        }
+    }
+
+    /// A [`PsiTriggerFileError`](PsiError::PsiTriggerFileError) for `trigger`
+    #[cfg(feature = "monitor")]
+    pub(crate) fn trigger_file(trigger: &Trigger) -> Self {
+        let kind = if in_removed_cgroup(&trigger.target_file_path) {
+            PsiErrorKind::CgroupGone
+        } else {
+            PsiErrorKind::Other
+        };
+        PsiTriggerFileError {
+            trigger: Box::new(trigger.clone()),
+            kind,
+        }
+    }
+
+    /// Classify the error
+    pub fn kind(&self) -> PsiErrorKind {
+        match self {
+            IoError { kind, .. } => *kind,
+            #[cfg(feature = "monitor")]
+            TriggerError { kind, .. } | PsiTriggerFileError { kind, .. } => *kind,
+            PsiParseError(_) => PsiErrorKind::Parse,
+            PsiUnsupported | PsiDisabled | UnsupportedLine { .. } | CgroupPressureDisabled(_) => {
+                PsiErrorKind::NotSupported
+            }
+            UnexpectedTriggerEvent { .. }
+            | UnregisteredEvent { .. }
+            | UnexpectedEpollEvent { .. }
+            | UnknownTrigger
+            | InvalidPattern(_) => PsiErrorKind::Other,
+            #[cfg(feature = "systemd")]
+            UnknownUnit(_) => PsiErrorKind::NotFound,
+        }
+    }
+
+    /// The file involved, if known
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            IoError { path, .. } => path.as_deref(),
+            #[cfg(feature = "monitor")]
+            TriggerError { trigger, .. } | PsiTriggerFileError { trigger, .. } => {
+                Some(&trigger.target_file_path)
+            }
+            CgroupPressureDisabled(cgroup) => Some(cgroup),
+            _ => None,
+        }
+    }
+
+    /// The operation that failed, for I/O errors
+    pub fn operation(&self) -> Option<Operation> {
+        match self {
+            IoError { op, .. } => Some(*op),
+            #[cfg(feature = "monitor")]
+            TriggerError { op, .. } => Some(*op),
+            _ => None,
+        }
+    }
+
+    /// The trigger involved, if any
+    #[cfg(feature = "monitor")]
+    pub fn trigger_info(&self) -> Option<&Trigger> {
+        match self {
+            TriggerError { trigger, .. } | PsiTriggerFileError { trigger, .. } => Some(trigger),
+            _ => None,
+        }
+    }
+
+    /// Whether a file or directory didn't exist, including files of a
+    /// removed cgroup
+    pub(crate) fn is_not_found(&self) -> bool {
+        matches!(
+            self.kind(),
+            PsiErrorKind::NotFound | PsiErrorKind::CgroupGone
+        )
+    }
+
+    pub(crate) fn raw_os_error(&self) -> Option<i32> {
+        match self {
+            IoError { source, .. } => source.raw_os_error(),
+            #[cfg(feature = "monitor")]
+            TriggerError { source, .. } => source.raw_os_error(),
+            _ => None,
+        }
+    }
+}
+
+/// Attach the operation and file to I/O errors
+pub(crate) trait IoResultExt<T> {
+    fn context(self, op: Operation, path: &Path) -> Result<T>;
+
+    /// Attach only the operation, for calls not involving a file such as
+    /// creating an epoll instance
+    fn op_context(self, op: Operation) -> Result<T>;
+}
+
+impl<T> IoResultExt<T> for io::Result<T> {
+    fn context(self, op: Operation, path: &Path) -> Result<T> {
+        self.map_err(|e| PsiError::io(op, path, e))
+    }
+
+    fn op_context(self, op: Operation) -> Result<T> {
+        self.map_err(|source| PsiError::io_at(op, None, source))
+    }
+}
+
+fn classify_io(e: &io::Error, path: Option<&Path>) -> PsiErrorKind {
+    if e.raw_os_error() == Some(EOPNOTSUPP) {
+        return PsiErrorKind::NotSupported;
+    }
+    // files under /proc don't belong to a cgroup
+    let cgroup_file = path.filter(|path| !path.starts_with("/proc"));
+    if cgroup_file.is_some() && e.raw_os_error() == Some(ENODEV) {
+        return PsiErrorKind::CgroupGone;
+    }
+    match e.kind() {
+        ErrorKind::PermissionDenied => PsiErrorKind::PermissionDenied,
+        ErrorKind::NotFound if matches!(cgroup_file, Some(path) if in_removed_cgroup(path)) => {
+            PsiErrorKind::CgroupGone
+        }
+        ErrorKind::NotFound => PsiErrorKind::NotFound,
+        _ => PsiErrorKind::Other,
+    }
+}
+
+/// Whether the directory holding a cgroup file has gone
+fn in_removed_cgroup(path: &Path) -> bool {
+    match path.parent() {
+        Some(dir) => !dir.as_os_str().is_empty() && !dir.exists(),
+        None => false,
+    }
 }
 
 /// Error type for PSI parsing
+///
+/// Offsets are in bytes from the start of the parsed input.
 #[derive(Debug)]
 pub enum ParseError {
-    TotalParseError(ParseIntError),
-    AvgParseError(ParseFloatError),
-    UnexpectedTerm(String),
+    TotalParseError {
+        offset: usize,
+        error: ParseIntError,
+    },
+    AvgParseError {
+        offset: usize,
+        error: ParsePercentError,
+    },
+    UnexpectedTerm {
+        offset: usize,
+        term: String,
+    },
+    UnknownField {
+        offset: usize,
+        key: String,
+    },
+    DuplicateField {
+        offset: usize,
+        key: String,
+    },
+    MissingField {
+        offset: usize,
+        field: &'static str,
+    },
     MissingLine(crate::PsiLine),
 }
 
+impl ParseError {
+    /// Byte offset of the error within the parsed input, if known
+    pub fn offset(&self) -> Option<usize> {
+        match self {
+            TotalParseError { offset, .. }
+            | AvgParseError { offset, .. }
+            | UnexpectedTerm { offset, .. }
+            | UnknownField { offset, .. }
+            | DuplicateField { offset, .. }
+            | MissingField { offset, .. } => Some(*offset),
+            MissingLine(_) => None,
+        }
+    }
+}
+
+/// Error parsing a [`PsiPercent`](crate::psi::PsiPercent)
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ParsePercentError {
+    /// Not a non-negative decimal number
+    Invalid,
+    /// Too large to represent
+    Overflow,
+}
+
+impl fmt::Display for ParsePercentError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParsePercentError::Invalid => write!(f, "invalid percentage"),
+            ParsePercentError::Overflow => write!(f, "percentage too large"),
+        }
+    }
+}
+
+impl Error for ParsePercentError {}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UnexpectedTerm { offset, term } => {
+                write!(f, "unexpected psi term '{}' at byte {}", term, offset)
+            }
+            TotalParseError { offset, error } => {
+                write!(f, "error parsing psi total at byte {}: {}", offset, error)
+            }
+            AvgParseError { offset, error } => {
+                write!(f, "error parsing psi avg at byte {}: {}", offset, error)
+            }
+            UnknownField { offset, key } => {
+                write!(f, "unknown psi field '{}' at byte {}", key, offset)
+            }
+            DuplicateField { offset, key } => {
+                write!(f, "duplicate psi field '{}' at byte {}", key, offset)
+            }
+            MissingField { offset, field } => {
+                write!(f, "missing psi field '{}' at byte {}", field, offset)
+            }
+            MissingLine(line) => write!(f, "missing line '{}'", line),
+        }
+    }
+}
+
 impl From<ParseError> for PsiError {
     fn from(e: ParseError) -> Self {
         PsiParseError(e)
     }
 }
 
+impl Error for ParseError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TotalParseError { error, .. } => Some(error),
+            AvgParseError { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
 impl Error for PsiError {
+    /// I/O errors are part of the message already, so their own source, if
+    /// any, is returned instead
     fn source(&self) -> Option<&(dyn Error + 'static)> {
         match self {
-            IoError(e) => Some(e),
-            PsiParseError(e) => match e {
-                TotalParseError(_) => None,
-                AvgParseError(_) => None,
-                UnexpectedTerm(_) => None,
-                MissingLine(_) => None,
-            },
-            InvalidThreshold(e) => Some(e),
-            LoggingInitError(e) => Some(e),
+            IoError { source, .. } => source.source(),
+            #[cfg(feature = "monitor")]
+            TriggerError { source, .. } => source.source(),
+            PsiParseError(e) => Some(e),
             _ => None,
         }
     }
@@ -58,53 +419,155 @@ impl Error for PsiError {
 
 impl fmt::Display for PsiError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self.source() {
-            None => match self {
-                UnexpectedTriggerEvent {
-                    expected_kind,
-                    expected_line,
-                } => write!(
-                    f,
-                    "unexpected trigger event; expected {} {}",
-                    expected_kind, expected_line
-                ),
-                UnregisteredEvent => write!(f, "unregistered event triggered"),
-                PsiParseError(p) => match p {
-                    UnexpectedTerm(t) => write!(f, "unexpected psi term '{}'", t),
-                    TotalParseError(e) => write!(f, "error parsing psi total: {}", e),
-                    AvgParseError(e) => write!(f, "error parsing psi avg: {}", e),
-                    MissingLine(line) => write!(f, "missing line '{}'", line),
-                },
-                _ => write!(f, "unknown error"),
-            },
-            Some(e) => write!(f, "{}", e),
+        match self {
+            IoError {
+                op,
+                path: Some(path),
+                source,
+                ..
+            } => write!(f, "failed to {} {}: {}", op, path.display(), source),
+            IoError {
+                op: Operation::Other,
+                path: None,
+                source,
+                ..
+            } => write!(f, "{}", source),
+            IoError {
+                op,
+                path: None,
+                source,
+                ..
+            } => write!(f, "{} failed: {}", op, source),
+            #[cfg(feature = "monitor")]
+            TriggerError {
+                op,
+                trigger,
+                source,
+                ..
+            } => write!(
+                f,
+                "failed to {} {} for {}: {}",
+                op,
+                trigger.target_file_path.display(),
+                trigger,
+                source
+            ),
+            PsiParseError(e) => write!(f, "{}", e),
+            UnexpectedTriggerEvent {
+                expected_kind,
+                expected_line,
+            } => write!(
+                f,
+                "unexpected trigger event; expected {} {}",
+                expected_kind, expected_line
+            ),
+            UnregisteredEvent { fd } => {
+                write!(f, "event on unregistered file descriptor {}", fd)
+            }
+            UnexpectedEpollEvent { data, events } => {
+                write!(f, "unexpected epoll event {:#x} with data {}", events, data)
+            }
+            UnknownTrigger => write!(f, "no trigger registered with that id"),
+            #[cfg(feature = "monitor")]
+            PsiTriggerFileError { trigger, .. } => write!(
+                f,
+                "error condition on {} for {}",
+                trigger.target_file_path.display(),
+                trigger
+            ),
+            PsiUnsupported => write!(f, "psi is not supported by this kernel"),
+            PsiDisabled => write!(f, "psi is disabled; boot with psi=1 to enable"),
+            UnsupportedLine { kind, line } => write!(f, "{} pressure has no '{}' line", kind, line),
+            CgroupPressureDisabled(cgroup) => write!(
+                f,
+                "psi accounting is disabled for cgroup {}",
+                cgroup.display()
+            ),
+            InvalidPattern(e) => write!(f, "invalid cgroup pattern: {}", e),
+            #[cfg(feature = "systemd")]
+            UnknownUnit(unit) => write!(f, "no cgroup found for unit {}", unit),
         }
     }
 }
 
-impl From<std::io::Error> for PsiError {
-    fn from(e: std::io::Error) -> Self {
-        match e.kind() {
-            ErrorKind::InvalidInput => InvalidThreshold(e),
-            _ => IoError(e),
-        }
+impl From<io::Error> for PsiError {
+    fn from(source: io::Error) -> Self {
+        PsiError::io_at(Operation::Other, None, source)
     }
 }
 
-impl From<ParseFloatError> for PsiError {
-    fn from(e: ParseFloatError) -> Self {
-        PsiParseError(AvgParseError(e))
+impl From<ParsePercentError> for PsiError {
+    fn from(e: ParsePercentError) -> Self {
+        PsiParseError(AvgParseError {
+            offset: 0,
+            error: e,
+        })
     }
 }
 
 impl From<ParseIntError> for PsiError {
     fn from(e: ParseIntError) -> Self {
-        PsiParseError(TotalParseError(e))
+        PsiParseError(TotalParseError {
+            offset: 0,
+            error: e,
+        })
     }
 }
 
-impl From<log::SetLoggerError> for PsiError {
-    fn from(e: log::SetLoggerError) -> Self {
-        LoggingInitError(e)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn should_classify_io_errors() {
+        let dir = TempDir::new().unwrap();
+        let gone = dir.path().join("removed/memory.pressure");
+        let missing = dir.path().join("memory.pressure");
+        let not_found = || io::Error::new(ErrorKind::NotFound, "missing");
+
+        let e = PsiError::io(Operation::Read, &gone, not_found());
+        assert_eq!(e.kind(), PsiErrorKind::CgroupGone);
+        assert_eq!(e.path(), Some(gone.as_path()));
+        assert_eq!(e.operation(), Some(Operation::Read));
+        let e = PsiError::io(Operation::Open, &missing, not_found());
+        assert_eq!(e.kind(), PsiErrorKind::NotFound);
+        assert!(e.to_string().starts_with("failed to open "));
+        let e = PsiError::io(
+            Operation::Read,
+            &missing,
+            io::Error::from_raw_os_error(EOPNOTSUPP),
+        );
+        assert_eq!(e.kind(), PsiErrorKind::NotSupported);
+        let e: PsiError = io::Error::new(ErrorKind::InvalidInput, "bad").into();
+        assert_eq!(e.kind(), PsiErrorKind::Other);
+    }
+
+    #[test]
+    fn should_classify_once_and_print_io_source_once() {
+        let dir = TempDir::new().unwrap();
+        let cgroup = dir.path().join("app.service");
+        fs::create_dir(&cgroup).unwrap();
+        let path = cgroup.join("memory.pressure");
+        let e = PsiError::io(
+            Operation::Read,
+            &path,
+            io::Error::new(ErrorKind::NotFound, "missing"),
+        );
+        // removing the cgroup later doesn't change what was decided
+        fs::remove_dir(&cgroup).unwrap();
+        assert_eq!(e.kind(), PsiErrorKind::NotFound);
+        assert!(e.to_string().ends_with(": missing"));
+        assert!(e.source().is_none());
+    }
+
+    #[test]
+    fn should_expose_parse_error_source() {
+        let error = "x".parse::<u64>().unwrap_err();
+        let e = PsiParseError(TotalParseError { offset: 3, error });
+        let parse = e.source().expect("parse error has a source");
+        assert!(parse.source().is_some());
+        assert_eq!(e.kind(), PsiErrorKind::Parse);
     }
 }
@@ -0,0 +1,367 @@
+//! Pressure-aware admission control
+//!
+//! A [`PressureGate`] tracks whether the host (or a cgroup) is under enough
+//! pressure that new work should be shed. The decision is kept in an atomic
+//! flag so [`PressureGate::should_admit`] is cheap enough to call per request,
+//! while a background thread keeps it up to date.
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::Duration;
+
+use log::*;
+
+use crate::error::*;
+use crate::psi::*;
+
+/// Shedding threshold for a single pressure kind and line
+///
+/// Values are compared with `avg10`. Shedding starts once the average reaches
+/// `enter` and stops only once it has fallen below `exit`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct GateThreshold {
+    pub kind: PsiKind,
+    pub line: PsiLine,
+    pub enter: PsiPercent,
+    pub exit: PsiPercent,
+}
+
+impl GateThreshold {
+    pub fn new(kind: PsiKind, line: PsiLine, enter: PsiPercent, exit: PsiPercent) -> Self {
+        GateThreshold {
+            kind,
+            line,
+            enter,
+            exit,
+        }
+    }
+}
+
+/// Pressure gate configuration
+#[derive(Debug, Clone)]
+pub struct PressureGateConfig {
+    pub thresholds: Vec<GateThreshold>,
+    /// cgroup2 directory to read pressure from instead of `/proc/pressure`
+    pub cgroup: Option<PathBuf>,
+    /// How often the background thread re-reads pressure
+    pub poll_interval: Duration,
+    /// Trigger window used to wake the background thread as soon as a
+    /// threshold is entered, rather than at the next poll.
+    ///
+    /// The trigger stall is derived from each threshold's `enter` percentage.
+    /// If the kernel refuses the trigger the gate falls back to polling.
+    pub trigger_window: Option<Duration>,
+}
+
+impl Default for PressureGateConfig {
+    fn default() -> Self {
+        PressureGateConfig {
+            thresholds: Vec::new(),
+            cgroup: None,
+            poll_interval: Duration::from_secs(1),
+            trigger_window: None,
+        }
+    }
+}
+
+impl PressureGateConfig {
+    pub fn threshold(mut self, threshold: GateThreshold) -> Self {
+        self.thresholds.push(threshold);
+        self
+    }
+
+    pub fn cgroup<P: Into<PathBuf>>(mut self, cgroup: P) -> Self {
+        self.cgroup = Some(cgroup.into());
+        self
+    }
+
+    pub fn poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    pub fn trigger_window(mut self, trigger_window: Duration) -> Self {
+        self.trigger_window = Some(trigger_window);
+        self
+    }
+}
+
+struct GateInner {
+    config: PressureGateConfig,
+    shedding: AtomicBool,
+}
+
+/// Lock-free admission check driven by pressure thresholds
+///
+/// Clones share the same state. The background thread started by
+/// [`PressureGate::spawn`] exits once every clone has been dropped.
+#[derive(Clone)]
+pub struct PressureGate {
+    inner: Arc<GateInner>,
+}
+
+impl PressureGate {
+    /// Create a gate which is only updated by calling [`PressureGate::refresh`]
+    pub fn new(config: PressureGateConfig) -> Self {
+        PressureGate {
+            inner: Arc::new(GateInner {
+                config,
+                shedding: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    /// Create a gate and start a background thread keeping it up to date
+    pub fn spawn(config: PressureGateConfig) -> Result<Self> {
+        let gate = PressureGate::new(config);
+        gate.refresh()?;
+        let weak = Arc::downgrade(&gate.inner);
+        thread::Builder::new()
+            .name("psi-gate".to_string())
+            .spawn(move || background_loop(weak))
+            .op_context(Operation::Other)?;
+        Ok(gate)
+    }
+
+    /// Whether new work should be accepted
+    #[inline]
+    pub fn should_admit(&self) -> bool {
+        !self.is_shedding()
+    }
+
+    /// Whether the gate is currently in the shed state
+    #[inline]
+    pub fn is_shedding(&self) -> bool {
+        self.inner.shedding.load(Ordering::Relaxed)
+    }
+
+    pub fn config(&self) -> &PressureGateConfig {
+        &self.inner.config
+    }
+
+    /// Read current pressure and update the shed state, returning it
+    ///
+    /// Only the lines the thresholds name are read, so CPU `some` works on
+    /// kernels without a CPU `full` line, as does IRQ `full`.
+    pub fn refresh(&self) -> Result<bool> {
+        let cgroup = self.inner.config.cgroup.clone();
+        self.update_from(|kind, line| match &cgroup {
+            Some(cgroup) => kind.read_cgroup_psi_line(cgroup, line),
+            None => kind.read_psi_line(line),
+        })
+    }
+
+    fn update_from<F>(&self, mut read: F) -> Result<bool>
+    where
+        F: FnMut(PsiKind, PsiLine) -> Result<Psi>,
+    {
+        let was_shedding = self.is_shedding();
+        let mut readings: Vec<(PsiKind, Psi)> = Vec::with_capacity(4);
+        let mut shedding = false;
+        for threshold in &self.inner.config.thresholds {
+            let read_before = readings
+                .iter()
+                .find(|(kind, psi)| *kind == threshold.kind && psi.line == threshold.line)
+                .map(|(_, psi)| psi.avg10);
+            let avg10 = match read_before {
+                Some(avg10) => avg10,
+                None => {
+                    let psi = read(threshold.kind, threshold.line)?;
+                    readings.push((threshold.kind, psi));
+                    psi.avg10
+                }
+            };
+            let limit = if was_shedding {
+                threshold.exit
+            } else {
+                threshold.enter
+            };
+            if avg10 >= limit {
+                shedding = true;
+            }
+        }
+        if shedding != was_shedding {
+            if shedding {
+                warn!("pressure threshold reached; shedding load");
+            } else {
+                info!("pressure cleared; admitting load");
+            }
+            self.inner.shedding.store(shedding, Ordering::Relaxed);
+        }
+        Ok(shedding)
+    }
+}
+
+fn background_loop(weak: Weak<GateInner>) {
+    #[cfg(feature = "monitor")]
+    let mut monitor = weak
+        .upgrade()
+        .and_then(|inner| register_triggers(&inner.config));
+    loop {
+        let poll_interval = match weak.upgrade() {
+            Some(inner) => inner.config.poll_interval,
+            None => return,
+        };
+        #[cfg(feature = "monitor")]
+        {
+            match monitor.as_mut().map(|m| m.wait_timeout(poll_interval)) {
+                None => thread::sleep(poll_interval),
+                Some(Ok(_)) => {}
+                Some(Err(e)) => {
+                    warn!(
+                        "pressure gate monitor failed, falling back to polling: {}",
+                        e
+                    );
+                    monitor = None;
+                }
+            }
+        }
+        #[cfg(not(feature = "monitor"))]
+        thread::sleep(poll_interval);
+
+        let gate = match weak.upgrade() {
+            Some(inner) => PressureGate { inner },
+            None => return,
+        };
+        if let Err(e) = gate.refresh() {
+            warn!("failed to refresh pressure gate: {}", e);
+        }
+    }
+}
+
+#[cfg(feature = "monitor")]
+fn register_triggers(config: &PressureGateConfig) -> Option<crate::monitor::PsiMonitor> {
+    use crate::trigger::{stall_for, Trigger};
+
+    let window = config.trigger_window?;
+    let mut monitor = match crate::monitor::PsiMonitor::new() {
+        Ok(monitor) => monitor,
+        Err(e) => {
+            warn!("unable to create pressure gate monitor: {}", e);
+            return None;
+        }
+    };
+    for threshold in &config.thresholds {
+        let stall = stall_for(threshold.enter, window);
+        let builder = Trigger::new_builder().kind(threshold.kind);
+        let builder = match &config.cgroup {
+            Some(cgroup) => builder.cgroup(cgroup),
+            None => builder,
+        };
+        let trigger = builder
+            .line(threshold.line)
+            .stall(stall)
+            .window(window)
+            .build();
+        if let Err(e) = monitor.add_trigger(trigger) {
+            warn!("unable to register pressure gate trigger: {}", e);
+            return None;
+        }
+    }
+    Some(monitor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{fake_cgroup, pressure_line, write_pressure};
+    use tempfile::TempDir;
+
+    fn psi(line: PsiLine, avg10: u32) -> Psi {
+        Psi {
+            line,
+            avg10: PsiPercent::from_hundredths(avg10 * 100),
+            avg60: PsiPercent::ZERO,
+            avg300: PsiPercent::ZERO,
+            total: Duration::from_micros(0),
+        }
+    }
+
+    fn threshold(kind: PsiKind, line: PsiLine, enter: u32, exit: u32) -> GateThreshold {
+        GateThreshold::new(
+            kind,
+            line,
+            PsiPercent::from_hundredths(enter * 100),
+            PsiPercent::from_hundredths(exit * 100),
+        )
+    }
+
+    #[test]
+    fn should_shed_with_hysteresis() {
+        let gate = PressureGate::new(PressureGateConfig::default().threshold(threshold(
+            PsiKind::Memory,
+            PsiLine::Some,
+            20,
+            10,
+        )));
+        assert!(gate.should_admit());
+
+        assert!(!gate.update_from(|_, line| Ok(psi(line, 15))).unwrap());
+        assert!(gate.should_admit());
+
+        assert!(gate.update_from(|_, line| Ok(psi(line, 25))).unwrap());
+        assert!(!gate.should_admit());
+
+        // below enter but above exit keeps shedding
+        assert!(gate.update_from(|_, line| Ok(psi(line, 15))).unwrap());
+        assert!(!gate.should_admit());
+
+        assert!(!gate.update_from(|_, line| Ok(psi(line, 5))).unwrap());
+        assert!(gate.should_admit());
+    }
+
+    #[test]
+    fn should_read_each_line_once() {
+        let gate = PressureGate::new(
+            PressureGateConfig::default()
+                .threshold(threshold(PsiKind::IO, PsiLine::Some, 50, 40))
+                .threshold(threshold(PsiKind::IO, PsiLine::Full, 20, 10))
+                .threshold(threshold(PsiKind::IO, PsiLine::Full, 40, 30)),
+        );
+        let mut reads = Vec::new();
+        let shedding = gate
+            .update_from(|kind, line| {
+                reads.push((kind, line));
+                Ok(psi(line, 30))
+            })
+            .unwrap();
+        assert!(shedding);
+        assert_eq!(
+            reads,
+            vec![(PsiKind::IO, PsiLine::Some), (PsiKind::IO, PsiLine::Full)]
+        );
+    }
+
+    #[test]
+    fn should_refresh_cpu_some_and_irq_full_only() {
+        let dir = TempDir::new().unwrap();
+        let cgroup = fake_cgroup(dir.path(), "");
+        // kernels before 5.13 have no cpu full line, and irq has no some line
+        write_pressure(
+            &cgroup,
+            PsiKind::CPU,
+            &pressure_line(PsiLine::Some, "30.00", 0),
+        );
+        write_pressure(
+            &cgroup,
+            PsiKind::IRQ,
+            &pressure_line(PsiLine::Full, "5.00", 0),
+        );
+        let gate = PressureGate::new(
+            PressureGateConfig::default()
+                .cgroup(&cgroup)
+                .threshold(threshold(PsiKind::CPU, PsiLine::Some, 50, 40))
+                .threshold(threshold(PsiKind::IRQ, PsiLine::Full, 10, 5)),
+        );
+        assert!(!gate.refresh().unwrap());
+
+        write_pressure(
+            &cgroup,
+            PsiKind::IRQ,
+            &pressure_line(PsiLine::Full, "12.00", 0),
+        );
+        assert!(gate.refresh().unwrap());
+    }
+}
@@ -0,0 +1,376 @@
+//! Aggregated health score
+//!
+//! [`HealthScorer`] condenses a [`PsiSnapshot`] into a single 0–100 score
+//! and a [`HealthLevel`], for dashboards and load balancer health checks.
+//!
+//! Each configured pressure line contributes a penalty of
+//! `weight * min(1, pressure / critical) ^ exponent`, and the score is the
+//! product of what every penalty leaves behind. An exponent below 1 makes
+//! small pressures count for more, one above 1 forgives them. A single line
+//! with a weight of 1 at its critical pressure drives the score to 0.
+
+use std::fmt;
+use std::path::Path;
+
+use crate::error::*;
+use crate::psi::*;
+use crate::snapshot::PsiSnapshot;
+
+/// Which kernel average the score is computed from
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum HealthAverage {
+    Avg10,
+    Avg60,
+    Avg300,
+}
+
+impl HealthAverage {
+    fn of(&self, psi: &Psi) -> PsiPercent {
+        match self {
+            HealthAverage::Avg10 => psi.avg10,
+            HealthAverage::Avg60 => psi.avg60,
+            HealthAverage::Avg300 => psi.avg300,
+        }
+    }
+}
+
+/// How much a pressure line counts towards the score
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HealthWeight {
+    pub kind: PsiKind,
+    pub line: PsiLine,
+    /// Largest share of the score, from 0 to 1, this line can take away
+    pub weight: f64,
+    /// Pressure at which the full weight is taken away
+    pub critical: PsiPercent,
+}
+
+impl HealthWeight {
+    pub fn new(kind: PsiKind, line: PsiLine, weight: f64, critical: PsiPercent) -> Self {
+        HealthWeight {
+            kind,
+            line,
+            weight,
+            critical,
+        }
+    }
+}
+
+/// Weight whose `critical` pressure is a whole percentage
+fn weight(kind: PsiKind, line: PsiLine, weight: f64, critical: u32) -> HealthWeight {
+    HealthWeight::new(
+        kind,
+        line,
+        weight,
+        PsiPercent::from_hundredths(critical * 100),
+    )
+}
+
+/// Health scorer configuration
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HealthConfig {
+    pub weights: Vec<HealthWeight>,
+    pub average: HealthAverage,
+    /// Shape of each line's penalty curve
+    pub exponent: f64,
+    /// Scores below this are [`HealthLevel::Degraded`]
+    pub degraded_below: u8,
+    /// Scores below this are [`HealthLevel::Critical`]
+    pub critical_below: u8,
+    /// Smallest penalty, in score points, reported as a reason
+    pub min_reason: f64,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            weights: vec![
+                weight(PsiKind::Memory, PsiLine::Some, 0.5, 40),
+                weight(PsiKind::Memory, PsiLine::Full, 1.0, 20),
+                weight(PsiKind::IO, PsiLine::Some, 0.3, 60),
+                weight(PsiKind::IO, PsiLine::Full, 0.8, 30),
+                weight(PsiKind::CPU, PsiLine::Some, 0.5, 80),
+                weight(PsiKind::CPU, PsiLine::Full, 0.8, 40),
+            ],
+            average: HealthAverage::Avg10,
+            exponent: 0.5,
+            degraded_below: 80,
+            critical_below: 50,
+            min_reason: 1.0,
+        }
+    }
+}
+
+impl HealthConfig {
+    /// Replace the weight of a line, or add it
+    pub fn weight(mut self, weight: HealthWeight) -> Self {
+        self.weights
+            .retain(|w| (w.kind, w.line) != (weight.kind, weight.line));
+        self.weights.push(weight);
+        self
+    }
+
+    pub fn average(mut self, average: HealthAverage) -> Self {
+        self.average = average;
+        self
+    }
+
+    pub fn exponent(mut self, exponent: f64) -> Self {
+        self.exponent = exponent;
+        self
+    }
+
+    pub fn degraded_below(mut self, degraded_below: u8) -> Self {
+        self.degraded_below = degraded_below;
+        self
+    }
+
+    pub fn critical_below(mut self, critical_below: u8) -> Self {
+        self.critical_below = critical_below;
+        self
+    }
+
+    pub fn min_reason(mut self, min_reason: f64) -> Self {
+        self.min_reason = min_reason;
+        self
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum HealthLevel {
+    Healthy,
+    Degraded,
+    Critical,
+}
+
+impl fmt::Display for HealthLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HealthLevel::Healthy => write!(f, "healthy"),
+            HealthLevel::Degraded => write!(f, "degraded"),
+            HealthLevel::Critical => write!(f, "critical"),
+        }
+    }
+}
+
+/// A pressure line which lowered the score
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HealthReason {
+    pub kind: PsiKind,
+    pub line: PsiLine,
+    pub pressure: PsiPercent,
+    /// Score points taken away by this line
+    pub penalty: f64,
+}
+
+impl fmt::Display for HealthReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {}% (-{:.0})",
+            self.kind, self.line, self.pressure, self.penalty
+        )
+    }
+}
+
+/// Score, level and the reasons for them
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HealthReport {
+    /// 100 with no pressure, down to 0
+    pub score: u8,
+    pub level: HealthLevel,
+    /// Lines which lowered the score, largest penalty first
+    pub reasons: Vec<HealthReason>,
+}
+
+impl fmt::Display for HealthReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (score {})", self.level, self.score)?;
+        for (i, reason) in self.reasons.iter().enumerate() {
+            write!(f, "{}{}", if i == 0 { ": " } else { ", " }, reason)?;
+        }
+        Ok(())
+    }
+}
+
+/// Computes [`HealthReport`]s from pressure snapshots
+#[derive(Debug, Clone, Default)]
+pub struct HealthScorer {
+    config: HealthConfig,
+}
+
+impl HealthScorer {
+    pub fn new(config: HealthConfig) -> Self {
+        HealthScorer { config }
+    }
+
+    pub fn config(&self) -> &HealthConfig {
+        &self.config
+    }
+
+    /// Score system-wide pressure
+    pub fn read(&self) -> Result<HealthReport> {
+        Ok(self.score(&PsiSnapshot::read_all()?))
+    }
+
+    /// Score the pressure of a cgroup2 directory
+    pub fn read_cgroup<P: AsRef<Path>>(&self, cgroup: P) -> Result<HealthReport> {
+        Ok(self.score(&PsiSnapshot::read_cgroup(cgroup)?))
+    }
+
+    /// Score a snapshot; lines missing from it, such as CPU `full` before
+    /// Linux 5.13, are ignored
+    pub fn score(&self, snapshot: &PsiSnapshot) -> HealthReport {
+        let mut remaining = 1.0;
+        let mut reasons = Vec::new();
+        for weight in &self.config.weights {
+            let psi = match snapshot.line(weight.kind, weight.line) {
+                Some(psi) => psi,
+                None => continue,
+            };
+            let pressure = self.config.average.of(psi);
+            let penalty = self.penalty(weight, pressure);
+            // points taken from what earlier lines left
+            let points = remaining * penalty * 100.0;
+            remaining *= 1.0 - penalty;
+            if points >= self.config.min_reason {
+                reasons.push(HealthReason {
+                    kind: weight.kind,
+                    line: weight.line,
+                    pressure,
+                    penalty: points,
+                });
+            }
+        }
+        reasons.sort_by(|a, b| {
+            b.penalty
+                .partial_cmp(&a.penalty)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        let score = (remaining * 100.0).round().clamp(0.0, 100.0) as u8;
+        let level = if score < self.config.critical_below {
+            HealthLevel::Critical
+        } else if score < self.config.degraded_below {
+            HealthLevel::Degraded
+        } else {
+            HealthLevel::Healthy
+        };
+        HealthReport {
+            score,
+            level,
+            reasons,
+        }
+    }
+
+    fn penalty(&self, weight: &HealthWeight, pressure: PsiPercent) -> f64 {
+        let critical = weight.critical.as_f64().max(0.01);
+        let ratio = (pressure.as_f64() / critical).min(1.0);
+        weight.weight.clamp(0.0, 1.0) * ratio.powf(self.config.exponent)
+    }
+}
+
+impl From<HealthConfig> for HealthScorer {
+    fn from(config: HealthConfig) -> Self {
+        HealthScorer::new(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{fake_cgroup, pressure_line, write_pressure};
+    use std::time::{Duration, SystemTime};
+
+    fn snapshot(memory_full: f32, io_some: f32) -> PsiSnapshot {
+        let psi = |line, avg10| Psi {
+            line,
+            avg10: PsiPercent::from_f32(avg10),
+            avg60: PsiPercent::ZERO,
+            avg300: PsiPercent::ZERO,
+            total: Duration::from_micros(0),
+        };
+        let all = |some, full| AllPsiStats {
+            some: psi(PsiLine::Some, some),
+            full: psi(PsiLine::Full, full),
+        };
+        PsiSnapshot {
+            timestamp: SystemTime::now(),
+            cpu_some: psi(PsiLine::Some, 0.0),
+            cpu_full: Some(psi(PsiLine::Full, 0.0)),
+            io: all(io_some, 0.0),
+            memory: all(memory_full, memory_full),
+            irq: None,
+        }
+    }
+
+    fn scorer() -> HealthScorer {
+        HealthScorer::new(HealthConfig {
+            weights: vec![
+                weight(PsiKind::Memory, PsiLine::Full, 1.0, 20),
+                weight(PsiKind::IO, PsiLine::Some, 0.5, 50),
+            ],
+            exponent: 1.0,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn should_score_and_classify() {
+        let scorer = scorer();
+        let healthy = scorer.score(&snapshot(0.0, 0.0));
+        assert_eq!(healthy.score, 100);
+        assert_eq!(healthy.level, HealthLevel::Healthy);
+        assert!(healthy.reasons.is_empty());
+        assert_eq!(healthy.to_string(), "healthy (score 100)");
+
+        // memory takes 25 points, io half of the remaining 75 at 50%
+        let pressured = scorer.score(&snapshot(5.0, 50.0));
+        assert_eq!(pressured.score, 38);
+        assert_eq!(pressured.level, HealthLevel::Critical);
+        assert_eq!(pressured.reasons[0].kind, PsiKind::IO);
+        assert_eq!(
+            pressured.to_string(),
+            "critical (score 38): io some 50.00% (-38), memory full 5.00% (-25)"
+        );
+
+        let critical = scorer.score(&snapshot(40.0, 0.0));
+        assert_eq!(critical.score, 0);
+        assert_eq!(critical.level, HealthLevel::Critical);
+    }
+
+    #[test]
+    fn should_weigh_small_pressure_more_with_low_exponent() {
+        let linear = scorer().score(&snapshot(2.0, 0.0)).score;
+        let config = scorer().config().clone().exponent(0.5);
+        let concave = HealthScorer::new(config).score(&snapshot(2.0, 0.0));
+        assert_eq!(linear, 90);
+        assert_eq!(concave.score, 68);
+        assert_eq!(concave.level, HealthLevel::Degraded);
+    }
+
+    #[test]
+    fn should_ignore_missing_cpu_full_line() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let cgroup = fake_cgroup(dir.path(), "");
+        write_pressure(
+            &cgroup,
+            PsiKind::CPU,
+            &pressure_line(PsiLine::Some, "40.00", 0),
+        );
+
+        let report = HealthScorer::new(HealthConfig::default().exponent(1.0))
+            .read_cgroup(&cgroup)
+            .unwrap();
+        // cpu some at half its critical level takes half of its 0.5 weight
+        assert_eq!(report.score, 75);
+        assert_eq!(report.reasons.len(), 1);
+        assert_eq!(report.reasons[0].kind, PsiKind::CPU);
+        assert_eq!(report.reasons[0].line, PsiLine::Some);
+    }
+}
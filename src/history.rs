@@ -0,0 +1,285 @@
+//! Pressure history over arbitrary windows
+//!
+//! The kernel only reports 10s, 60s and 300s moving averages. [`PsiHistory`]
+//! keeps timestamped `total` stall counters in a ring buffer, from which the
+//! exact stall percentage over any window covered by the buffer can be
+//! computed, along with percentiles and maxima of the pressure between
+//! consecutive samples. [`PsiSampler`] feeds a history from a pressure file.
+
+use std::collections::VecDeque;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use log::*;
+
+use crate::error::*;
+use crate::psi::*;
+use crate::reader::PsiReader;
+
+/// Cumulative stall time at a point in time
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct PsiSample {
+    pub time: Instant,
+    pub total: Duration,
+}
+
+/// Fixed-size ring buffer of [`PsiSample`]s
+#[derive(Debug, Clone)]
+pub struct PsiHistory {
+    samples: VecDeque<PsiSample>,
+    capacity: usize,
+}
+
+impl PsiHistory {
+    /// Keep at most `capacity` samples, dropping the oldest first
+    ///
+    /// At least two samples are always kept, as any statistic needs an interval.
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(2);
+        PsiHistory {
+            samples: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    pub fn latest(&self) -> Option<PsiSample> {
+        self.samples.back().copied()
+    }
+
+    pub fn samples(&self) -> impl Iterator<Item = &PsiSample> {
+        self.samples.iter()
+    }
+
+    /// Record the stall total read at `time`
+    ///
+    /// Samples older than the latest are ignored. A total lower than the
+    /// latest means the counter was reset, e.g. the cgroup was recreated, so
+    /// the history starts over.
+    pub fn record(&mut self, time: Instant, total: Duration) {
+        if let Some(latest) = self.latest() {
+            if time < latest.time {
+                debug!("ignoring out of order sample");
+                return;
+            }
+            if total < latest.total {
+                debug!("stall total went backwards; clearing history");
+                self.samples.clear();
+            }
+        }
+        if self.samples.len() == self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(PsiSample { time, total });
+    }
+
+    /// Record the `total` of a parsed pressure line read at `time`
+    pub fn record_psi(&mut self, time: Instant, psi: &Psi) {
+        self.record(time, psi.total)
+    }
+
+    /// Exact share of the last `window` spent stalled, up to the latest sample
+    ///
+    /// The window starts at the newest sample at least `window` before the
+    /// latest, so may be slightly longer than requested. `None` if the history
+    /// doesn't reach back that far.
+    pub fn stall_percent(&self, window: Duration) -> Option<PsiPercent> {
+        let latest = self.latest()?;
+        let start = latest.time.checked_sub(window)?;
+        let first = self
+            .samples
+            .iter()
+            .rev()
+            .find(|sample| sample.time <= start)?;
+        Some(percent(first, &latest))
+    }
+
+    /// Pressure between each pair of consecutive samples in the last `window`
+    pub fn intervals(&self, window: Duration) -> impl Iterator<Item = PsiPercent> + '_ {
+        let start = self
+            .latest()
+            .and_then(|latest| latest.time.checked_sub(window));
+        let skip = match start {
+            Some(start) => self
+                .samples
+                .iter()
+                .take_while(|sample| sample.time < start)
+                .count(),
+            None => 0,
+        };
+        self.samples
+            .iter()
+            .skip(skip)
+            .zip(self.samples.iter().skip(skip + 1))
+            .map(|(a, b)| percent(a, b))
+    }
+
+    /// The `p`th percentile (0–100, nearest rank) of per-interval pressure in
+    /// the last `window`, e.g. 50 for the median or 99
+    pub fn percentile(&self, window: Duration, p: f64) -> Option<PsiPercent> {
+        let mut intervals: Vec<PsiPercent> = self.intervals(window).collect();
+        if intervals.is_empty() {
+            return None;
+        }
+        intervals.sort_unstable();
+        let rank = (p.clamp(0.0, 100.0) / 100.0 * intervals.len() as f64).ceil() as usize;
+        Some(intervals[rank.saturating_sub(1)])
+    }
+
+    /// Highest per-interval pressure in the last `window`
+    pub fn max(&self, window: Duration) -> Option<PsiPercent> {
+        self.intervals(window).max()
+    }
+}
+
+/// Share of the time between two samples spent stalled
+pub(crate) fn percent(from: &PsiSample, to: &PsiSample) -> PsiPercent {
+    let elapsed = to.time.duration_since(from.time).as_micros();
+    if elapsed == 0 {
+        return PsiPercent::ZERO;
+    }
+    let stalled = to.total.saturating_sub(from.total).as_micros();
+    let max = PsiPercent::MAX.hundredths() as u128;
+    // round to the nearest hundredth; samples read slightly out of step with
+    // their timestamps can exceed 100%
+    let hundredths = ((stalled * max + elapsed / 2) / elapsed).min(max);
+    PsiPercent::from_hundredths(hundredths as u32)
+}
+
+/// Reads a pressure line into a [`PsiHistory`]
+pub struct PsiSampler {
+    reader: PsiReader,
+    line: PsiLine,
+    history: PsiHistory,
+}
+
+impl PsiSampler {
+    /// Sample a line of an open pressure file, keeping `capacity` samples
+    pub fn new(reader: PsiReader, line: PsiLine, capacity: usize) -> Self {
+        PsiSampler {
+            reader,
+            line,
+            history: PsiHistory::new(capacity),
+        }
+    }
+
+    /// Sample a line of system-wide pressure
+    pub fn open(kind: PsiKind, line: PsiLine, capacity: usize) -> Result<Self> {
+        Ok(Self::new(PsiReader::open(kind)?, line, capacity))
+    }
+
+    /// Sample a line of a cgroup2 directory's pressure
+    pub fn cgroup<P: AsRef<Path>>(
+        kind: PsiKind,
+        line: PsiLine,
+        cgroup: P,
+        capacity: usize,
+    ) -> Result<Self> {
+        Ok(Self::new(PsiReader::cgroup(kind, cgroup)?, line, capacity))
+    }
+
+    pub fn line(&self) -> PsiLine {
+        self.line
+    }
+
+    pub fn history(&self) -> &PsiHistory {
+        &self.history
+    }
+
+    /// Read the pressure file and record the sample
+    pub fn sample(&mut self) -> Result<Psi> {
+        self.sample_at(Instant::now())
+    }
+
+    /// Read the pressure file and record the sample as taken at `now`
+    pub fn sample_at(&mut self, now: Instant) -> Result<Psi> {
+        let psi = self.reader.read_line(self.line)?;
+        self.history.record_psi(now, &psi);
+        Ok(psi)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{pressure, write_pressure};
+    use tempfile::TempDir;
+
+    fn history(totals_ms: &[u64]) -> (Instant, PsiHistory) {
+        let start = Instant::now();
+        let mut history = PsiHistory::new(totals_ms.len());
+        for (i, total) in totals_ms.iter().enumerate() {
+            history.record(
+                start + Duration::from_secs(i as u64),
+                Duration::from_millis(*total),
+            );
+        }
+        (start, history)
+    }
+
+    #[test]
+    fn should_compute_windowed_stall() {
+        // stalled 100ms, 0ms, 500ms, 250ms in consecutive seconds
+        let (_, history) = history(&[0, 100, 100, 600, 850]);
+        let percent = |secs| history.stall_percent(Duration::from_secs(secs));
+        assert_eq!(percent(1), Some(PsiPercent::from_hundredths(2500)));
+        assert_eq!(percent(2), Some(PsiPercent::from_hundredths(3750)));
+        assert_eq!(percent(4), Some(PsiPercent::from_hundredths(2125)));
+        assert_eq!(percent(5), None);
+
+        let window = Duration::from_secs(4);
+        assert_eq!(history.max(window), Some(PsiPercent::from_hundredths(5000)));
+        assert_eq!(
+            history.percentile(window, 50.0),
+            Some(PsiPercent::from_hundredths(1000))
+        );
+        assert_eq!(
+            history.percentile(window, 99.0),
+            Some(PsiPercent::from_hundredths(5000))
+        );
+        assert_eq!(
+            history.max(Duration::from_secs(1)),
+            Some(PsiPercent::from_hundredths(2500))
+        );
+    }
+
+    #[test]
+    fn should_evict_oldest_and_reset() {
+        let (start, mut history) = history(&[0, 100, 200]);
+        history.record(start + Duration::from_secs(3), Duration::from_millis(300));
+        assert_eq!(history.len(), 3);
+        assert_eq!(history.samples().next().unwrap().total.as_millis(), 100);
+
+        history.record(start + Duration::from_secs(4), Duration::from_millis(10));
+        assert_eq!(history.len(), 1);
+        assert_eq!(history.stall_percent(Duration::from_secs(1)), None);
+    }
+
+    #[test]
+    fn should_sample_pressure_file() {
+        let dir = TempDir::new().unwrap();
+        let write =
+            |total| write_pressure(dir.path(), PsiKind::IO, &pressure("0.00", total, "0.00", 0));
+        write(0);
+        let mut sampler = PsiSampler::cgroup(PsiKind::IO, PsiLine::Some, dir.path(), 60).unwrap();
+        let start = Instant::now();
+        sampler.sample_at(start).unwrap();
+        write(200_000);
+        sampler.sample_at(start + Duration::from_secs(2)).unwrap();
+        assert_eq!(
+            sampler.history().stall_percent(Duration::from_secs(2)),
+            Some(PsiPercent::from_hundredths(1000))
+        );
+    }
+}
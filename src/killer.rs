@@ -0,0 +1,435 @@
+//! Userspace OOM killer
+//!
+//! Watches memory `full` pressure of cgroup2 directories and, when a
+//! threshold is crossed, kills the leaf cgroup most likely to relieve that
+//! pressure via `cgroup.kill` (Linux 5.14+).
+
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use log::*;
+
+use crate::error::*;
+use crate::monitor::{PsiMonitor, TriggerMonitor};
+use crate::psi::*;
+use crate::trigger::*;
+
+const CGROUP2_ROOT: &str = "/sys/fs/cgroup";
+const MEMORY_CURRENT_FILE: &str = "memory.current";
+const CGROUP_KILL_FILE: &str = "cgroup.kill";
+
+/// OOM killer configuration
+#[derive(Debug, Clone)]
+pub struct OomKillerConfig {
+    /// cgroups to register memory `full` triggers on; victims are chosen
+    /// from the leaves beneath whichever one fired
+    pub watched: Vec<PathBuf>,
+    /// Threshold used for the trigger on each watched cgroup
+    pub threshold: TriggerThreshold,
+    /// cgroups which are never killed, along with their ancestors and descendants
+    pub protected: Vec<PathBuf>,
+    /// Log decisions without killing anything
+    pub dry_run: bool,
+    /// Time to ignore further pressure events after a kill, giving the kernel
+    /// averages a chance to recover
+    pub kill_cooldown: Duration,
+}
+
+impl Default for OomKillerConfig {
+    fn default() -> Self {
+        OomKillerConfig {
+            watched: vec![PathBuf::from(CGROUP2_ROOT)],
+            threshold: TriggerThreshold {
+                stall: Duration::from_millis(100),
+                window: Duration::from_millis(500),
+            },
+            protected: Vec::new(),
+            dry_run: false,
+            kill_cooldown: Duration::from_secs(10),
+        }
+    }
+}
+
+/// A cgroup considered for killing
+#[derive(Debug, Clone, PartialEq)]
+pub struct Victim {
+    pub cgroup: PathBuf,
+    /// Contents of `memory.current` in bytes
+    pub memory_current: u64,
+    /// Memory `some` pressure of the cgroup, if accounted
+    pub pressure: Option<Psi>,
+    /// Ranking score; the highest scoring candidate is killed
+    pub score: f64,
+}
+
+impl Victim {
+    fn read<P: AsRef<Path>>(cgroup: P) -> Result<Victim> {
+        let cgroup = cgroup.as_ref();
+        let path = cgroup.join(MEMORY_CURRENT_FILE);
+        let memory_current = fs::read_to_string(&path)
+            .context(Operation::Read, &path)?
+            .trim()
+            .parse::<u64>()?;
+        let pressure = match PsiKind::Memory.read_cgroup_psi_line(cgroup, PsiLine::Some) {
+            Ok(psi) => Some(psi),
+            Err(ref e) if e.is_not_found() => None,
+            Err(e) => return Err(e),
+        };
+        // memory.current scaled by how much the cgroup itself is stalling,
+        // so a cgroup at 10% some pressure counts double
+        let avg10 = pressure.as_ref().map_or(0f64, |psi| psi.avg10.as_f64());
+        let score = memory_current as f64 * (1.0 + avg10 / 10.0);
+        Ok(Victim {
+            cgroup: cgroup.to_path_buf(),
+            memory_current,
+            pressure,
+            score,
+        })
+    }
+}
+
+impl fmt::Display for Victim {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} (memory.current={} score={:.0}",
+            self.cgroup.display(),
+            self.memory_current,
+            self.score
+        )?;
+        match &self.pressure {
+            Some(psi) => write!(f, " pressure: {})", psi),
+            None => write!(f, ")"),
+        }
+    }
+}
+
+/// Userspace OOM killer
+///
+/// Ranks leaf cgroups by `memory.current` and memory pressure, then kills
+/// the whole cgroup. Every decision is logged.
+pub struct OomKiller {
+    config: OomKillerConfig,
+    last_kill: Option<Instant>,
+}
+
+impl OomKiller {
+    pub fn new(config: OomKillerConfig) -> Self {
+        OomKiller {
+            config,
+            last_kill: None,
+        }
+    }
+
+    pub fn config(&self) -> &OomKillerConfig {
+        &self.config
+    }
+
+    /// Whether a cgroup is covered by the protection list
+    ///
+    /// Ancestors of a protected cgroup are protected too, as killing them
+    /// would kill the protected cgroup.
+    pub fn is_protected<P: AsRef<Path>>(&self, cgroup: P) -> bool {
+        let cgroup = cgroup.as_ref();
+        self.config
+            .protected
+            .iter()
+            .any(|p| cgroup.starts_with(p) || p.starts_with(cgroup))
+    }
+
+    /// All unprotected leaf cgroups beneath `scope`, highest score first
+    pub fn candidates<P: AsRef<Path>>(&self, scope: P) -> Result<Vec<Victim>> {
+        let mut leaves = Vec::new();
+        collect_leaves(scope.as_ref(), &mut leaves)?;
+        let mut candidates = Vec::with_capacity(leaves.len());
+        for leaf in leaves {
+            if self.is_protected(&leaf) {
+                debug!("skipping protected cgroup {}", leaf.display());
+                continue;
+            }
+            match Victim::read(&leaf) {
+                Ok(victim) => {
+                    debug!("candidate {}", victim);
+                    candidates.push(victim);
+                }
+                Err(ref e) if e.is_not_found() => {
+                    debug!("cgroup {} went away while scanning", leaf.display());
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        candidates.sort_by(|a, b| {
+            b.score
+                .partial_cmp(&a.score)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        Ok(candidates)
+    }
+
+    /// Choose the cgroup to kill to relieve pressure on `scope`
+    pub fn select_victim<P: AsRef<Path>>(&self, scope: P) -> Result<Option<Victim>> {
+        Ok(self.candidates(scope)?.into_iter().next())
+    }
+
+    /// Kill every process in the victim's cgroup, or only log it in dry-run mode
+    pub fn kill(&mut self, victim: &Victim) -> Result<()> {
+        self.last_kill = Some(Instant::now());
+        if self.config.dry_run {
+            warn!("dry run: would kill {}", victim);
+            return Ok(());
+        }
+        warn!("killing {}", victim);
+        let kill_path = victim.cgroup.join(CGROUP_KILL_FILE);
+        let result = OpenOptions::new()
+            .write(true)
+            .open(&kill_path)
+            .and_then(|mut file| file.write_all(b"1"));
+        match result {
+            Ok(()) => {
+                info!("killed {}", victim.cgroup.display());
+                Ok(())
+            }
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {
+                info!("cgroup {} already gone", victim.cgroup.display());
+                Ok(())
+            }
+            Err(e) => {
+                error!("failed to kill {}: {}", victim.cgroup.display(), e);
+                Err(PsiError::io(Operation::Write, &kill_path, e))
+            }
+        }
+    }
+
+    /// React to pressure on `scope`, returning the victim if one was chosen
+    ///
+    /// Does nothing while within the cooldown of a previous kill.
+    pub fn handle_pressure<P: AsRef<Path>>(&mut self, scope: P) -> Result<Option<Victim>> {
+        let scope = scope.as_ref();
+        if let Some(last_kill) = self.last_kill {
+            if last_kill.elapsed() < self.config.kill_cooldown {
+                info!(
+                    "pressure on {} ignored; within cooldown of previous kill",
+                    scope.display()
+                );
+                return Ok(None);
+            }
+        }
+        match self.select_victim(scope)? {
+            None => {
+                warn!(
+                    "pressure on {} but no killable cgroup found",
+                    scope.display()
+                );
+                Ok(None)
+            }
+            Some(victim) => {
+                info!("pressure on {}; selected {}", scope.display(), victim);
+                self.kill(&victim)?;
+                Ok(Some(victim))
+            }
+        }
+    }
+
+    /// Register a memory `full` trigger on each watched cgroup and handle
+    /// pressure events until no watched cgroup is left
+    ///
+    /// Errors handling an event are logged and the next event is waited for.
+    /// Watched cgroups whose triggers fail are dropped.
+    pub fn run(&mut self) -> Result<()> {
+        self.run_with_monitor(&mut PsiMonitor::new()?)
+    }
+
+    /// [`run`](Self::run), registering the triggers with `monitor`
+    pub fn run_with_monitor<M: TriggerMonitor>(&mut self, monitor: &mut M) -> Result<()> {
+        let mut triggers = Vec::with_capacity(self.config.watched.len());
+        for cgroup in &self.config.watched {
+            let id = monitor.add_trigger(
+                Trigger::new_builder()
+                    .memory()
+                    .cgroup(cgroup)
+                    .full()
+                    .threshold(self.config.threshold.clone())
+                    .build(),
+            )?;
+            triggers.push((id, cgroup.clone()));
+        }
+        while !triggers.is_empty() {
+            let event = match monitor.wait_single() {
+                Ok(event) => event,
+                // the kernel signals an error on triggers of a removed cgroup
+                Err(PsiTriggerFileError { trigger, kind }) => {
+                    let cgroup = trigger.target_file_path.parent();
+                    match triggers
+                        .iter()
+                        .position(|(_, c)| Some(c.as_path()) == cgroup)
+                    {
+                        Some(index) => {
+                            let (id, cgroup) = triggers.remove(index);
+                            warn!("watched cgroup {} went away", cgroup.display());
+                            monitor.remove_trigger(id)?;
+                            continue;
+                        }
+                        None => return Err(PsiTriggerFileError { trigger, kind }),
+                    }
+                }
+                Err(e) => return Err(e),
+            };
+            info!("{}", event);
+            let scope = event
+                .trigger
+                .target_file_path
+                .parent()
+                .map(Path::to_path_buf)
+                .unwrap_or_else(|| PathBuf::from(CGROUP2_ROOT));
+            if let Err(e) = self.handle_pressure(&scope) {
+                warn!("failed to handle pressure on {}: {}", scope.display(), e);
+            }
+        }
+        info!("no watched cgroups left");
+        Ok(())
+    }
+}
+
+fn collect_leaves(cgroup: &Path, leaves: &mut Vec<PathBuf>) -> Result<()> {
+    let entries = match fs::read_dir(cgroup) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(PsiError::io(Operation::ReadDir, cgroup, e)),
+    };
+    let mut has_children = false;
+    for entry in entries {
+        let entry = entry.context(Operation::ReadDir, cgroup)?;
+        if entry
+            .file_type()
+            .context(Operation::ReadDir, &entry.path())?
+            .is_dir()
+        {
+            has_children = true;
+            collect_leaves(&entry.path(), leaves)?;
+        }
+    }
+    if !has_children {
+        leaves.push(cgroup.to_path_buf());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{self, pressure, write_pressure, ScriptedMonitor, Step};
+    use tempfile::TempDir;
+
+    fn fake_cgroup(root: &Path, name: &str, memory_current: u64, avg10: &str) -> PathBuf {
+        let path = testutil::fake_cgroup(root, name);
+        fs::write(
+            path.join(MEMORY_CURRENT_FILE),
+            format!("{}\n", memory_current),
+        )
+        .unwrap();
+        write_pressure(&path, PsiKind::Memory, &pressure(avg10, 0, "0.00", 0));
+        fs::write(path.join(CGROUP_KILL_FILE), "").unwrap();
+        path
+    }
+
+    fn killer(protected: Vec<PathBuf>, dry_run: bool) -> OomKiller {
+        OomKiller::new(OomKillerConfig {
+            protected,
+            dry_run,
+            kill_cooldown: Duration::from_secs(0),
+            ..OomKillerConfig::default()
+        })
+    }
+
+    #[test]
+    fn should_rank_leaves_by_memory_and_pressure() {
+        let root = TempDir::new().unwrap();
+        fake_cgroup(root.path(), "a.slice/big.service", 1000, "0.00");
+        let pressured = fake_cgroup(root.path(), "a.slice/pressured.service", 600, "10.00");
+        fake_cgroup(root.path(), "small.service", 100, "0.00");
+
+        let victim = killer(vec![], false)
+            .select_victim(root.path())
+            .unwrap()
+            .unwrap();
+        assert_eq!(victim.cgroup, pressured);
+        assert_eq!(victim.memory_current, 600);
+    }
+
+    #[test]
+    fn should_skip_protected_cgroups() {
+        let root = TempDir::new().unwrap();
+        let big = fake_cgroup(root.path(), "a.slice/big.service", 1000, "0.00");
+        let small = fake_cgroup(root.path(), "b.slice/small.service", 100, "0.00");
+
+        let killer = killer(vec![big.clone()], false);
+        assert!(killer.is_protected(root.path().join("a.slice")));
+        let candidates = killer.candidates(root.path()).unwrap();
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].cgroup, small);
+    }
+
+    #[test]
+    fn should_write_cgroup_kill() {
+        let root = TempDir::new().unwrap();
+        let victim = fake_cgroup(root.path(), "victim.service", 1000, "0.00");
+
+        let chosen = killer(vec![], false).handle_pressure(root.path()).unwrap();
+        assert_eq!(chosen.unwrap().cgroup, victim);
+        assert_eq!(
+            fs::read_to_string(victim.join(CGROUP_KILL_FILE)).unwrap(),
+            "1"
+        );
+    }
+
+    #[test]
+    fn should_not_kill_in_dry_run() {
+        let root = TempDir::new().unwrap();
+        let victim = fake_cgroup(root.path(), "victim.service", 1000, "0.00");
+
+        let chosen = killer(vec![], true).handle_pressure(root.path()).unwrap();
+        assert_eq!(chosen.unwrap().cgroup, victim);
+        assert_eq!(
+            fs::read_to_string(victim.join(CGROUP_KILL_FILE)).unwrap(),
+            ""
+        );
+    }
+
+    #[test]
+    fn should_respect_cooldown() {
+        let root = TempDir::new().unwrap();
+        fake_cgroup(root.path(), "victim.service", 1000, "0.00");
+
+        let mut killer = OomKiller::new(OomKillerConfig {
+            dry_run: true,
+            ..OomKillerConfig::default()
+        });
+        assert!(killer.handle_pressure(root.path()).unwrap().is_some());
+        assert!(killer.handle_pressure(root.path()).unwrap().is_none());
+    }
+
+    #[test]
+    fn should_keep_running_until_watched_cgroups_are_gone() {
+        let root = TempDir::new().unwrap();
+        let victim = fake_cgroup(root.path(), "a/victim.service", 1000, "0.00");
+        let broken = fake_cgroup(root.path(), "b/broken.service", 1000, "0.00");
+        fs::write(broken.join(MEMORY_CURRENT_FILE), "garbage").unwrap();
+
+        let mut killer = killer(vec![], false);
+        killer.config.watched = vec![root.path().join("a"), root.path().join("b")];
+        let mut monitor = ScriptedMonitor::new(vec![
+            Step::Pressure(1),
+            Step::Pressure(0),
+            Step::Gone(1),
+            Step::Gone(0),
+        ]);
+        killer.run_with_monitor(&mut monitor).unwrap();
+        assert_eq!(
+            fs::read_to_string(victim.join(CGROUP_KILL_FILE)).unwrap(),
+            "1"
+        );
+    }
+}
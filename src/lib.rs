@@ -3,9 +3,10 @@
 //! # About
 //!
 //! The Linux [Pressure Stall Information (PSI)] feature provides real-time
-//! pressure information for CPU, IO and memory.
+//! pressure information for CPU, IO, memory and IRQ.
 //! [psi] is a rust library for reading PSI and monitoring for pressure
 //! thresholds on Linux 4.20+.
+//! Pressure of individual cgroup2 directories can be read and monitored too.
 //!
 //! # Example
 //!
@@ -49,22 +50,79 @@
 //! }
 //! ```
 //!
-//! # TODO
-//! * cgroup2 support
-//!
 //! [psi]: https://crates.io/crates/psi
 //! [Pressure Stall Information (PSI)]: https://www.kernel.org/doc/html/latest/accounting/psi.html
 
+pub mod backoff;
+pub mod capabilities;
+pub mod cgroup;
 pub mod error;
+pub mod gate;
+pub mod health;
+pub mod history;
+#[cfg (feature = "monitor")]
+pub mod killer;
 #[cfg (feature = "monitor")]
 pub mod monitor;
+pub mod parse;
 pub mod psi;
+pub mod reader;
+pub mod reclaim;
+pub mod recorder;
+#[cfg (feature = "monitor")]
+pub mod simulate;
+pub mod snapshot;
+pub mod source;
+#[cfg (feature = "systemd")]
+pub mod systemd;
+#[cfg (feature = "monitor")]
+pub mod state;
+#[cfg (test)]
+mod testutil;
+#[cfg (feature = "monitor")]
+pub mod throttle;
 #[cfg (feature = "monitor")]
 pub mod trigger;
+pub mod walker;
+#[cfg (feature = "monitor")]
+pub mod watcher;
 
-pub use crate::psi::{AllPsiStats, Psi, PsiKind, PsiLine};
-pub use error::{PsiError, Result};
+pub use crate::backoff::{
+    wait_until_pressure_below, BackoffSignal, PressureBackoff, PressureBackoffConfig,
+};
+pub use crate::parse::{ParseMode, PsiParser};
+pub use crate::psi::{AllPsiStats, Psi, PsiKind, PsiLine, PsiPercent};
+pub use crate::reader::PsiReader;
+pub use crate::reclaim::{ReclaimAction, ReclaimConfig, ReclaimController, ReclaimMode};
+pub use crate::recorder::{
+    PsiEventRecord, PsiRecord, PsiRecorder, PsiRecorderConfig, PsiSampleRecord, PsiTraceReader,
+};
+pub use crate::snapshot::{PsiSnapshot, PsiSnapshotReader};
+pub use crate::source::{MockClock, PsiSource, ReplaySource, SystemPsi};
+pub use crate::walker::{CgroupPressure, CgroupPressureWalker, WalkMetric};
+pub use capabilities::PsiCapabilities;
+pub use error::{Operation, PsiError, PsiErrorKind, Result};
+pub use gate::{GateThreshold, PressureGate, PressureGateConfig};
+pub use health::{
+    HealthAverage, HealthConfig, HealthLevel, HealthReason, HealthReport, HealthScorer, HealthWeight,
+};
+pub use history::{PsiHistory, PsiSample, PsiSampler};
 #[cfg (feature = "monitor")]
-pub use monitor::{PsiEvent, PsiMonitor, TriggerId};
+pub use monitor::{PsiEvent, PsiMonitor, TriggerId, TriggerMonitor};
+#[cfg (feature = "monitor")]
+pub use killer::{OomKiller, OomKillerConfig};
+#[cfg (feature = "monitor")]
+pub use simulate::SimulatedMonitor;
+#[cfg (feature = "monitor")]
+pub use state::{PressureEvent, PressureState, PressureStateConfig, PressureTransition};
+#[cfg (feature = "systemd")]
+pub use systemd::Notifier;
+#[cfg (feature = "monitor")]
+pub use throttle::{
+    CpuAdjustment, CpuControl, CpuLimit, CpuThrottleConfig, CpuThrottler, DeviceNumber, IoAdjustment,
+    IoControl, IoLimit, IoMax, IoStat, IoThrottleConfig, IoThrottler,
+};
 #[cfg (feature = "monitor")]
 pub use trigger::Trigger;
+#[cfg (feature = "monitor")]
+pub use watcher::{CgroupWatcher, WatchEvent};
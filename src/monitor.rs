@@ -1,17 +1,25 @@
 use std::collections::hash_map::*;
+use std::collections::VecDeque;
+use std::convert::TryFrom;
 use std::fmt;
-use std::fs::{File, OpenOptions};
+use std::fs::{read_link, File, OpenOptions};
 use std::io::SeekFrom::Start;
-use std::io::{Read, Seek, Write};
+use std::io::{self, ErrorKind, Read, Seek, Write};
 use std::os::unix::io::*;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
 use epoll::*;
 use log::*;
 
 use crate::error::*;
+use crate::parse::parse_line;
 use crate::psi::*;
 use crate::trigger::*;
 
+/// Most events handled per `epoll_wait`
+const MAX_EVENTS: usize = 8;
+
 /// PSI event
 pub struct PsiEvent {
     /// PSI stats as read after the event fired
@@ -32,6 +40,33 @@ impl fmt::Display for PsiEvent {
     }
 }
 
+/// Something triggers can be registered with and waited on
+///
+/// Implemented by [`PsiMonitor`] and, for testing trigger policies without
+/// real pressure, [`SimulatedMonitor`](crate::simulate::SimulatedMonitor).
+pub trait TriggerMonitor {
+    fn add_trigger(&mut self, trigger: Trigger) -> Result<TriggerId>;
+
+    fn remove_trigger(&mut self, id: TriggerId) -> Result<Trigger>;
+
+    /// Wait up to `timeout` for an event, `None` if none fired
+    fn wait_timeout(&mut self, timeout: Duration) -> Result<Option<PsiEvent>>;
+
+    /// Current time on the clock the monitor's triggers run on
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    /// Wait for the next event, however long it takes
+    fn wait_single(&mut self) -> Result<PsiEvent> {
+        loop {
+            if let Some(event) = self.wait_timeout(Duration::from_secs(u64::from(u32::MAX)))? {
+                return Ok(event);
+            }
+        }
+    }
+}
+
 struct PsiTriggerTarget {
     trigger: Trigger,
     file: File,
@@ -44,15 +79,18 @@ struct PsiTriggerTarget {
 pub struct PsiMonitor {
     epoll_fd: RawFd,
     triggers: HashMap<RawFd, PsiTriggerTarget>,
+    /// Events returned by epoll but not yet handled
+    ready: VecDeque<Event>,
 }
 
 impl PsiMonitor {
     /// Create a PsiMonitor instance.
     pub fn new() -> Result<Self> {
-        let epoll_fd = create(false)?;
+        let epoll_fd = create(false).op_context(Operation::Epoll)?;
         Ok(PsiMonitor {
             epoll_fd,
             triggers: HashMap::new(),
+            ready: VecDeque::new(),
         })
     }
 
@@ -61,23 +99,28 @@ impl PsiMonitor {
     /// Registers a threshold with the kernel and uses epoll to handle events
     /// the kernel produces when the threshold is reached.
     pub fn add_trigger(&mut self, trigger: Trigger) -> Result<TriggerId> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("add_trigger", trigger = %trigger).entered();
         let mut file = OpenOptions::new()
             .read(true)
             .write(true)
-            .open(&trigger.target_file_path)?;
+            .open(&trigger.target_file_path)
+            .map_err(|e| PsiError::trigger(Operation::Open, &trigger, e))?;
         info!("registering {}", &trigger);
         debug!("trigger: {:?}", trigger.generate_trigger());
         debug!(
             "trigger bytes: {:?}",
             trigger.generate_trigger().as_bytes_with_nul()
         );
-        file.write_all(trigger.generate_trigger().as_bytes_with_nul())?;
+        file.write_all(trigger.generate_trigger().as_bytes_with_nul())
+            .map_err(|e| PsiError::trigger(Operation::WriteTrigger, &trigger, e))?;
         info!("successfully registered {}", trigger);
         let raw_fd = file.as_raw_fd();
 
         // add event to epoll
         let event = Event::new(Events::EPOLLPRI, raw_fd as u64);
-        ctl(self.epoll_fd, ControlOptions::EPOLL_CTL_ADD, raw_fd, event)?;
+        ctl(self.epoll_fd, ControlOptions::EPOLL_CTL_ADD, raw_fd, event)
+            .map_err(|e| PsiError::trigger(Operation::Epoll, &trigger, e))?;
 
         let target = PsiTriggerTarget {
             file,
@@ -88,42 +131,289 @@ impl PsiMonitor {
         Ok(TriggerId { raw_fd })
     }
 
+    /// Remove a trigger from the monitor
+    ///
+    /// Closing the trigger's file unregisters it with the kernel.
+    pub fn remove_trigger(&mut self, id: TriggerId) -> Result<Trigger> {
+        let target = self.triggers.remove(&id.raw_fd).ok_or(UnknownTrigger)?;
+        self.ready.retain(|event| event.data != id.raw_fd as u64);
+        info!("unregistering {}", target.trigger);
+        let event = Event::new(Events::empty(), 0);
+        if let Err(e) = ctl(
+            self.epoll_fd,
+            ControlOptions::EPOLL_CTL_DEL,
+            id.raw_fd,
+            event,
+        ) {
+            warn!("failed to remove {} from epoll: {}", target.trigger, e);
+        }
+        Ok(target.trigger)
+    }
+
     /// Wait for a PSI pressure event to fire based on some previously added trigger(s).
     pub fn wait_single(&mut self) -> Result<PsiEvent> {
-        debug!("waiting for psi event");
-        let mut event_buf = [Event { events: 0, data: 0 }];
-        let n = wait(self.epoll_fd, -1, &mut event_buf)?;
-        assert_eq!(n, 1);
-        let event = event_buf[0];
-        let fd = event.data as RawFd;
-        assert_ne!(fd, 0);
-        match self.triggers.get_mut(&fd) {
-            None => Err(UnregisteredEvent),
-            Some(target) => {
-                info!("psi event triggered: {}", target.trigger);
-                if event.events & Events::EPOLLERR.bits() != 0 {
-                    error!("error on watched psi file");
-                    return Err(PsiTriggerFileError);
-                }
-                target.buf.clear();
-                debug!("reading contents of file {:?}", target.file.file_path());
-                target.file.seek(Start(0))?;
-                target.file.read_to_string(&mut target.buf)?;
-                debug!("psi: {}", target.buf);
-
-                let stats = target.buf.parse()?;
-                Ok(PsiEvent {
-                    stats,
-                    trigger: target.trigger.clone(),
-                    id: TriggerId { raw_fd: fd },
-                })
+        loop {
+            if let Some(event) = self.wait_event(-1)? {
+                return Ok(event);
+            }
+        }
+    }
+
+    /// Wait up to `timeout` for a PSI pressure event to fire.
+    ///
+    /// Returns `None` if no event fired before the timeout elapsed.
+    pub fn wait_timeout(&mut self, timeout: Duration) -> Result<Option<PsiEvent>> {
+        let timeout_ms = timeout.as_millis().min(i32::MAX as u128) as i32;
+        self.wait_event(timeout_ms)
+    }
+
+    fn wait_event(&mut self, timeout_ms: i32) -> Result<Option<PsiEvent>> {
+        let epoll_fd = self.epoll_fd;
+        self.next_event(timeout_ms, |timeout_ms, events| {
+            wait(epoll_fd, timeout_ms, events)
+        })
+    }
+
+    /// Take the next ready event, waiting with `wait` if none are queued
+    fn next_event<W>(&mut self, timeout_ms: i32, wait: W) -> Result<Option<PsiEvent>>
+    where
+        W: FnMut(i32, &mut [Event]) -> io::Result<usize>,
+    {
+        #[cfg(feature = "tracing")]
+        let _span =
+            tracing::debug_span!("wait", timeout_ms, triggers = self.triggers.len()).entered();
+        if self.ready.is_empty() {
+            debug!("waiting for psi event");
+            let mut events = [Event { events: 0, data: 0 }; MAX_EVENTS];
+            let n = wait_retrying(timeout_ms, &mut events, wait)?;
+            self.ready.extend(events[..n].iter().copied());
+        }
+        match self.ready.pop_front() {
+            Some(event) => self.read_event(event).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn read_event(&mut self, event: Event) -> Result<PsiEvent> {
+        let Event { events, data } = event;
+        let fd = RawFd::try_from(data).map_err(|_| UnexpectedEpollEvent { data, events })?;
+        let target = self.triggers.get_mut(&fd).ok_or(UnregisteredEvent { fd })?;
+        info!("psi event triggered: {}", target.trigger);
+        if events & (Events::EPOLLERR | Events::EPOLLHUP).bits() != 0 {
+            error!("error on watched psi file");
+            return Err(PsiError::trigger_file(&target.trigger));
+        }
+        if events & Events::EPOLLPRI.bits() == 0 {
+            return Err(UnexpectedEpollEvent { data, events });
+        }
+        target.buf.clear();
+        debug!("reading contents of file {:?}", target.file.file_path());
+        let (file, buf) = (&mut target.file, &mut target.buf);
+        file.seek(Start(0))
+            .and_then(|_| file.read_to_string(buf))
+            .map_err(|e| PsiError::trigger(Operation::Read, &target.trigger, e))?;
+        debug!("psi: {}", target.buf);
+
+        let stats = parse_line(&target.buf, target.trigger.line)?;
+        #[cfg(feature = "tracing")]
+        tracing::info!(
+            trigger = %target.trigger,
+            avg10 = %stats.avg10,
+            total_us = stats.total.as_micros() as u64,
+            "psi event"
+        );
+        Ok(PsiEvent {
+            stats,
+            trigger: target.trigger.clone(),
+            id: TriggerId { raw_fd: fd },
+        })
+    }
+}
+
+/// Call `wait` until it reports events or `timeout_ms` elapses
+///
+/// Waits interrupted by a signal, or returning early with no events, are
+/// retried with the remaining timeout. A negative timeout waits forever.
+pub(crate) fn wait_retrying<W>(timeout_ms: i32, events: &mut [Event], mut wait: W) -> Result<usize>
+where
+    W: FnMut(i32, &mut [Event]) -> io::Result<usize>,
+{
+    let deadline = u64::try_from(timeout_ms)
+        .ok()
+        .map(|ms| Instant::now() + Duration::from_millis(ms));
+    let mut remaining = timeout_ms;
+    loop {
+        match wait(remaining, events) {
+            Ok(0) => debug!("epoll returned no events"),
+            Ok(n) => return Ok(n),
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => debug!("epoll interrupted"),
+            Err(e) => return Err(e).op_context(Operation::Epoll),
+        }
+        if let Some(deadline) = deadline {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left == Duration::from_secs(0) {
+                return Ok(0);
             }
+            // round up so a sub-millisecond remainder doesn't busy loop
+            remaining = (left.as_micros() as u64)
+                .div_ceil(1000)
+                .min(i32::MAX as u64) as i32;
         }
     }
 }
 
+impl TriggerMonitor for PsiMonitor {
+    fn add_trigger(&mut self, trigger: Trigger) -> Result<TriggerId> {
+        PsiMonitor::add_trigger(self, trigger)
+    }
+
+    fn remove_trigger(&mut self, id: TriggerId) -> Result<Trigger> {
+        PsiMonitor::remove_trigger(self, id)
+    }
+
+    fn wait_timeout(&mut self, timeout: Duration) -> Result<Option<PsiEvent>> {
+        PsiMonitor::wait_timeout(self, timeout)
+    }
+
+    fn wait_single(&mut self) -> Result<PsiEvent> {
+        PsiMonitor::wait_single(self)
+    }
+}
+
+impl AsRawFd for PsiMonitor {
+    /// The epoll file descriptor, readable while an event is pending
+    fn as_raw_fd(&self) -> RawFd {
+        self.epoll_fd
+    }
+}
+
 /// ID for a specific trigger
-#[derive(Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct TriggerId {
-    raw_fd: RawFd,
+    pub(crate) raw_fd: RawFd,
+}
+
+pub(crate) trait FilePath {
+    fn file_path(&self) -> StdResult<PathBuf, std::io::Error>;
+}
+
+impl FilePath for File {
+    fn file_path(&self) -> StdResult<PathBuf, std::io::Error> {
+        let raw_fd = self.as_raw_fd();
+        let fd_link_path = format!("/proc/self/fd/{}", raw_fd);
+        read_link(fd_link_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::io::ErrorKind::Interrupted;
+
+    const CONTENTS: &str = "some avg10=1.00 avg60=0.00 avg300=0.00 total=10\n\
+                            full avg10=0.00 avg60=0.00 avg300=0.00 total=5\n";
+
+    /// Monitor with a trigger at `fd` backed by a plain file, as if epoll
+    /// had registered it
+    fn monitor_with_trigger(fd: RawFd) -> PsiMonitor {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(CONTENTS.as_bytes()).unwrap();
+        let mut monitor = PsiMonitor::new().unwrap();
+        let trigger = Trigger::new_builder()
+            .memory()
+            .some()
+            .stall(Duration::from_millis(100))
+            .window(Duration::from_secs(1))
+            .build();
+        monitor.triggers.insert(
+            fd,
+            PsiTriggerTarget {
+                trigger,
+                file,
+                buf: String::new(),
+            },
+        );
+        monitor
+    }
+
+    /// Fake `epoll_wait` returning each of `results` in turn
+    fn fake_wait<'a>(
+        results: &'a [io::Result<&'a [Event]>],
+        calls: &'a Cell<usize>,
+    ) -> impl FnMut(i32, &mut [Event]) -> io::Result<usize> + 'a {
+        move |_, events| {
+            let call = calls.get();
+            calls.set(call + 1);
+            match &results[call] {
+                Ok(ready) => {
+                    events[..ready.len()].copy_from_slice(ready);
+                    Ok(ready.len())
+                }
+                Err(e) => Err(io::Error::new(e.kind(), "fake")),
+            }
+        }
+    }
+
+    fn pri(fd: u64) -> Event {
+        Event::new(Events::EPOLLPRI, fd)
+    }
+
+    #[test]
+    fn should_retry_interrupted_and_empty_waits() {
+        let mut monitor = monitor_with_trigger(0);
+        let calls = Cell::new(0);
+        let results = [
+            Err(io::Error::from(Interrupted)),
+            Ok(&[][..]),
+            Ok(&[pri(0), pri(0)][..]),
+        ];
+        let event = monitor
+            .next_event(-1, fake_wait(&results, &calls))
+            .unwrap()
+            .unwrap();
+        assert_eq!(event.id, TriggerId { raw_fd: 0 });
+        assert_eq!(event.stats.total, Duration::from_micros(10));
+        assert_eq!(calls.get(), 3);
+
+        // the second queued event is handled without waiting again
+        let event = monitor.next_event(-1, fake_wait(&[], &calls)).unwrap();
+        assert!(event.is_some());
+        assert_eq!(calls.get(), 3);
+    }
+
+    #[test]
+    fn should_time_out_without_events() {
+        let mut monitor = monitor_with_trigger(3);
+        let calls = Cell::new(0);
+        let results = [Err(io::Error::from(Interrupted)), Ok(&[][..])];
+        let event = monitor.next_event(0, fake_wait(&results, &calls)).unwrap();
+        assert!(event.is_none());
+    }
+
+    #[test]
+    fn should_return_typed_errors_for_unexpected_events() {
+        let mut monitor = monitor_with_trigger(3);
+        let calls = Cell::new(0);
+        let results = [
+            Ok(&[pri(4)][..]),
+            Ok(&[Event::new(Events::EPOLLIN, 3)][..]),
+            Ok(&[Event::new(Events::EPOLLPRI | Events::EPOLLERR, 3)][..]),
+            Ok(&[pri(u64::MAX)][..]),
+            Err(io::Error::from(ErrorKind::InvalidInput)),
+        ];
+        let mut wait = fake_wait(&results, &calls);
+        let mut next = || monitor.next_event(-1, &mut wait);
+        assert!(matches!(next(), Err(UnregisteredEvent { fd: 4 })));
+        assert!(matches!(next(), Err(UnexpectedEpollEvent { data: 3, .. })));
+        assert!(matches!(next(), Err(PsiTriggerFileError { .. })));
+        assert!(matches!(next(), Err(UnexpectedEpollEvent { .. })));
+        assert!(matches!(
+            next(),
+            Err(IoError {
+                op: Operation::Epoll,
+                ..
+            })
+        ));
+    }
 }
@@ -0,0 +1,396 @@
+//! Pressure file parsing
+//!
+//! Lines are split into whitespace separated terms by a tokenizer which keeps
+//! track of byte offsets, so errors can point at the offending term. Fields
+//! may appear in any order. In [`ParseMode::Strict`] unknown fields and lines
+//! are errors; in [`ParseMode::Lenient`] they are collected or skipped so that
+//! newer kernels adding fields keep working.
+//!
+//! Parsing never panics, and without collecting unknown fields it performs no
+//! heap allocation unless it fails.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::error::*;
+use crate::psi::*;
+
+/// How to treat input the parser doesn't recognise
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum ParseMode {
+    /// Reject unknown fields, unknown lines and duplicates
+    Strict,
+    /// Collect unknown fields, skip unknown lines and keep the last duplicate
+    #[default]
+    Lenient,
+}
+
+/// Unknown `key=value` fields of a line, keyed by field name
+pub type Extra = BTreeMap<String, String>;
+
+/// A parsed line along with any fields the parser didn't recognise
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParsedPsi {
+    pub psi: Psi,
+    pub extra: Extra,
+}
+
+/// A parsed pressure file along with any fields the parser didn't recognise
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParsedAllPsiStats {
+    pub stats: AllPsiStats,
+    pub some_extra: Extra,
+    pub full_extra: Extra,
+}
+
+/// Configurable parser for pressure lines and files
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct PsiParser {
+    mode: ParseMode,
+}
+
+impl PsiParser {
+    pub fn new(mode: ParseMode) -> Self {
+        PsiParser { mode }
+    }
+
+    pub fn strict() -> Self {
+        PsiParser::new(ParseMode::Strict)
+    }
+
+    pub fn lenient() -> Self {
+        PsiParser::new(ParseMode::Lenient)
+    }
+
+    pub fn mode(&self) -> ParseMode {
+        self.mode
+    }
+
+    /// Parse a single `some` or `full` line
+    pub fn parse_line(&self, s: &str) -> Result<ParsedPsi> {
+        let mut extra = Extra::new();
+        let psi = parse_psi(s, 0, self.mode, &mut |k, v| {
+            extra.insert(k.to_string(), v.to_string());
+        })?;
+        Ok(ParsedPsi { psi, extra })
+    }
+
+    /// Parse the contents of a whole pressure file
+    pub fn parse_all(&self, s: &str) -> Result<ParsedAllPsiStats> {
+        let mut some_extra = Extra::new();
+        let mut full_extra = Extra::new();
+        let stats = parse_lines(s, self.mode, &mut |line, k, v| {
+            let extra = match line {
+                PsiLine::Some => &mut some_extra,
+                PsiLine::Full => &mut full_extra,
+            };
+            extra.insert(k.to_string(), v.to_string());
+        })?;
+        Ok(ParsedAllPsiStats {
+            stats,
+            some_extra,
+            full_extra,
+        })
+    }
+}
+
+/// A whitespace separated term and its byte offset within the input
+struct Token<'a> {
+    offset: usize,
+    text: &'a str,
+}
+
+struct Tokenizer<'a> {
+    s: &'a str,
+    pos: usize,
+}
+
+impl<'a> Tokenizer<'a> {
+    fn new(s: &'a str) -> Self {
+        Tokenizer { s, pos: 0 }
+    }
+}
+
+impl<'a> Iterator for Tokenizer<'a> {
+    type Item = Token<'a>;
+
+    fn next(&mut self) -> Option<Token<'a>> {
+        let bytes = self.s.as_bytes();
+        while self.pos < bytes.len() && bytes[self.pos].is_ascii_whitespace() {
+            self.pos += 1;
+        }
+        if self.pos == bytes.len() {
+            return None;
+        }
+        let start = self.pos;
+        while self.pos < bytes.len() && !bytes[self.pos].is_ascii_whitespace() {
+            self.pos += 1;
+        }
+        // splitting on ASCII bytes always lands on char boundaries
+        Some(Token {
+            offset: start,
+            text: &self.s[start..self.pos],
+        })
+    }
+}
+
+fn line_kind(term: &str) -> Option<PsiLine> {
+    match term {
+        "some" => Some(PsiLine::Some),
+        "full" => Some(PsiLine::Full),
+        _ => None,
+    }
+}
+
+fn set<T>(
+    slot: &mut Option<T>,
+    value: T,
+    key: &str,
+    offset: usize,
+    mode: ParseMode,
+) -> StdResult<(), ParseError> {
+    if slot.is_some() && mode == ParseMode::Strict {
+        return Err(DuplicateField {
+            offset,
+            key: key.to_string(),
+        });
+    }
+    *slot = Some(value);
+    Ok(())
+}
+
+/// Parse a single line starting at byte `base` of the original input,
+/// passing unknown fields to `extra` in lenient mode
+pub(crate) fn parse_psi<F: FnMut(&str, &str)>(
+    s: &str,
+    base: usize,
+    mode: ParseMode,
+    extra: &mut F,
+) -> Result<Psi> {
+    let mut tokens = Tokenizer::new(s);
+    let first = tokens.next().ok_or(MissingField {
+        offset: base + s.len(),
+        field: "some|full",
+    })?;
+    let line = line_kind(first.text).ok_or_else(|| UnexpectedTerm {
+        offset: base + first.offset,
+        term: first.text.to_string(),
+    })?;
+
+    let mut avg10 = None;
+    let mut avg60 = None;
+    let mut avg300 = None;
+    let mut total = None;
+    for token in tokens {
+        let offset = base + token.offset;
+        let (key, value) = token.text.split_once('=').ok_or_else(|| UnexpectedTerm {
+            offset,
+            term: token.text.to_string(),
+        })?;
+        // offset of the value, for number parsing errors
+        let value_offset = offset + key.len() + 1;
+        let avg = |value: &str| {
+            value.parse::<PsiPercent>().map_err(|error| AvgParseError {
+                offset: value_offset,
+                error,
+            })
+        };
+        match key {
+            "avg10" => set(&mut avg10, avg(value)?, key, offset, mode)?,
+            "avg60" => set(&mut avg60, avg(value)?, key, offset, mode)?,
+            "avg300" => set(&mut avg300, avg(value)?, key, offset, mode)?,
+            "total" => {
+                let micros = value.parse::<u64>().map_err(|error| TotalParseError {
+                    offset: value_offset,
+                    error,
+                })?;
+                set(&mut total, micros, key, offset, mode)?
+            }
+            _ => match mode {
+                ParseMode::Strict => {
+                    return Err(UnknownField {
+                        offset,
+                        key: key.to_string(),
+                    }
+                    .into())
+                }
+                ParseMode::Lenient => extra(key, value),
+            },
+        }
+    }
+
+    let end = base + s.len();
+    let missing = |field| MissingField { offset: end, field };
+    Ok(Psi {
+        line,
+        avg10: avg10.ok_or_else(|| missing("avg10"))?,
+        avg60: avg60.ok_or_else(|| missing("avg60"))?,
+        avg300: avg300.ok_or_else(|| missing("avg300"))?,
+        total: Duration::from_micros(total.ok_or_else(|| missing("total"))?),
+    })
+}
+
+/// Parse a whole pressure file, passing unknown fields to `extra` in lenient mode
+pub(crate) fn parse_lines<F: FnMut(PsiLine, &str, &str)>(
+    s: &str,
+    mode: ParseMode,
+    extra: &mut F,
+) -> Result<AllPsiStats> {
+    let (some, full) = parse_present_lines(s, mode, extra)?;
+    Ok(AllPsiStats {
+        some: some.ok_or(MissingLine(PsiLine::Some))?,
+        full: full.ok_or(MissingLine(PsiLine::Full))?,
+    })
+}
+
+/// Parse whichever of the `some` and `full` lines are present
+fn parse_present_lines<F: FnMut(PsiLine, &str, &str)>(
+    s: &str,
+    mode: ParseMode,
+    extra: &mut F,
+) -> Result<(Option<Psi>, Option<Psi>)> {
+    let mut some = None;
+    let mut full = None;
+    let mut base = 0;
+    for line in s.split('\n') {
+        let offset = base;
+        base += line.len() + 1;
+        let first = match Tokenizer::new(line).next() {
+            Some(first) => first,
+            None => continue,
+        };
+        let kind = match line_kind(first.text) {
+            Some(kind) => kind,
+            None if mode == ParseMode::Lenient => continue,
+            None => {
+                return Err(UnexpectedTerm {
+                    offset: offset + first.offset,
+                    term: first.text.to_string(),
+                }
+                .into())
+            }
+        };
+        let psi = parse_psi(line, offset, mode, &mut |k, v| extra(kind, k, v))?;
+        let slot = match kind {
+            PsiLine::Some => &mut some,
+            PsiLine::Full => &mut full,
+        };
+        set(slot, psi, first.text, offset + first.offset, mode)?;
+    }
+    Ok((some, full))
+}
+
+/// Leniently parse a whole pressure file, ignoring unknown fields
+pub(crate) fn parse_all(s: &str) -> Result<AllPsiStats> {
+    parse_lines(s, ParseMode::Lenient, &mut |_, _, _| {})
+}
+
+/// Leniently parse the `some` line and, if present, the `full` line, which
+/// CPU pressure lacks before Linux 5.13
+pub(crate) fn parse_some_full(s: &str) -> Result<(Psi, Option<Psi>)> {
+    let (some, full) = parse_present_lines(s, ParseMode::Lenient, &mut |_, _, _| {})?;
+    Ok((some.ok_or(MissingLine(PsiLine::Some))?, full))
+}
+
+/// Leniently parse a single line out of a pressure file
+pub(crate) fn parse_line(s: &str, line: PsiLine) -> Result<Psi> {
+    let mut base = 0;
+    for l in s.split('\n') {
+        let offset = base;
+        base += l.len() + 1;
+        let matches = Tokenizer::new(l)
+            .next()
+            .is_some_and(|first| line_kind(first.text) == Some(line));
+        if matches {
+            return parse_psi(l, offset, ParseMode::Lenient, &mut |_, _| {});
+        }
+    }
+    Err(MissingLine(line).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_error(result: Result<impl std::fmt::Debug>) -> ParseError {
+        match result {
+            Err(PsiParseError(e)) => e,
+            other => panic!("expected parse error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn should_parse_fields_in_any_order() {
+        let psi: Psi = "full total=5 avg300=0.03 avg10=0.01 avg60=0.02"
+            .parse()
+            .unwrap();
+        assert_eq!(psi.line, PsiLine::Full);
+        assert_eq!(psi.avg10, PsiPercent::from_hundredths(1));
+        assert_eq!(psi.avg60, PsiPercent::from_hundredths(2));
+        assert_eq!(psi.avg300, PsiPercent::from_hundredths(3));
+        assert_eq!(psi.total, Duration::from_micros(5));
+    }
+
+    #[test]
+    fn should_collect_unknown_fields_when_lenient() {
+        let parsed = PsiParser::lenient()
+            .parse_line("some avg10=0.00 avg60=0.00 avg300=0.00 total=0 avg1=0.50")
+            .unwrap();
+        assert_eq!(parsed.extra.get("avg1").map(String::as_str), Some("0.50"));
+    }
+
+    #[test]
+    fn should_reject_unknown_fields_when_strict() {
+        let s = "some avg10=0.00 avg60=0.00 avg300=0.00 total=0 avg1=0.50";
+        match parse_error(PsiParser::strict().parse_line(s)) {
+            UnknownField { offset, key } => {
+                assert_eq!(offset, 47);
+                assert_eq!(key, "avg1");
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn should_report_offsets() {
+        let s = "some avg10=0.00 avg60=0.00 avg300=0.00 total=0\nfull avg10=x avg60=0.00 avg300=0.00 total=0\n";
+        match parse_error(PsiParser::strict().parse_all(s)) {
+            AvgParseError { offset, .. } => assert_eq!(offset, 58),
+            e => panic!("unexpected error {:?}", e),
+        }
+        match parse_error("some avg10=0.00 avg60=0.00".parse::<Psi>()) {
+            MissingField { offset, field } => {
+                assert_eq!(offset, 26);
+                assert_eq!(field, "avg300");
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn should_not_panic_on_garbage() {
+        for s in &[
+            "",
+            " ",
+            "\n\n",
+            "some",
+            "some =",
+            "full avg10=",
+            "é avg10=1",
+            "some total=-1",
+        ] {
+            assert!(s.parse::<Psi>().is_err(), "{:?}", s);
+            assert!(PsiParser::strict().parse_all(s).is_err(), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn should_skip_unknown_lines_when_lenient() {
+        let s = "some avg10=0.00 avg60=0.00 avg300=0.00 total=1\n\
+                 partial avg10=0.00\n\
+                 full avg10=0.00 avg60=0.00 avg300=0.00 total=2\n";
+        let parsed = PsiParser::lenient().parse_all(s).unwrap();
+        assert_eq!(parsed.stats.full.total, Duration::from_micros(2));
+        assert!(PsiParser::strict().parse_all(s).is_err());
+    }
+}
@@ -1,58 +1,130 @@
 use std::fmt;
-use std::fs::{read_link, File, OpenOptions};
+use std::fs::OpenOptions;
 use std::io::Read;
-use std::os::unix::io::*;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::time::Duration;
 
+use crate::capabilities::{unavailable_error, EOPNOTSUPP};
+use crate::cgroup::*;
 use crate::error::*;
+use crate::parse::*;
 
-pub(crate) const CPU_PRESSURE_FILEPATH: &'static str = "/proc/pressure/cpu";
-pub(crate) const IO_PRESSURE_FILEPATH: &'static str = "/proc/pressure/io";
-pub(crate) const MEMORY_PRESSURE_FILEPATH: &'static str = "/proc/pressure/memory";
+pub(crate) const CPU_PRESSURE_FILEPATH: &str = "/proc/pressure/cpu";
+pub(crate) const IO_PRESSURE_FILEPATH: &str = "/proc/pressure/io";
+pub(crate) const MEMORY_PRESSURE_FILEPATH: &str = "/proc/pressure/memory";
+pub(crate) const IRQ_PRESSURE_FILEPATH: &str = "/proc/pressure/irq";
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+/// Kind of pressure
+///
+/// Marked `#[non_exhaustive]` since the kernel grows new kinds, as with IRQ
+/// pressure in Linux 6.1; matches on it need a wildcard arm.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
 pub enum PsiKind {
     Memory,
     IO,
     CPU,
+    /// IRQ pressure (Linux 6.1+); only has a `full` line
+    IRQ,
 }
 
 impl PsiKind {
-    pub fn read_psi(&self) -> Result<AllPsiStats> {
-        let path = match self {
+    /// Name of the pressure file for this kind within a cgroup2 directory
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            PsiKind::Memory => "memory.pressure",
+            PsiKind::IO => "io.pressure",
+            PsiKind::CPU => "cpu.pressure",
+            PsiKind::IRQ => "irq.pressure",
+        }
+    }
+
+    /// Path of the system-wide pressure file for this kind
+    pub fn file_path(&self) -> &'static Path {
+        Path::new(match self {
             PsiKind::Memory => MEMORY_PRESSURE_FILEPATH,
             PsiKind::IO => IO_PRESSURE_FILEPATH,
             PsiKind::CPU => CPU_PRESSURE_FILEPATH,
-        };
-        let mut file = OpenOptions::new().read(true).open(path)?;
-        let mut buf = String::with_capacity(256);
-        file.read_to_string(&mut buf)?;
-        let all = buf
-            .lines()
-            .map(|line| line.parse())
-            .collect::<Result<Vec<Psi>>>()?;
-        let some = all
-            .iter()
-            .find(|psi| psi.line == PsiLine::Some)
-            .ok_or(MissingLine(PsiLine::Some))?
-            .clone();
-        let full = all
-            .iter()
-            .find(|psi| psi.line == PsiLine::Full)
-            .ok_or(MissingLine(PsiLine::Full))?
-            .clone();
-        Ok(AllPsiStats { some, full })
+            PsiKind::IRQ => IRQ_PRESSURE_FILEPATH,
+        })
+    }
+
+    /// Whether pressure of this kind has the given line; IRQ has only `full`
+    pub fn has_line(&self, line: PsiLine) -> bool {
+        !(*self == PsiKind::IRQ && line == PsiLine::Some)
+    }
+
+    /// Path of the pressure file for this kind within a cgroup2 directory
+    pub fn cgroup_file_path<P: AsRef<Path>>(&self, cgroup: P) -> PathBuf {
+        cgroup.as_ref().join(self.file_name())
+    }
+
+    /// Read system-wide pressure of this kind
+    ///
+    /// Fails with [`PsiError::PsiUnsupported`] or [`PsiError::PsiDisabled`]
+    /// when the kernel doesn't provide it, and with
+    /// [`PsiError::UnsupportedLine`] for IRQ, which has no `some` line.
+    pub fn read_psi(&self) -> Result<AllPsiStats> {
+        self.check_line(PsiLine::Some)?;
+        parse_all(&read_system_psi_file(self.file_path())?)
     }
 
     pub fn read_psi_line(&self, line: PsiLine) -> Result<Psi> {
-        let all = self.read_psi()?;
-        match line {
-            PsiLine::Some => Ok(all.some),
-            PsiLine::Full => Ok(all.full),
+        self.check_line(line)?;
+        parse_line(&read_system_psi_file(self.file_path())?, line)
+    }
+
+    /// Read pressure of this kind for a cgroup2 directory
+    ///
+    /// Fails with [`PsiError::CgroupPressureDisabled`] if PSI accounting is
+    /// turned off for the cgroup, and with [`PsiError::UnsupportedLine`] for
+    /// IRQ, which has no `some` line.
+    pub fn read_cgroup_psi<P: AsRef<Path>>(&self, cgroup: P) -> Result<AllPsiStats> {
+        self.check_line(PsiLine::Some)?;
+        parse_all(&self.read_cgroup_psi_file(cgroup.as_ref())?)
+    }
+
+    pub fn read_cgroup_psi_line<P: AsRef<Path>>(&self, cgroup: P, line: PsiLine) -> Result<Psi> {
+        self.check_line(line)?;
+        parse_line(&self.read_cgroup_psi_file(cgroup.as_ref())?, line)
+    }
+
+    fn check_line(&self, line: PsiLine) -> Result<()> {
+        if self.has_line(line) {
+            Ok(())
+        } else {
+            Err(UnsupportedLine { kind: *self, line })
         }
     }
+
+    fn read_cgroup_psi_file(&self, cgroup: &Path) -> Result<String> {
+        read_psi_file(self.cgroup_file_path(cgroup)).map_err(|e| classify_read_error(cgroup, e))
+    }
+}
+
+fn read_system_psi_file(path: &Path) -> Result<String> {
+    read_psi_file(path).map_err(|e| match e {
+        e if e.is_not_found() => match path.parent() {
+            Some(dir) if dir.is_dir() => PsiUnsupported,
+            _ => unavailable_error(),
+        },
+        e if e.raw_os_error() == Some(EOPNOTSUPP) => PsiDisabled,
+        e => e,
+    })
+}
+
+fn read_psi_file<P: AsRef<Path>>(path: P) -> Result<String> {
+    let path = path.as_ref();
+    let mut file = OpenOptions::new()
+        .read(true)
+        .open(path)
+        .context(Operation::Open, path)?;
+    let mut buf = String::with_capacity(256);
+    file.read_to_string(&mut buf)
+        .context(Operation::Read, path)?;
+    Ok(buf)
 }
 
 impl fmt::Display for PsiKind {
@@ -61,11 +133,31 @@ impl fmt::Display for PsiKind {
             PsiKind::Memory => write!(f, "memory"),
             PsiKind::IO => write!(f, "io"),
             PsiKind::CPU => write!(f, "cpu"),
+            PsiKind::IRQ => write!(f, "irq"),
         }
     }
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+impl FromStr for PsiKind {
+    type Err = PsiError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "memory" => Ok(PsiKind::Memory),
+            "io" => Ok(PsiKind::IO),
+            "cpu" => Ok(PsiKind::CPU),
+            "irq" => Ok(PsiKind::IRQ),
+            _ => Err(UnexpectedTerm {
+                offset: 0,
+                term: s.to_string(),
+            }
+            .into()),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum PsiLine {
     Some,
     Full,
@@ -87,28 +179,150 @@ impl FromStr for PsiLine {
         match s {
             "some" => Ok(PsiLine::Some),
             "full" => Ok(PsiLine::Full),
-            _ => Err(UnexpectedTerm(s.to_string()).into()),
+            _ => Err(UnexpectedTerm {
+                offset: 0,
+                term: s.to_string(),
+            }
+            .into()),
         }
     }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+/// Exact pressure average percentage
+///
+/// The kernel keeps averages in fixed point and prints them with exactly two
+/// decimal places, so they are stored here as hundredths of a percent. The
+/// [`Display`](fmt::Display) impl prints the same format as the kernel.
+///
+/// # Migrating from `f32`
+///
+/// `PsiPercent` can be compared directly with `f32`, so code such as
+/// `psi.avg10 > 0.1f32` continues to work. Where an `f32` or `f64` is needed,
+/// use [`PsiPercent::as_f32`], [`PsiPercent::as_f64`] or the `From` impls.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct PsiPercent(u32);
+
+impl PsiPercent {
+    pub const ZERO: PsiPercent = PsiPercent(0);
+    pub const MAX: PsiPercent = PsiPercent(10_000);
+
+    pub const fn from_hundredths(hundredths: u32) -> Self {
+        PsiPercent(hundredths)
+    }
+
+    /// Round a percentage to the nearest hundredth, saturating at zero
+    pub fn from_f32(percent: f32) -> Self {
+        PsiPercent(
+            (f64::from(percent) * 100.0)
+                .round()
+                .max(0.0)
+                .min(u32::MAX as f64) as u32,
+        )
+    }
+
+    pub const fn hundredths(&self) -> u32 {
+        self.0
+    }
+
+    pub fn as_f32(&self) -> f32 {
+        self.0 as f32 / 100.0
+    }
+
+    pub fn as_f64(&self) -> f64 {
+        f64::from(self.0) / 100.0
+    }
+}
+
+impl fmt::Display for PsiPercent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{:02}", self.0 / 100, self.0 % 100)
+    }
+}
+
+impl FromStr for PsiPercent {
+    type Err = ParsePercentError;
+
+    /// Parses a non-negative decimal, rounding to two decimal places
+    fn from_str(s: &str) -> StdResult<Self, ParsePercentError> {
+        let digits = |d: &str| !d.is_empty() && d.bytes().all(|b| b.is_ascii_digit());
+        let (int, frac) = match s.split_once('.') {
+            Some((int, frac)) if digits(int) && digits(frac) => (int, frac),
+            None if digits(s) => (s, ""),
+            _ => return Err(ParsePercentError::Invalid),
+        };
+        let int: u32 = int.parse().map_err(|_| ParsePercentError::Overflow)?;
+        let frac = frac.as_bytes();
+        let digit = |i: usize| frac.get(i).map_or(0, |b| u32::from(b - b'0'));
+        let round_up = u32::from(digit(2) >= 5);
+        int.checked_mul(100)
+            .and_then(|v| v.checked_add(digit(0) * 10 + digit(1) + round_up))
+            .map(PsiPercent)
+            .ok_or(ParsePercentError::Overflow)
+    }
+}
+
+/// Serialized as a floating point percentage, as in the pressure files
+#[cfg(feature = "serde")]
+impl serde::Serialize for PsiPercent {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> StdResult<S::Ok, S::Error> {
+        serializer.serialize_f64(self.as_f64())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for PsiPercent {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> StdResult<Self, D::Error> {
+        let percent = f64::deserialize(deserializer)?;
+        Ok(PsiPercent::from_f32(percent as f32))
+    }
+}
+
+impl From<PsiPercent> for f32 {
+    fn from(p: PsiPercent) -> f32 {
+        p.as_f32()
+    }
+}
+
+impl From<PsiPercent> for f64 {
+    fn from(p: PsiPercent) -> f64 {
+        p.as_f64()
+    }
+}
+
+impl PartialEq<f32> for PsiPercent {
+    fn eq(&self, other: &f32) -> bool {
+        self.as_f32() == *other
+    }
+}
+
+impl PartialOrd<f32> for PsiPercent {
+    fn partial_cmp(&self, other: &f32) -> Option<std::cmp::Ordering> {
+        self.as_f32().partial_cmp(other)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Psi {
     pub line: PsiLine,
-    pub avg10: f32,
-    pub avg60: f32,
-    pub avg300: f32,
+    pub avg10: PsiPercent,
+    pub avg60: PsiPercent,
+    pub avg300: PsiPercent,
     pub total: Duration,
 }
 
 impl Psi {
-    fn parse_stat<E: Into<PsiError>, T: FromStr<Err = E>>(key: &str, term: &str) -> Result<T> {
-        let v: Vec<&str> = term.splitn(2, |c| c == '=').collect();
-        if v.len() == 2 && v[0] == key {
-            v[1].parse::<T>().map_err(E::into)
-        } else {
-            Err(PsiParseError(UnexpectedTerm(term.to_string())))
-        }
+    /// Read a line of the pressure of a process's cgroup
+    ///
+    /// Use [`nearest_pressure_cgroup`] with [`PsiKind::read_cgroup_psi_line`]
+    /// to fall back to an ancestor when accounting is disabled for the cgroup.
+    pub fn for_pid(kind: PsiKind, line: PsiLine, pid: u32) -> Result<Psi> {
+        kind.read_cgroup_psi_line(pid_cgroup(pid)?, line)
+    }
+
+    /// Read a line of the pressure of the current process's cgroup
+    pub fn for_self(kind: PsiKind, line: PsiLine) -> Result<Psi> {
+        kind.read_cgroup_psi_line(self_cgroup()?, line)
     }
 }
 
@@ -129,30 +343,56 @@ impl fmt::Display for Psi {
 impl FromStr for Psi {
     type Err = PsiError;
 
+    /// Leniently parses a single line; see [`PsiParser`] for strict parsing
+    /// and access to unknown fields.
     fn from_str(s: &str) -> Result<Self> {
-        let terms: Vec<&str> = s.split_ascii_whitespace().collect();
-        let line = terms[0].parse()?;
-        let avg10 = Psi::parse_stat("avg10", terms.get(1).ok_or(UnexpectedTerm(s.to_string()))?)?;
-        let avg60 = Psi::parse_stat("avg60", terms.get(2).ok_or(UnexpectedTerm(s.to_string()))?)?;
-        let avg300 = Psi::parse_stat("avg300", terms.get(3).ok_or(UnexpectedTerm(s.to_string()))?)?;
-        let total_stall =
-            Psi::parse_stat("total", terms.get(4).ok_or(UnexpectedTerm(s.to_string()))?)?;
-        Ok(Psi {
-            line,
-            avg10,
-            avg60,
-            avg300,
-            total: Duration::from_micros(total_stall),
-        })
+        parse_psi(s, 0, ParseMode::Lenient, &mut |_, _| {})
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct AllPsiStats {
     pub some: Psi,
     pub full: Psi,
 }
 
+impl AllPsiStats {
+    /// Read the pressure of a process's cgroup
+    ///
+    /// Use [`nearest_pressure_cgroup`] with [`PsiKind::read_cgroup_psi`] to
+    /// fall back to an ancestor when accounting is disabled for the cgroup.
+    pub fn for_pid(kind: PsiKind, pid: u32) -> Result<Self> {
+        kind.read_cgroup_psi(pid_cgroup(pid)?)
+    }
+
+    /// Read the pressure of the current process's cgroup
+    pub fn for_self(kind: PsiKind) -> Result<Self> {
+        kind.read_cgroup_psi(self_cgroup()?)
+    }
+
+    /// Parse the contents of a pressure file from any reader, such as a file
+    /// in an archive or a stream from a remote agent
+    pub fn from_reader<R: Read>(mut reader: R) -> Result<Self> {
+        let mut buf = String::with_capacity(256);
+        reader
+            .read_to_string(&mut buf)
+            .op_context(Operation::Read)?;
+        buf.parse()
+    }
+}
+
+impl FromStr for AllPsiStats {
+    type Err = PsiError;
+
+    /// Leniently parses the one or two lines of a pressure file, failing with
+    /// [`MissingLine`] if either `some` or `full` is absent. See [`PsiParser`]
+    /// for strict parsing.
+    fn from_str(s: &str) -> Result<Self> {
+        parse_all(s)
+    }
+}
+
 impl fmt::Display for AllPsiStats {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         writeln!(f, "{}", self.some)?;
@@ -163,6 +403,7 @@ impl fmt::Display for AllPsiStats {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::io::Cursor;
 
     #[test]
     fn should_parse_full() {
@@ -172,9 +413,9 @@ mod tests {
             stats.unwrap(),
             Psi {
                 line: PsiLine::Full,
-                avg10: 0.16f32,
-                avg60: 0f32,
-                avg300: 0f32,
+                avg10: PsiPercent::from_hundredths(16),
+                avg60: PsiPercent::ZERO,
+                avg300: PsiPercent::ZERO,
                 total: Duration::from_micros(27787674),
             }
         );
@@ -188,23 +429,104 @@ mod tests {
             stats.unwrap(),
             Psi {
                 line: PsiLine::Some,
-                avg10: 0.16f32,
-                avg60: 0f32,
-                avg300: 0f32,
+                avg10: PsiPercent::from_hundredths(16),
+                avg60: PsiPercent::ZERO,
+                avg300: PsiPercent::ZERO,
                 total: Duration::from_micros(27787674),
             }
         );
     }
-}
 
-pub(crate) trait FilePath {
-    fn file_path(&self) -> StdResult<PathBuf, std::io::Error>;
-}
+    #[test]
+    fn should_parse_all_stats() {
+        let s = "some avg10=0.16 avg60=0.00 avg300=0.00 total=27787674\n\
+                 full avg10=0.01 avg60=0.00 avg300=0.00 total=1234";
+        let all: AllPsiStats = s.parse().unwrap();
+        assert_eq!(all.some.total, Duration::from_micros(27787674));
+        assert_eq!(all.full.total, Duration::from_micros(1234));
+        assert_eq!(AllPsiStats::from_reader(Cursor::new(s)).unwrap(), all);
+        assert_eq!(all.to_string().parse::<AllPsiStats>().unwrap(), all);
+    }
+
+    #[test]
+    fn should_report_missing_line() {
+        let s = "some avg10=0.16 avg60=0.00 avg300=0.00 total=27787674\n";
+        match s.parse::<AllPsiStats>() {
+            Err(PsiParseError(MissingLine(PsiLine::Full))) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn should_report_unexpected_term() {
+        let s = "some avg10=0.16 avg60=0.00 avg300=0.00 total=27787674\nfull avg10 0.01\n";
+        match AllPsiStats::from_reader(Cursor::new(s)) {
+            Err(PsiParseError(UnexpectedTerm { offset, term })) => {
+                assert_eq!(offset, 59);
+                assert_eq!(term, "avg10");
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
 
-impl FilePath for File {
-    fn file_path(&self) -> StdResult<PathBuf, std::io::Error> {
-        let raw_fd = self.as_raw_fd();
-        let fd_link_path = format!("/proc/self/fd/{}", raw_fd);
-        read_link(fd_link_path)
+    #[test]
+    fn should_round_trip_kernel_format() {
+        let line = "some avg10=0.16 avg60=100.00 avg300=0.00 total=27787674";
+        let psi: Psi = line.parse().unwrap();
+        assert_eq!(psi.avg60, PsiPercent::MAX);
+        assert_eq!(psi.to_string(), line);
+    }
+
+    #[test]
+    fn should_parse_percent() {
+        let parse = |s: &str| s.parse::<PsiPercent>().map(|p| p.hundredths());
+        assert_eq!(parse("0.16"), Ok(16));
+        assert_eq!(parse("12.5"), Ok(1250));
+        assert_eq!(parse("3"), Ok(300));
+        assert_eq!(parse("0.125"), Ok(13));
+        assert_eq!(parse("-1.00"), Err(ParsePercentError::Invalid));
+        assert_eq!(parse("1."), Err(ParsePercentError::Invalid));
+        assert_eq!(parse("99999999999.00"), Err(ParsePercentError::Overflow));
+        assert!(PsiPercent::from_hundredths(16) > 0.1f32);
+        assert_eq!(f64::from(PsiPercent::from_hundredths(250)), 2.5);
+        assert_eq!(PsiPercent::from_f32(0.16), PsiPercent::from_hundredths(16));
+    }
+
+    #[test]
+    fn should_reject_irq_some_line() {
+        for result in &[
+            PsiKind::IRQ.read_psi().map(|_| ()),
+            PsiKind::IRQ.read_psi_line(PsiLine::Some).map(|_| ()),
+            PsiKind::IRQ.read_cgroup_psi("/nonexistent").map(|_| ()),
+        ] {
+            match result {
+                Err(e @ UnsupportedLine { .. }) => {
+                    assert_eq!(e.kind(), PsiErrorKind::NotSupported);
+                    assert_eq!(e.to_string(), "irq pressure has no 'some' line");
+                }
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn should_round_trip_serde() {
+        let s = "some avg10=0.16 avg60=12.50 avg300=100.00 total=27787674\n\
+                 full avg10=0.01 avg60=0.00 avg300=0.00 total=1234";
+        let all: AllPsiStats = s.parse().unwrap();
+        let json = serde_json::to_string(&all).unwrap();
+        assert!(json.contains(r#""avg10":0.16"#), "{}", json);
+        assert!(json.contains(r#""line":"Some""#), "{}", json);
+        assert_eq!(serde_json::from_str::<AllPsiStats>(&json).unwrap(), all);
+
+        let percent: PsiPercent = serde_json::from_str("12.345").unwrap();
+        assert_eq!(percent, PsiPercent::from_hundredths(1235));
+        assert_eq!(serde_json::to_string(&percent).unwrap(), "12.35");
+        assert_eq!(
+            serde_json::from_str::<PsiKind>(&serde_json::to_string(&PsiKind::IRQ).unwrap())
+                .unwrap(),
+            PsiKind::IRQ
+        );
     }
 }
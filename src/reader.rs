@@ -0,0 +1,144 @@
+//! Allocation-free pressure file reader
+//!
+//! [`PsiReader`] keeps a pressure file open and reads it with `pread(2)` into
+//! a stack buffer, parsing the contents in place. Successful reads perform no
+//! heap allocation, making it suitable for sampling many cgroups at a high
+//! rate.
+
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::str;
+
+use crate::cgroup::classify_read_error;
+use crate::error::*;
+use crate::parse::{parse_all, parse_line, parse_some_full};
+use crate::psi::*;
+
+/// Pressure files are two lines of at most ~80 bytes each
+const BUF_SIZE: usize = 256;
+
+/// Reader for a single pressure file held open between reads
+pub struct PsiReader {
+    kind: PsiKind,
+    path: PathBuf,
+    file: File,
+}
+
+impl PsiReader {
+    /// Open the system-wide pressure file for a kind
+    pub fn open(kind: PsiKind) -> Result<Self> {
+        Self::from_path(kind, kind.file_path())
+    }
+
+    /// Open the pressure file for a kind within a cgroup2 directory
+    ///
+    /// Fails with [`PsiError::CgroupPressureDisabled`] if PSI accounting is
+    /// turned off for the cgroup.
+    pub fn cgroup<P: AsRef<Path>>(kind: PsiKind, cgroup: P) -> Result<Self> {
+        let cgroup = cgroup.as_ref();
+        Self::from_path(kind, kind.cgroup_file_path(cgroup))
+            .map_err(|e| classify_read_error(cgroup, e))
+    }
+
+    /// Open a pressure file at an arbitrary path
+    pub fn from_path<P: AsRef<Path>>(kind: PsiKind, path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new()
+            .read(true)
+            .open(&path)
+            .context(Operation::Open, &path)?;
+        Ok(PsiReader { kind, path, file })
+    }
+
+    pub fn kind(&self) -> PsiKind {
+        self.kind
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Read both lines of the pressure file
+    pub fn read(&self) -> Result<AllPsiStats> {
+        let mut buf = [0u8; BUF_SIZE];
+        parse_all(self.read_into(&mut buf)?)
+    }
+
+    /// Read the `some` line and, if present, the `full` line
+    ///
+    /// CPU pressure has no `full` line before Linux 5.13, which makes
+    /// [`read`](Self::read) fail with [`ParseError::MissingLine`].
+    pub fn read_some_full(&self) -> Result<(Psi, Option<Psi>)> {
+        let mut buf = [0u8; BUF_SIZE];
+        parse_some_full(self.read_into(&mut buf)?)
+    }
+
+    /// Read a single line of the pressure file
+    pub fn read_line(&self, line: PsiLine) -> Result<Psi> {
+        let mut buf = [0u8; BUF_SIZE];
+        parse_line(self.read_into(&mut buf)?, line)
+    }
+
+    fn read_into<'a>(&self, buf: &'a mut [u8; BUF_SIZE]) -> Result<&'a str> {
+        let mut len = 0;
+        loop {
+            let n = self
+                .file
+                .read_at(&mut buf[len..], len as u64)
+                .context(Operation::Read, &self.path)?;
+            if n == 0 {
+                break;
+            }
+            len += n;
+            if len == buf.len() {
+                return Err(UnexpectedTerm {
+                    offset: len,
+                    term: "pressure file exceeds read buffer".to_string(),
+                }
+                .into());
+            }
+        }
+        str::from_utf8(&buf[..len]).map_err(|e| {
+            UnexpectedTerm {
+                offset: e.valid_up_to(),
+                term: String::from_utf8_lossy(&buf[e.valid_up_to()..len]).into_owned(),
+            }
+            .into()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn should_reread_from_start() {
+        let dir = TempDir::new().unwrap();
+        let path = PsiKind::IO.cgroup_file_path(dir.path());
+        fs::write(
+            &path,
+            "some avg10=1.00 avg60=0.00 avg300=0.00 total=10\nfull avg10=0.50 avg60=0.00 avg300=0.00 total=5\n",
+        )
+        .unwrap();
+        let reader = PsiReader::cgroup(PsiKind::IO, dir.path()).unwrap();
+        assert_eq!(reader.read().unwrap().some.total.as_micros(), 10);
+        assert_eq!(reader.read().unwrap().some.total.as_micros(), 10);
+        assert_eq!(
+            reader.read_line(PsiLine::Full).unwrap().total.as_micros(),
+            5
+        );
+    }
+
+    #[test]
+    fn should_reject_oversized_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("io.pressure");
+        fs::write(&path, "x".repeat(BUF_SIZE * 2)).unwrap();
+        let reader = PsiReader::from_path(PsiKind::IO, &path).unwrap();
+        assert!(reader.read().is_err());
+    }
+}
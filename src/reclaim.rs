@@ -0,0 +1,442 @@
+//! Pressure-driven proactive reclaim
+//!
+//! Like Senpai, [`ReclaimController`] squeezes a cgroup's memory in a
+//! feedback loop, using the cgroup's own memory `some` pressure as the
+//! signal. While pressure stays below the target the cgroup is probed with
+//! small reductions; once it rises above the target the controller backs
+//! off. This finds the cgroup's working set without driving it into
+//! thrashing.
+//!
+//! The limit is applied either by lowering `memory.high`, or, on Linux 5.19+,
+//! by asking the kernel to reclaim a number of bytes via `memory.reclaim`,
+//! which doesn't leave a limit behind if the controller stops.
+
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::*;
+
+use crate::error::*;
+use crate::history::{percent, PsiSample};
+use crate::psi::*;
+use crate::reader::PsiReader;
+
+const MEMORY_CURRENT_FILE: &str = "memory.current";
+const MEMORY_HIGH_FILE: &str = "memory.high";
+const MEMORY_RECLAIM_FILE: &str = "memory.reclaim";
+
+/// How the controller takes memory away from the cgroup
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ReclaimMode {
+    /// Adjust `memory.high`, which throttles and reclaims above the limit
+    MemoryHigh,
+    /// Write to `memory.reclaim` (Linux 5.19+), reclaiming once per step
+    MemoryReclaim,
+}
+
+/// Reclaim controller configuration
+#[derive(Debug, Clone)]
+pub struct ReclaimConfig {
+    /// cgroup2 directory to reclaim from
+    pub cgroup: PathBuf,
+    pub mode: ReclaimMode,
+    /// Memory `some` pressure to hold the cgroup at
+    pub target: PsiPercent,
+    /// Time between adjustments; pressure is measured over this interval
+    pub interval: Duration,
+    /// Largest reduction per step, as a fraction of the current size
+    pub max_probe: f64,
+    /// Largest increase of `memory.high` per step, as a fraction of the limit
+    pub max_backoff: f64,
+    /// Never shrink the cgroup below this many bytes
+    pub min_size: u64,
+    /// Never raise `memory.high` above this many bytes
+    pub max_size: u64,
+    /// Log adjustments without writing them
+    pub dry_run: bool,
+}
+
+impl Default for ReclaimConfig {
+    fn default() -> Self {
+        ReclaimConfig {
+            cgroup: PathBuf::new(),
+            mode: ReclaimMode::MemoryHigh,
+            target: PsiPercent::from_hundredths(10),
+            interval: Duration::from_secs(6),
+            max_probe: 0.01,
+            max_backoff: 1.0,
+            min_size: 100 << 20,
+            max_size: u64::MAX,
+            dry_run: false,
+        }
+    }
+}
+
+impl ReclaimConfig {
+    pub fn new<P: Into<PathBuf>>(cgroup: P) -> Self {
+        ReclaimConfig {
+            cgroup: cgroup.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn mode(mut self, mode: ReclaimMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn target(mut self, target: PsiPercent) -> Self {
+        self.target = target;
+        self
+    }
+
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn max_probe(mut self, max_probe: f64) -> Self {
+        self.max_probe = max_probe;
+        self
+    }
+
+    pub fn max_backoff(mut self, max_backoff: f64) -> Self {
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    pub fn min_size(mut self, min_size: u64) -> Self {
+        self.min_size = min_size;
+        self
+    }
+
+    pub fn max_size(mut self, max_size: u64) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+}
+
+/// What a controller step did
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ReclaimAction {
+    /// `memory.high` was changed, in bytes
+    SetHigh { from: u64, to: u64 },
+    /// The kernel was asked to reclaim this many bytes
+    Reclaim { bytes: u64 },
+    /// Nothing to do, e.g. already at a size limit
+    Hold,
+}
+
+impl fmt::Display for ReclaimAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReclaimAction::SetHigh { from, to } => {
+                write!(f, "memory.high {} -> {} bytes", from, to)
+            }
+            ReclaimAction::Reclaim { bytes } => write!(f, "reclaim {} bytes", bytes),
+            ReclaimAction::Hold => write!(f, "hold"),
+        }
+    }
+}
+
+/// Feedback loop holding a cgroup's memory pressure near a target
+pub struct ReclaimController {
+    config: ReclaimConfig,
+    reader: PsiReader,
+    previous: Option<PsiSample>,
+}
+
+impl ReclaimController {
+    /// Open the cgroup's `memory.pressure`
+    pub fn new(config: ReclaimConfig) -> Result<Self> {
+        let reader = PsiReader::cgroup(PsiKind::Memory, &config.cgroup)?;
+        Ok(ReclaimController {
+            config,
+            reader,
+            previous: None,
+        })
+    }
+
+    pub fn config(&self) -> &ReclaimConfig {
+        &self.config
+    }
+
+    /// Relative size change for the pressure seen over the last interval
+    ///
+    /// Negative below the target, growing quadratically with the distance
+    /// from it, and limited to the configured step sizes.
+    pub fn adjustment(&self, pressure: PsiPercent) -> f64 {
+        let target = self.config.target.as_f64().max(f64::EPSILON);
+        let error = (pressure.as_f64() - target) / target;
+        if error < 0.0 {
+            -(error * error).min(1.0) * self.config.max_probe
+        } else {
+            (error * error).min(1.0) * self.config.max_backoff
+        }
+    }
+
+    /// Sample pressure and adjust the cgroup
+    ///
+    /// Returns `None` for the first sample, as pressure is measured between
+    /// consecutive steps.
+    pub fn step(&mut self) -> Result<Option<(PsiPercent, ReclaimAction)>> {
+        self.step_at(Instant::now())
+    }
+
+    /// Sample pressure as if read at `now` and adjust the cgroup
+    pub fn step_at(&mut self, now: Instant) -> Result<Option<(PsiPercent, ReclaimAction)>> {
+        let sample = PsiSample {
+            time: now,
+            total: self.reader.read_line(PsiLine::Some)?.total,
+        };
+        let previous = self.previous.replace(sample);
+        let pressure = match previous {
+            Some(previous) if previous.time < now && previous.total <= sample.total => {
+                percent(&previous, &sample)
+            }
+            _ => return Ok(None),
+        };
+        let adjustment = self.adjustment(pressure);
+        let action = match self.config.mode {
+            ReclaimMode::MemoryHigh => self.set_high(adjustment)?,
+            ReclaimMode::MemoryReclaim => self.reclaim(adjustment)?,
+        };
+        info!(
+            "{}: memory some pressure {}% (target {}%): {}",
+            self.config.cgroup.display(),
+            pressure,
+            self.config.target,
+            action
+        );
+        Ok(Some((pressure, action)))
+    }
+
+    /// Step every interval until the cgroup is removed
+    ///
+    /// Other errors, such as `EBUSY` from `memory.reclaim`, are logged and
+    /// the next step is taken as usual.
+    pub fn run(&mut self) -> Result<()> {
+        loop {
+            match self.step() {
+                Ok(_) => {}
+                Err(e) if e.kind() == PsiErrorKind::CgroupGone => return Err(e),
+                Err(e) => warn!(
+                    "{}: reclaim step failed: {}",
+                    self.config.cgroup.display(),
+                    e
+                ),
+            }
+            thread::sleep(self.config.interval);
+        }
+    }
+
+    fn set_high(&self, adjustment: f64) -> Result<ReclaimAction> {
+        let current = self.read_bytes(MEMORY_CURRENT_FILE)?;
+        // an unlimited cgroup starts from its current usage
+        let from = self.read_high()?;
+        let base = from.unwrap_or(current).min(self.config.max_size);
+        let to = ((base as f64 * (1.0 + adjustment)) as u64)
+            .max(self.config.min_size)
+            .min(self.config.max_size);
+        if Some(to) == from {
+            return Ok(ReclaimAction::Hold);
+        }
+        let from = from.unwrap_or(u64::MAX);
+        if !self.config.dry_run {
+            self.write(MEMORY_HIGH_FILE, to)?;
+        }
+        Ok(ReclaimAction::SetHigh { from, to })
+    }
+
+    fn reclaim(&self, adjustment: f64) -> Result<ReclaimAction> {
+        if adjustment >= 0.0 {
+            return Ok(ReclaimAction::Hold);
+        }
+        let current = self.read_bytes(MEMORY_CURRENT_FILE)?;
+        let bytes = ((current as f64 * -adjustment) as u64)
+            .min(current.saturating_sub(self.config.min_size));
+        if bytes == 0 {
+            return Ok(ReclaimAction::Hold);
+        }
+        if !self.config.dry_run {
+            match self.write(MEMORY_RECLAIM_FILE, bytes) {
+                // fewer bytes were reclaimed than asked for
+                Err(IoError { ref source, .. }) if source.kind() == ErrorKind::WouldBlock => {
+                    debug!("partial reclaim of {}", self.config.cgroup.display());
+                }
+                result => result?,
+            }
+        }
+        Ok(ReclaimAction::Reclaim { bytes })
+    }
+
+    fn read_bytes(&self, file: &str) -> Result<u64> {
+        let path = self.config.cgroup.join(file);
+        Ok(fs::read_to_string(&path)
+            .context(Operation::Read, &path)?
+            .trim()
+            .parse::<u64>()?)
+    }
+
+    /// `memory.high`, or `None` if unlimited
+    fn read_high(&self) -> Result<Option<u64>> {
+        let path = self.config.cgroup.join(MEMORY_HIGH_FILE);
+        match fs::read_to_string(&path) {
+            Ok(contents) if contents.trim() == "max" => Ok(None),
+            Ok(contents) => Ok(Some(contents.trim().parse::<u64>()?)),
+            Err(ref e) if e.kind() == ErrorKind::NotFound && self.config.cgroup.is_dir() => {
+                // the root cgroup has no memory.high
+                Ok(None)
+            }
+            Err(e) => Err(PsiError::io(Operation::Read, &path, e)),
+        }
+    }
+
+    fn write(&self, file: &str, bytes: u64) -> Result<()> {
+        write_bytes(&self.config.cgroup.join(file), bytes)
+    }
+}
+
+fn write_bytes(path: &Path, bytes: u64) -> Result<()> {
+    OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(bytes.to_string().as_bytes()))
+        .context(Operation::Write, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{pressure, write_pressure};
+    use tempfile::TempDir;
+
+    fn fake_cgroup(memory_high: &str) -> TempDir {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join(MEMORY_CURRENT_FILE), "1000000000\n").unwrap();
+        fs::write(dir.path().join(MEMORY_HIGH_FILE), memory_high).unwrap();
+        fs::write(dir.path().join(MEMORY_RECLAIM_FILE), "").unwrap();
+        set_total(dir.path(), 0);
+        dir
+    }
+
+    fn set_total(cgroup: &Path, total_us: u64) {
+        write_pressure(
+            cgroup,
+            PsiKind::Memory,
+            &pressure("0.00", total_us, "0.00", 0),
+        );
+    }
+
+    fn read(cgroup: &Path, file: &str) -> String {
+        fs::read_to_string(cgroup.join(file)).unwrap()
+    }
+
+    #[test]
+    fn should_probe_and_back_off_memory_high() {
+        let dir = fake_cgroup("max\n");
+        let config = ReclaimConfig::new(dir.path())
+            .target(PsiPercent::from_hundredths(100))
+            .max_probe(0.05)
+            .max_backoff(0.5);
+        let mut controller = ReclaimController::new(config).unwrap();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        assert_eq!(controller.step_at(at(0)).unwrap(), None);
+
+        // no pressure: probe the full step down from memory.current
+        let (pressure, action) = controller.step_at(at(1)).unwrap().unwrap();
+        assert_eq!(pressure, PsiPercent::ZERO);
+        assert_eq!(
+            action,
+            ReclaimAction::SetHigh {
+                from: u64::MAX,
+                to: 950_000_000
+            }
+        );
+        assert_eq!(read(dir.path(), MEMORY_HIGH_FILE), "950000000");
+
+        // 3% pressure against a 1% target: the full step back up
+        set_total(dir.path(), 30_000);
+        let (_, action) = controller.step_at(at(2)).unwrap().unwrap();
+        assert_eq!(
+            action,
+            ReclaimAction::SetHigh {
+                from: 950_000_000,
+                to: 1_425_000_000
+            }
+        );
+    }
+
+    #[test]
+    fn should_reclaim_down_to_min_size() {
+        let dir = fake_cgroup("max\n");
+        let config = ReclaimConfig::new(dir.path())
+            .mode(ReclaimMode::MemoryReclaim)
+            .max_probe(0.5)
+            .min_size(900_000_000);
+        let mut controller = ReclaimController::new(config).unwrap();
+        let start = Instant::now();
+        controller.step_at(start).unwrap();
+        let (_, action) = controller
+            .step_at(start + Duration::from_secs(1))
+            .unwrap()
+            .unwrap();
+        assert_eq!(action, ReclaimAction::Reclaim { bytes: 100_000_000 });
+        assert_eq!(read(dir.path(), MEMORY_RECLAIM_FILE), "100000000");
+        assert_eq!(read(dir.path(), MEMORY_HIGH_FILE), "max\n");
+    }
+
+    #[test]
+    fn should_not_write_in_dry_run() {
+        let dir = fake_cgroup("2000000000\n");
+        let config = ReclaimConfig::new(dir.path()).dry_run(true);
+        let mut controller = ReclaimController::new(config).unwrap();
+        let start = Instant::now();
+        controller.step_at(start).unwrap();
+        let (_, action) = controller
+            .step_at(start + Duration::from_secs(1))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            action,
+            ReclaimAction::SetHigh {
+                from: 2_000_000_000,
+                to: 1_980_000_000
+            }
+        );
+        assert_eq!(read(dir.path(), MEMORY_HIGH_FILE), "2000000000\n");
+    }
+
+    #[test]
+    fn should_run_through_errors_until_cgroup_is_gone() {
+        let dir = fake_cgroup("max\n");
+        let cgroup = dir.path().join("app.service");
+        fs::create_dir(&cgroup).unwrap();
+        for file in &[MEMORY_HIGH_FILE, MEMORY_RECLAIM_FILE] {
+            fs::copy(dir.path().join(file), cgroup.join(file)).unwrap();
+        }
+        fs::write(cgroup.join(MEMORY_CURRENT_FILE), "garbage").unwrap();
+        set_total(&cgroup, 0);
+        let config = ReclaimConfig::new(&cgroup).interval(Duration::from_millis(10));
+        let mut controller = ReclaimController::new(config).unwrap();
+
+        let remover = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            fs::remove_dir_all(&cgroup).unwrap();
+        });
+        let e = controller.run().unwrap_err();
+        remover.join().unwrap();
+        assert_eq!(e.kind(), PsiErrorKind::CgroupGone);
+    }
+}
@@ -0,0 +1,651 @@
+//! Recording pressure traces to disk
+//!
+//! [`PsiRecorder`] samples pressure of chosen kinds, system-wide and for
+//! chosen cgroups, and appends it to a CSV log which is rotated by size.
+//! Trigger events can be recorded alongside the samples. [`PsiTraceReader`]
+//! reads a log back for analysis or replay.
+//!
+//! Each row is a sample (`S`) or an event (`E`). Times are microseconds, the
+//! timestamp since the Unix epoch, and the source is the cgroup directory,
+//! left empty for system-wide pressure. The `full` fields are left empty when
+//! the line is missing, as for CPU before Linux 5.13. The source comes last so
+//! paths containing commas need no quoting:
+//!
+//! ```text
+//! S,timestamp,kind,some avg10,some avg60,some avg300,some total,full avg10,full avg60,full avg300,full total,source
+//! E,timestamp,kind,line,stall,window,avg10,avg60,avg300,total,source
+//! ```
+
+use std::fmt::Write as _;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::*;
+
+use crate::error::*;
+use crate::psi::*;
+use crate::reader::PsiReader;
+
+#[cfg(feature = "monitor")]
+use crate::monitor::{PsiEvent, TriggerMonitor};
+
+const TRACE_HEADER: &str = "# psi trace v1\n";
+
+/// Recorder configuration
+#[derive(Debug, Clone)]
+pub struct PsiRecorderConfig {
+    /// Log file; rotated files get a `.1`, `.2`, ... suffix, `.1` being the newest
+    pub path: PathBuf,
+    /// Kinds to sample; IRQ has no `some` line so is skipped
+    pub kinds: Vec<PsiKind>,
+    /// Whether to sample system-wide pressure
+    pub system: bool,
+    /// cgroup2 directories to sample
+    pub cgroups: Vec<PathBuf>,
+    pub interval: Duration,
+    /// Size in bytes beyond which the log is rotated
+    pub max_file_size: u64,
+    /// Number of rotated files to keep
+    pub max_files: usize,
+}
+
+impl Default for PsiRecorderConfig {
+    fn default() -> Self {
+        PsiRecorderConfig {
+            path: PathBuf::from("psi.csv"),
+            kinds: vec![PsiKind::CPU, PsiKind::IO, PsiKind::Memory],
+            system: true,
+            cgroups: Vec::new(),
+            interval: Duration::from_secs(1),
+            max_file_size: 64 * 1024 * 1024,
+            max_files: 5,
+        }
+    }
+}
+
+impl PsiRecorderConfig {
+    pub fn path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.path = path.into();
+        self
+    }
+
+    pub fn kinds(mut self, kinds: Vec<PsiKind>) -> Self {
+        self.kinds = kinds;
+        self
+    }
+
+    pub fn system(mut self, system: bool) -> Self {
+        self.system = system;
+        self
+    }
+
+    pub fn cgroup<P: Into<PathBuf>>(mut self, cgroup: P) -> Self {
+        self.cgroups.push(cgroup.into());
+        self
+    }
+
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn max_file_size(mut self, max_file_size: u64) -> Self {
+        self.max_file_size = max_file_size;
+        self
+    }
+
+    pub fn max_files(mut self, max_files: usize) -> Self {
+        self.max_files = max_files;
+        self
+    }
+}
+
+struct RecordedSource {
+    cgroup: Option<PathBuf>,
+    reader: PsiReader,
+}
+
+/// Appends sampled pressure and trigger events to a rotated CSV log
+pub struct PsiRecorder {
+    config: PsiRecorderConfig,
+    sources: Vec<RecordedSource>,
+    file: File,
+    written: u64,
+}
+
+impl PsiRecorder {
+    /// Open every configured pressure file and the log, appending to it if
+    /// it already exists
+    pub fn new(config: PsiRecorderConfig) -> Result<Self> {
+        let mut sources = Vec::new();
+        for kind in &config.kinds {
+            if *kind == PsiKind::IRQ {
+                warn!("not recording irq pressure, which has no some line");
+                continue;
+            }
+            if config.system {
+                sources.push(RecordedSource {
+                    cgroup: None,
+                    reader: PsiReader::open(*kind)?,
+                });
+            }
+            for cgroup in &config.cgroups {
+                sources.push(RecordedSource {
+                    cgroup: Some(cgroup.clone()),
+                    reader: PsiReader::cgroup(*kind, cgroup)?,
+                });
+            }
+        }
+        let (file, written) = open_log(&config.path)?;
+        Ok(PsiRecorder {
+            config,
+            sources,
+            file,
+            written,
+        })
+    }
+
+    pub fn config(&self) -> &PsiRecorderConfig {
+        &self.config
+    }
+
+    /// Sample every source once
+    ///
+    /// Sources of cgroups which have been removed are dropped and no longer
+    /// sampled.
+    pub fn record_sample(&mut self) -> Result<()> {
+        let timestamp = unix_micros(SystemTime::now());
+        let mut rows = String::new();
+        let mut removed = Vec::new();
+        for (index, source) in self.sources.iter().enumerate() {
+            let (some, full) = match source.reader.read_some_full() {
+                Ok(stats) => stats,
+                Err(ref e) if e.is_not_found() => {
+                    warn!(
+                        "no longer recording {}: {}",
+                        source.reader.path().display(),
+                        e
+                    );
+                    removed.push(index);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            let full = match full {
+                Some(full) => format!(
+                    "{},{},{},{}",
+                    full.avg10,
+                    full.avg60,
+                    full.avg300,
+                    full.total.as_micros()
+                ),
+                None => ",,,".to_string(),
+            };
+            writeln!(
+                rows,
+                "S,{},{},{},{},{},{},{},{}",
+                timestamp,
+                source.reader.kind(),
+                some.avg10,
+                some.avg60,
+                some.avg300,
+                some.total.as_micros(),
+                full,
+                source_field(source.cgroup.as_deref()),
+            )
+            .unwrap();
+        }
+        for index in removed.into_iter().rev() {
+            self.sources.remove(index);
+        }
+        self.append(&rows)
+    }
+
+    /// Record a trigger event from a [`TriggerMonitor`]
+    #[cfg(feature = "monitor")]
+    pub fn record_event(&mut self, event: &PsiEvent) -> Result<()> {
+        let trigger = &event.trigger;
+        let cgroup = trigger
+            .target_file_path
+            .parent()
+            .filter(|dir| Some(*dir) != trigger.kind.file_path().parent());
+        let stats = &event.stats;
+        let row = format!(
+            "E,{},{},{},{},{},{},{},{},{},{}\n",
+            unix_micros(SystemTime::now()),
+            trigger.kind,
+            trigger.line,
+            trigger.threshold.stall.as_micros(),
+            trigger.threshold.window.as_micros(),
+            stats.avg10,
+            stats.avg60,
+            stats.avg300,
+            stats.total.as_micros(),
+            source_field(cgroup),
+        );
+        self.append(&row)
+    }
+
+    /// Sample at the configured interval until an error occurs
+    pub fn run(&mut self) -> Result<()> {
+        loop {
+            let next = Instant::now() + self.config.interval;
+            self.record_sample()?;
+            thread::sleep(next.saturating_duration_since(Instant::now()));
+        }
+    }
+
+    /// Sample at the configured interval, recording events from `monitor`
+    /// as they fire, until an error occurs
+    #[cfg(feature = "monitor")]
+    pub fn run_with_monitor<M: TriggerMonitor>(&mut self, monitor: &mut M) -> Result<()> {
+        let mut next = Instant::now();
+        loop {
+            let now = Instant::now();
+            if now >= next {
+                self.record_sample()?;
+                next += self.config.interval;
+                continue;
+            }
+            if let Some(event) = monitor.wait_timeout(next - now)? {
+                self.record_event(&event)?;
+            }
+        }
+    }
+
+    fn append(&mut self, rows: &str) -> Result<()> {
+        if rows.is_empty() {
+            return Ok(());
+        }
+        let len = rows.len() as u64;
+        if self.written > TRACE_HEADER.len() as u64
+            && self.written + len > self.config.max_file_size
+        {
+            self.rotate()?;
+        }
+        self.file
+            .write_all(rows.as_bytes())
+            .context(Operation::Write, &self.config.path)?;
+        self.written += len;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        let path = &self.config.path;
+        info!("rotating {}", path.display());
+        let result = if self.config.max_files == 0 {
+            fs::remove_file(path)
+        } else {
+            for n in (1..self.config.max_files).rev() {
+                let from = rotated_path(path, n);
+                match fs::rename(&from, rotated_path(path, n + 1)) {
+                    Err(ref e) if e.kind() == ErrorKind::NotFound => {}
+                    result => result.context(Operation::Write, &from)?,
+                }
+            }
+            fs::rename(path, rotated_path(path, 1))
+        };
+        match result {
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {}
+            result => result.context(Operation::Write, path)?,
+        }
+        let (file, written) = open_log(path)?;
+        self.file = file;
+        self.written = written;
+        Ok(())
+    }
+}
+
+fn open_log(path: &Path) -> Result<(File, u64)> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .context(Operation::Open, path)?;
+    let mut written = file.metadata().context(Operation::Open, path)?.len();
+    if written == 0 {
+        file.write_all(TRACE_HEADER.as_bytes())
+            .context(Operation::Write, path)?;
+        written = TRACE_HEADER.len() as u64;
+    }
+    Ok((file, written))
+}
+
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{}", n));
+    PathBuf::from(rotated)
+}
+
+fn unix_micros(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros()
+}
+
+fn source_field(cgroup: Option<&Path>) -> std::borrow::Cow<'_, str> {
+    cgroup.map(Path::to_string_lossy).unwrap_or_default()
+}
+
+/// A sample of both lines of a kind's pressure
+#[derive(Debug, Clone, PartialEq)]
+pub struct PsiSampleRecord {
+    pub timestamp: SystemTime,
+    /// cgroup2 directory sampled, or `None` for system-wide pressure
+    pub cgroup: Option<PathBuf>,
+    pub kind: PsiKind,
+    pub some: Psi,
+    /// `None` when the line was missing, as for CPU before Linux 5.13
+    pub full: Option<Psi>,
+}
+
+impl PsiSampleRecord {
+    /// Both lines, if the `full` line was present
+    pub fn stats(&self) -> Option<AllPsiStats> {
+        self.full.map(|full| AllPsiStats {
+            some: self.some,
+            full,
+        })
+    }
+}
+
+/// A trigger event
+#[derive(Debug, Clone, PartialEq)]
+pub struct PsiEventRecord {
+    pub timestamp: SystemTime,
+    /// cgroup2 directory of the trigger, or `None` for system-wide pressure
+    pub cgroup: Option<PathBuf>,
+    pub kind: PsiKind,
+    pub stall: Duration,
+    pub window: Duration,
+    /// Stats of the trigger's line as read when it fired
+    pub stats: Psi,
+}
+
+/// A row of a trace log
+#[derive(Debug, Clone, PartialEq)]
+pub enum PsiRecord {
+    Sample(PsiSampleRecord),
+    Event(PsiEventRecord),
+}
+
+/// Reads back a log written by [`PsiRecorder`]
+pub struct PsiTraceReader<R> {
+    reader: R,
+    offset: usize,
+    buf: String,
+}
+
+impl PsiTraceReader<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path).context(Operation::Open, path)?;
+        Ok(Self::new(BufReader::new(file)))
+    }
+}
+
+impl<R: BufRead> PsiTraceReader<R> {
+    pub fn new(reader: R) -> Self {
+        PsiTraceReader {
+            reader,
+            offset: 0,
+            buf: String::new(),
+        }
+    }
+
+    /// Only the samples
+    pub fn samples(self) -> impl Iterator<Item = Result<PsiSampleRecord>> {
+        self.filter_map(|record| match record {
+            Ok(PsiRecord::Sample(sample)) => Some(Ok(sample)),
+            Ok(PsiRecord::Event(_)) => None,
+            Err(e) => Some(Err(e)),
+        })
+    }
+}
+
+impl<R: BufRead> Iterator for PsiTraceReader<R> {
+    type Item = Result<PsiRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            self.buf.clear();
+            let base = self.offset;
+            match self.reader.read_line(&mut self.buf) {
+                Ok(0) => return None,
+                Ok(n) => self.offset += n,
+                Err(e) => return Some(Err(e).op_context(Operation::Read)),
+            }
+            let line = self.buf.trim_end_matches('\n');
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            return Some(parse_record(line, base));
+        }
+    }
+}
+
+/// Comma separated fields of a row, tracking byte offsets for errors
+struct Fields<'a> {
+    line: &'a str,
+    pos: usize,
+    base: usize,
+}
+
+impl<'a> Fields<'a> {
+    fn next(&mut self, field: &'static str) -> StdResult<(usize, &'a str), ParseError> {
+        let rest = self.line.get(self.pos..).ok_or(MissingField {
+            offset: self.base + self.line.len(),
+            field,
+        })?;
+        let len = rest.find(',').unwrap_or(rest.len());
+        let offset = self.base + self.pos;
+        self.pos += len + 1;
+        Ok((offset, &rest[..len]))
+    }
+
+    /// The remainder of the row, which may contain commas
+    fn rest(&mut self, field: &'static str) -> StdResult<&'a str, ParseError> {
+        let rest = self.line.get(self.pos..).ok_or(MissingField {
+            offset: self.base + self.line.len(),
+            field,
+        })?;
+        self.pos = self.line.len() + 1;
+        Ok(rest)
+    }
+
+    fn micros(&mut self, field: &'static str) -> StdResult<Duration, ParseError> {
+        let (offset, value) = self.next(field)?;
+        value
+            .parse()
+            .map(Duration::from_micros)
+            .map_err(|error| TotalParseError { offset, error })
+    }
+
+    fn percent(&mut self, field: &'static str) -> StdResult<PsiPercent, ParseError> {
+        let (offset, value) = self.next(field)?;
+        value
+            .parse()
+            .map_err(|error| AvgParseError { offset, error })
+    }
+
+    fn term<T: std::str::FromStr>(&mut self, field: &'static str) -> StdResult<T, ParseError> {
+        let (offset, value) = self.next(field)?;
+        value.parse().map_err(|_| UnexpectedTerm {
+            offset,
+            term: value.to_string(),
+        })
+    }
+
+    fn psi(&mut self, line: PsiLine) -> StdResult<Psi, ParseError> {
+        Ok(Psi {
+            line,
+            avg10: self.percent("avg10")?,
+            avg60: self.percent("avg60")?,
+            avg300: self.percent("avg300")?,
+            total: self.micros("total")?,
+        })
+    }
+
+    /// A line whose fields may all be empty
+    fn optional_psi(&mut self, line: PsiLine) -> StdResult<Option<Psi>, ParseError> {
+        let rest = self.line.get(self.pos..).unwrap_or_default();
+        if !rest.starts_with(",,,,") {
+            return self.psi(line).map(Some);
+        }
+        self.pos += 4;
+        Ok(None)
+    }
+
+    fn cgroup(&mut self) -> StdResult<Option<PathBuf>, ParseError> {
+        let source = self.rest("source")?;
+        Ok(Some(source).filter(|s| !s.is_empty()).map(PathBuf::from))
+    }
+}
+
+fn parse_record(line: &str, base: usize) -> Result<PsiRecord> {
+    let mut fields = Fields { line, pos: 0, base };
+    let (offset, tag) = fields.next("record type")?;
+    if tag != "S" && tag != "E" {
+        return Err(UnexpectedTerm {
+            offset,
+            term: tag.to_string(),
+        }
+        .into());
+    }
+    let timestamp = UNIX_EPOCH + fields.micros("timestamp")?;
+    let kind = fields.term("kind")?;
+    match tag {
+        "S" => Ok(PsiRecord::Sample(PsiSampleRecord {
+            timestamp,
+            kind,
+            some: fields.psi(PsiLine::Some)?,
+            full: fields.optional_psi(PsiLine::Full)?,
+            cgroup: fields.cgroup()?,
+        })),
+        _ => {
+            let line = fields.term("line")?;
+            Ok(PsiRecord::Event(PsiEventRecord {
+                timestamp,
+                kind,
+                stall: fields.micros("stall")?,
+                window: fields.micros("window")?,
+                stats: fields.psi(line)?,
+                cgroup: fields.cgroup()?,
+            }))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{pressure, pressure_line, write_pressure};
+    use tempfile::TempDir;
+
+    fn set_total(cgroup: &Path, total: u64) {
+        write_pressure(cgroup, PsiKind::Memory, &pressure("1.50", total, "0.00", 0));
+    }
+
+    fn recorder(dir: &TempDir, cgroup: &Path, max_file_size: u64) -> PsiRecorder {
+        let config = PsiRecorderConfig::default()
+            .path(dir.path().join("psi.csv"))
+            .kinds(vec![PsiKind::Memory])
+            .system(false)
+            .cgroup(cgroup)
+            .max_file_size(max_file_size)
+            .max_files(2);
+        PsiRecorder::new(config).unwrap()
+    }
+
+    #[test]
+    fn should_round_trip_samples() {
+        let dir = TempDir::new().unwrap();
+        let cgroup = dir.path().join("app,1.service");
+        fs::create_dir(&cgroup).unwrap();
+        set_total(&cgroup, 10);
+
+        let mut recorder = recorder(&dir, &cgroup, 1 << 20);
+        recorder.record_sample().unwrap();
+        set_total(&cgroup, 20);
+        recorder.record_sample().unwrap();
+
+        let samples: Vec<_> = PsiTraceReader::open(dir.path().join("psi.csv"))
+            .unwrap()
+            .samples()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(samples.len(), 2);
+        let sample = &samples[1];
+        assert_eq!(sample.cgroup.as_deref(), Some(cgroup.as_path()));
+        assert_eq!(sample.kind, PsiKind::Memory);
+        assert_eq!(sample.some.avg10, PsiPercent::from_hundredths(150));
+        assert_eq!(sample.some.total, Duration::from_micros(20));
+        assert!(sample.stats().is_some());
+    }
+
+    #[test]
+    fn should_rotate_logs() {
+        let dir = TempDir::new().unwrap();
+        set_total(dir.path(), 0);
+        let mut recorder = recorder(&dir, dir.path(), 1);
+        for _ in 0..4 {
+            recorder.record_sample().unwrap();
+        }
+        let path = dir.path().join("psi.csv");
+        for path in &[path.clone(), rotated_path(&path, 1), rotated_path(&path, 2)] {
+            assert_eq!(PsiTraceReader::open(path).unwrap().count(), 1);
+        }
+        assert!(!rotated_path(&path, 3).exists());
+    }
+
+    #[test]
+    fn should_parse_event_rows() {
+        let log =
+            "# psi trace v1\nE,1000000,memory,full,100000,1000000,5.00,1.00,0.50,42,\nX,1,cpu,\n";
+        let mut reader = PsiTraceReader::new(log.as_bytes());
+        match reader.next().unwrap().unwrap() {
+            PsiRecord::Event(event) => {
+                assert_eq!(event.timestamp, UNIX_EPOCH + Duration::from_secs(1));
+                assert_eq!(event.stats.line, PsiLine::Full);
+                assert_eq!(event.stats.total, Duration::from_micros(42));
+                assert_eq!(event.cgroup, None);
+            }
+            record => panic!("unexpected record {:?}", record),
+        }
+        match reader.next().unwrap() {
+            Err(PsiParseError(UnexpectedTerm { offset, .. })) => assert_eq!(offset, 71),
+            record => panic!("unexpected record {:?}", record),
+        }
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn should_record_cpu_without_full_line() {
+        let dir = TempDir::new().unwrap();
+        write_pressure(
+            dir.path(),
+            PsiKind::CPU,
+            &pressure_line(PsiLine::Some, "2.00", 30),
+        );
+        let config = PsiRecorderConfig::default()
+            .path(dir.path().join("psi.csv"))
+            .kinds(vec![PsiKind::CPU])
+            .system(false)
+            .cgroup(dir.path());
+        let mut recorder = PsiRecorder::new(config).unwrap();
+        recorder.record_sample().unwrap();
+
+        let samples: Vec<_> = PsiTraceReader::open(dir.path().join("psi.csv"))
+            .unwrap()
+            .samples()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(samples.len(), 1);
+        assert_eq!(samples[0].some.total, Duration::from_micros(30));
+        assert_eq!(samples[0].full, None);
+        assert_eq!(samples[0].cgroup.as_deref(), Some(dir.path()));
+    }
+}
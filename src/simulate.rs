@@ -0,0 +1,290 @@
+//! Simulated trigger monitor
+//!
+//! [`SimulatedMonitor`] evaluates triggers against any [`PsiSource`] the way
+//! the kernel does, but on a [`MockClock`], so trigger policies can be tested
+//! end to end without generating real pressure.
+//!
+//! As in the kernel, stall growth is tracked over a sliding window which is
+//! approximated by carrying over a share of the previous window's growth, the
+//! clock is polled at a tenth of the shortest trigger window, and each trigger
+//! fires at most once per window. Time the clock is advanced by anything else
+//! is caught up on in a single poll the next time the monitor is waited on.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, ErrorKind};
+use std::time::{Duration, Instant};
+
+use log::*;
+
+use crate::error::*;
+use crate::monitor::*;
+use crate::psi::*;
+use crate::source::*;
+use crate::trigger::*;
+
+const WINDOW_MIN: Duration = Duration::from_millis(500);
+const WINDOW_MAX: Duration = Duration::from_secs(10);
+const UPDATES_PER_WINDOW: u32 = 10;
+
+struct SimulatedTrigger {
+    trigger: Trigger,
+    start_time: Duration,
+    start_value: Duration,
+    prev_growth: Duration,
+    last_event: Option<Duration>,
+}
+
+impl SimulatedTrigger {
+    /// Stall growth within the sliding window ending at `now`
+    fn window_update(&mut self, now: Duration, value: Duration) -> Duration {
+        let window = self.trigger.threshold.window;
+        let elapsed = now - self.start_time;
+        let growth = value.saturating_sub(self.start_value);
+        if elapsed > window {
+            self.start_time = now;
+            self.start_value = value;
+            self.prev_growth = growth;
+            growth
+        } else {
+            let remaining = (window - elapsed).as_micros();
+            let carried = self.prev_growth.as_micros() * remaining / window.as_micros();
+            growth + Duration::from_micros(carried as u64)
+        }
+    }
+
+    fn update(&mut self, now: Duration, psi: &Psi) -> bool {
+        let growth = self.window_update(now, psi.total);
+        if growth < self.trigger.threshold.stall {
+            return false;
+        }
+        let window = self.trigger.threshold.window;
+        if matches!(self.last_event, Some(last) if now < last + window) {
+            return false;
+        }
+        self.last_event = Some(now);
+        true
+    }
+}
+
+/// Trigger monitor driven by a [`PsiSource`] and a [`MockClock`]
+pub struct SimulatedMonitor<S> {
+    source: S,
+    clock: MockClock,
+    triggers: BTreeMap<TriggerId, SimulatedTrigger>,
+    next_id: i32,
+    pending: VecDeque<PsiEvent>,
+    /// Clock time triggers were last evaluated at
+    polled: Duration,
+    /// Instant reported for the clock's zero
+    epoch: Instant,
+}
+
+impl<S: PsiSource> SimulatedMonitor<S> {
+    pub fn new(source: S, clock: MockClock) -> Self {
+        let polled = clock.now();
+        SimulatedMonitor {
+            source,
+            clock,
+            triggers: BTreeMap::new(),
+            next_id: 0,
+            pending: VecDeque::new(),
+            polled,
+            epoch: Instant::now(),
+        }
+    }
+
+    pub fn clock(&self) -> &MockClock {
+        &self.clock
+    }
+
+    pub fn source_mut(&mut self) -> &mut S {
+        &mut self.source
+    }
+
+    /// Add a trigger, rejecting thresholds the kernel would reject
+    pub fn add_trigger(&mut self, trigger: Trigger) -> Result<TriggerId> {
+        let threshold = &trigger.threshold;
+        if threshold.window < WINDOW_MIN
+            || threshold.window > WINDOW_MAX
+            || threshold.stall == Duration::from_secs(0)
+            || threshold.stall > threshold.window
+        {
+            let e = io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid threshold: {}", threshold),
+            );
+            return Err(PsiError::trigger(Operation::WriteTrigger, &trigger, e));
+        }
+        let value = self.source.read_psi_line(trigger.kind, trigger.line)?.total;
+        info!("registering simulated {}", trigger);
+        let id = TriggerId {
+            raw_fd: self.next_id,
+        };
+        self.next_id += 1;
+        self.triggers.insert(
+            id,
+            SimulatedTrigger {
+                trigger,
+                start_time: self.clock.now(),
+                start_value: value,
+                prev_growth: Duration::from_secs(0),
+                last_event: None,
+            },
+        );
+        Ok(id)
+    }
+
+    pub fn remove_trigger(&mut self, id: TriggerId) -> Result<Trigger> {
+        self.triggers
+            .remove(&id)
+            .map(|simulated| simulated.trigger)
+            .ok_or(UnknownTrigger)
+    }
+
+    /// Advance the clock by `by`, returning every event fired on the way
+    pub fn advance(&mut self, by: Duration) -> Result<Vec<PsiEvent>> {
+        self.catch_up()?;
+        let end = self.clock.now() + by;
+        while self.clock.now() < end {
+            self.step(end)?;
+        }
+        Ok(self.pending.drain(..).collect())
+    }
+
+    /// Advance the clock until an event fires, up to `timeout`
+    ///
+    /// Returns `None` if no event fired before the timeout elapsed.
+    pub fn wait_timeout(&mut self, timeout: Duration) -> Result<Option<PsiEvent>> {
+        self.catch_up()?;
+        let end = self.clock.now() + timeout;
+        while self.pending.is_empty() && self.clock.now() < end {
+            self.step(end)?;
+        }
+        Ok(self.pending.pop_front())
+    }
+
+    /// Poll once, no later than `end`
+    fn step(&mut self, end: Duration) -> Result<()> {
+        let period = self
+            .triggers
+            .values()
+            .map(|simulated| simulated.trigger.threshold.window / UPDATES_PER_WINDOW)
+            .min();
+        let now = match period {
+            Some(period) => (self.clock.now() + period).min(end),
+            None => end,
+        };
+        self.clock.set(now);
+        self.poll(now)
+    }
+
+    /// Poll if the clock was advanced since the last poll
+    fn catch_up(&mut self) -> Result<()> {
+        let now = self.clock.now();
+        if now > self.polled {
+            self.poll(now)?;
+        }
+        Ok(())
+    }
+
+    /// Evaluate every trigger at `now`
+    fn poll(&mut self, now: Duration) -> Result<()> {
+        self.polled = now;
+        for (id, simulated) in &mut self.triggers {
+            let trigger = &simulated.trigger;
+            let stats = self.source.read_psi_line(trigger.kind, trigger.line)?;
+            if simulated.update(now, &stats) {
+                debug!("simulated psi event at {:?}: {}", now, simulated.trigger);
+                self.pending.push_back(PsiEvent {
+                    stats,
+                    trigger: simulated.trigger.clone(),
+                    id: *id,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S: PsiSource> TriggerMonitor for SimulatedMonitor<S> {
+    fn add_trigger(&mut self, trigger: Trigger) -> Result<TriggerId> {
+        SimulatedMonitor::add_trigger(self, trigger)
+    }
+
+    fn remove_trigger(&mut self, id: TriggerId) -> Result<Trigger> {
+        SimulatedMonitor::remove_trigger(self, id)
+    }
+
+    fn wait_timeout(&mut self, timeout: Duration) -> Result<Option<PsiEvent>> {
+        SimulatedMonitor::wait_timeout(self, timeout)
+    }
+
+    /// The mock clock's time, as an offset from when the monitor was created
+    fn now(&self) -> Instant {
+        self.epoch + self.clock.now()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trigger(stall_ms: u64, window_ms: u64) -> Trigger {
+        Trigger::new_builder()
+            .memory()
+            .full()
+            .stall(Duration::from_millis(stall_ms))
+            .window(Duration::from_millis(window_ms))
+            .build()
+    }
+
+    /// Memory `full` stall accruing at `percent` for `secs` seconds
+    fn steady(percent: u64, secs: u64) -> SimulatedMonitor<ReplaySource> {
+        let clock = MockClock::new();
+        let mut source = ReplaySource::new(clock.clone());
+        for sec in 0..=secs {
+            let total = Duration::from_millis(sec * percent * 10);
+            source.push_total(
+                Duration::from_secs(sec),
+                PsiKind::Memory,
+                PsiLine::Full,
+                total,
+            );
+        }
+        SimulatedMonitor::new(source, clock)
+    }
+
+    #[test]
+    fn should_fire_once_per_window() {
+        let mut monitor = steady(20, 5);
+        let id = monitor.add_trigger(trigger(100, 1000)).unwrap();
+
+        let event = monitor.wait_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(event.map(|event| event.id), Some(id));
+        assert_eq!(monitor.clock().now(), Duration::from_millis(500));
+
+        let events = monitor.advance(Duration::from_millis(4500)).unwrap();
+        assert_eq!(events.len(), 4);
+    }
+
+    #[test]
+    fn should_not_fire_below_threshold() {
+        let mut monitor = steady(5, 5);
+        let id = monitor.add_trigger(trigger(100, 1000)).unwrap();
+        assert!(monitor.advance(Duration::from_secs(5)).unwrap().is_empty());
+
+        monitor.remove_trigger(id).unwrap();
+        assert!(monitor.remove_trigger(id).is_err());
+    }
+
+    #[test]
+    fn should_reject_invalid_thresholds() {
+        let mut monitor = steady(0, 1);
+        for (stall, window) in &[(100, 100), (0, 1000), (2000, 1000), (100, 20_000)] {
+            match monitor.add_trigger(trigger(*stall, *window)) {
+                Err(e) if e.kind() == PsiErrorKind::InvalidThreshold => {}
+                _ => panic!("accepted {}ms in {}ms", stall, window),
+            }
+        }
+    }
+}
@@ -0,0 +1,253 @@
+//! Snapshot of every pressure kind
+//!
+//! [`PsiSnapshotReader`] keeps the pressure files of the system or a cgroup
+//! open as [`PsiReader`]s and re-reads them from the start on each call,
+//! avoiding the open/close churn of [`PsiKind::read_psi`] when polling
+//! frequently.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::cgroup::{classify_read_error, pid_cgroup, self_cgroup};
+use crate::error::*;
+use crate::psi::*;
+use crate::reader::PsiReader;
+
+/// Pressure of every kind captured together
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PsiSnapshot {
+    /// Time the snapshot was captured, taken before the first read
+    pub timestamp: SystemTime,
+    /// CPU `some` pressure
+    pub cpu_some: Psi,
+    /// CPU `full` pressure; `None` before Linux 5.13, which reports only `some`
+    pub cpu_full: Option<Psi>,
+    pub io: AllPsiStats,
+    pub memory: AllPsiStats,
+    /// IRQ `full` pressure; `None` on kernels or cgroups without it
+    pub irq: Option<Psi>,
+}
+
+impl PsiSnapshot {
+    /// Read a one-off snapshot of system-wide pressure
+    pub fn read_all() -> Result<PsiSnapshot> {
+        PsiSnapshotReader::new()?.read()
+    }
+
+    /// Read a one-off snapshot of a cgroup2 directory's pressure
+    pub fn read_cgroup<P: AsRef<Path>>(cgroup: P) -> Result<PsiSnapshot> {
+        PsiSnapshotReader::cgroup(cgroup)?.read()
+    }
+
+    /// Read a one-off snapshot of the pressure of a process's cgroup
+    pub fn read_pid(pid: u32) -> Result<PsiSnapshot> {
+        Self::read_cgroup(pid_cgroup(pid)?)
+    }
+
+    /// Read a one-off snapshot of the pressure of the current process's cgroup
+    pub fn read_self() -> Result<PsiSnapshot> {
+        Self::read_cgroup(self_cgroup()?)
+    }
+
+    /// Stats for a kind; `None` for [`PsiKind::IRQ`], which has only a `full`
+    /// line, and for CPU when its `full` line is missing
+    pub fn get(&self, kind: PsiKind) -> Option<AllPsiStats> {
+        match kind {
+            PsiKind::CPU => self.cpu_full.map(|full| AllPsiStats {
+                some: self.cpu_some,
+                full,
+            }),
+            PsiKind::IO => Some(self.io),
+            PsiKind::Memory => Some(self.memory),
+            PsiKind::IRQ => None,
+        }
+    }
+
+    /// Stats for a kind and line, if present
+    pub fn line(&self, kind: PsiKind, line: PsiLine) -> Option<&Psi> {
+        match (kind, line) {
+            (PsiKind::CPU, PsiLine::Some) => Some(&self.cpu_some),
+            (PsiKind::CPU, PsiLine::Full) => self.cpu_full.as_ref(),
+            (PsiKind::IO, PsiLine::Some) => Some(&self.io.some),
+            (PsiKind::IO, PsiLine::Full) => Some(&self.io.full),
+            (PsiKind::Memory, PsiLine::Some) => Some(&self.memory.some),
+            (PsiKind::Memory, PsiLine::Full) => Some(&self.memory.full),
+            (PsiKind::IRQ, PsiLine::Some) => None,
+            (PsiKind::IRQ, PsiLine::Full) => self.irq.as_ref(),
+        }
+    }
+}
+
+impl fmt::Display for PsiSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for kind in &[PsiKind::CPU, PsiKind::IO, PsiKind::Memory, PsiKind::IRQ] {
+            for line in &[PsiLine::Some, PsiLine::Full] {
+                if let Some(psi) = self.line(*kind, *line) {
+                    writeln!(f, "{} {}", kind, psi)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Snapshot reader holding pressure files open between reads
+pub struct PsiSnapshotReader {
+    cgroup: Option<PathBuf>,
+    cpu: PsiReader,
+    io: PsiReader,
+    memory: PsiReader,
+    irq: Option<PsiReader>,
+}
+
+impl PsiSnapshotReader {
+    /// Open the system-wide pressure files
+    pub fn new() -> Result<Self> {
+        Self::open(None, |kind| kind.file_path().to_path_buf())
+    }
+
+    /// Open the pressure files of a cgroup2 directory
+    ///
+    /// Fails with [`PsiError::CgroupPressureDisabled`] if PSI accounting is
+    /// turned off for the cgroup.
+    pub fn cgroup<P: AsRef<Path>>(cgroup: P) -> Result<Self> {
+        let cgroup = cgroup.as_ref();
+        Self::open(Some(cgroup.to_path_buf()), |kind| {
+            kind.cgroup_file_path(cgroup)
+        })
+        .map_err(|e| classify_read_error(cgroup, e))
+    }
+
+    fn open<F: Fn(PsiKind) -> PathBuf>(cgroup: Option<PathBuf>, path: F) -> Result<Self> {
+        let open = |kind: PsiKind| PsiReader::from_path(kind, path(kind));
+        let irq = match open(PsiKind::IRQ) {
+            Ok(reader) => Some(reader),
+            Err(ref e) if e.is_not_found() => None,
+            Err(e) => return Err(e),
+        };
+        Ok(PsiSnapshotReader {
+            cgroup,
+            cpu: open(PsiKind::CPU)?,
+            io: open(PsiKind::IO)?,
+            memory: open(PsiKind::Memory)?,
+            irq,
+        })
+    }
+
+    /// The cgroup2 directory being read, or `None` for system-wide pressure
+    pub fn cgroup_path(&self) -> Option<&Path> {
+        self.cgroup.as_deref()
+    }
+
+    /// The reader for a kind; `None` for IRQ on kernels or cgroups without it
+    pub(crate) fn reader(&self, kind: PsiKind) -> Option<&PsiReader> {
+        match kind {
+            PsiKind::CPU => Some(&self.cpu),
+            PsiKind::IO => Some(&self.io),
+            PsiKind::Memory => Some(&self.memory),
+            PsiKind::IRQ => self.irq.as_ref(),
+        }
+    }
+
+    /// Read every pressure kind
+    pub fn read(&self) -> Result<PsiSnapshot> {
+        let timestamp = SystemTime::now();
+        let (cpu_some, cpu_full) = self.cpu.read_some_full()?;
+        let io = self.io.read()?;
+        let memory = self.memory.read()?;
+        let irq = match &self.irq {
+            Some(reader) => Some(reader.read_line(PsiLine::Full)?),
+            None => None,
+        };
+        Ok(PsiSnapshot {
+            timestamp,
+            cpu_some,
+            cpu_full,
+            io,
+            memory,
+            irq,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{pressure, pressure_line, write_pressure};
+    use tempfile::TempDir;
+
+    fn set_total(dir: &Path, kind: PsiKind, some_total: u64) {
+        let contents = match kind {
+            PsiKind::IRQ => pressure_line(PsiLine::Full, "0.00", 0),
+            _ => pressure("0.00", some_total, "0.00", 0),
+        };
+        write_pressure(dir, kind, &contents);
+    }
+
+    fn write_cpu_some_only(dir: &Path) {
+        write_pressure(dir, PsiKind::CPU, &pressure_line(PsiLine::Some, "0.50", 3));
+    }
+
+    #[test]
+    fn should_reread_cached_files() {
+        let dir = TempDir::new().unwrap();
+        for kind in &[PsiKind::CPU, PsiKind::IO, PsiKind::Memory, PsiKind::IRQ] {
+            set_total(dir.path(), *kind, 1);
+        }
+        let reader = PsiSnapshotReader::cgroup(dir.path()).unwrap();
+        let first = reader.read().unwrap();
+        assert_eq!(first.memory.some.total.as_micros(), 1);
+        assert!(first.irq.is_some());
+
+        // rewrite in place so the cached handle sees the new contents
+        set_total(dir.path(), PsiKind::Memory, 2);
+        let second = reader.read().unwrap();
+        assert_eq!(second.memory.some.total.as_micros(), 2);
+        assert_eq!(
+            second.line(PsiKind::Memory, PsiLine::Some),
+            Some(&second.memory.some)
+        );
+    }
+
+    #[test]
+    fn should_allow_missing_irq() {
+        let dir = TempDir::new().unwrap();
+        for kind in &[PsiKind::CPU, PsiKind::IO, PsiKind::Memory] {
+            set_total(dir.path(), *kind, 0);
+        }
+        let snapshot = PsiSnapshot::read_cgroup(dir.path()).unwrap();
+        assert!(snapshot.irq.is_none());
+        assert!(snapshot.line(PsiKind::IRQ, PsiLine::Full).is_none());
+    }
+
+    #[test]
+    fn should_allow_cpu_without_full_line() {
+        let dir = TempDir::new().unwrap();
+        for kind in &[PsiKind::IO, PsiKind::Memory] {
+            set_total(dir.path(), *kind, 0);
+        }
+        write_cpu_some_only(dir.path());
+        let snapshot = PsiSnapshot::read_cgroup(dir.path()).unwrap();
+        assert_eq!(snapshot.cpu_some.total.as_micros(), 3);
+        assert!(snapshot.line(PsiKind::CPU, PsiLine::Full).is_none());
+        assert!(snapshot.get(PsiKind::CPU).is_none());
+        assert!(snapshot.get(PsiKind::IO).is_some());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn should_round_trip_serde() {
+        let dir = TempDir::new().unwrap();
+        for kind in &[PsiKind::CPU, PsiKind::IO, PsiKind::Memory, PsiKind::IRQ] {
+            set_total(dir.path(), *kind, 5);
+        }
+        let snapshot = PsiSnapshot::read_cgroup(dir.path()).unwrap();
+        let json = serde_json::to_string(&snapshot).unwrap();
+        assert_eq!(
+            serde_json::from_str::<PsiSnapshot>(&json).unwrap(),
+            snapshot
+        );
+    }
+}
@@ -0,0 +1,188 @@
+//! Pluggable pressure sources
+//!
+//! [`PsiSource`] abstracts over where pressure comes from, so that code can
+//! run against the real pressure files or, in tests, a [`ReplaySource`]
+//! playing back a recorded or synthetic trace under a [`MockClock`].
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::error::*;
+use crate::psi::*;
+use crate::snapshot::PsiSnapshotReader;
+
+/// Something pressure can be read from
+pub trait PsiSource {
+    /// Read a single line of a kind's pressure
+    fn read_psi_line(&mut self, kind: PsiKind, line: PsiLine) -> Result<Psi>;
+
+    /// Read both lines of a kind's pressure
+    fn read_psi(&mut self, kind: PsiKind) -> Result<AllPsiStats> {
+        Ok(AllPsiStats {
+            some: self.read_psi_line(kind, PsiLine::Some)?,
+            full: self.read_psi_line(kind, PsiLine::Full)?,
+        })
+    }
+}
+
+/// System-wide pressure, read from `/proc/pressure` on every call
+#[derive(Debug, Copy, Clone, Default)]
+pub struct SystemPsi;
+
+impl PsiSource for SystemPsi {
+    fn read_psi_line(&mut self, kind: PsiKind, line: PsiLine) -> Result<Psi> {
+        kind.read_psi_line(line)
+    }
+
+    fn read_psi(&mut self, kind: PsiKind) -> Result<AllPsiStats> {
+        kind.read_psi()
+    }
+}
+
+impl PsiSource for PsiSnapshotReader {
+    fn read_psi_line(&mut self, kind: PsiKind, line: PsiLine) -> Result<Psi> {
+        self.reader(kind).ok_or(PsiUnsupported)?.read_line(line)
+    }
+}
+
+/// Manually advanced clock measuring time since it was created
+#[derive(Debug, Clone, Default)]
+pub struct MockClock {
+    elapsed_us: Arc<AtomicU64>,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Time elapsed since the clock was created
+    pub fn now(&self) -> Duration {
+        Duration::from_micros(self.elapsed_us.load(Ordering::SeqCst))
+    }
+
+    pub fn set(&self, elapsed: Duration) {
+        self.elapsed_us
+            .store(elapsed.as_micros() as u64, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.set(self.now() + by);
+    }
+}
+
+/// Plays back pressure samples according to a [`MockClock`]
+///
+/// `total` is interpolated linearly between samples, as stall time accrues
+/// continuously between reads of the real files; the averages are those of
+/// the most recent sample. After the last sample everything holds steady.
+#[derive(Debug, Clone)]
+pub struct ReplaySource {
+    clock: MockClock,
+    traces: HashMap<(PsiKind, PsiLine), Vec<(Duration, Psi)>>,
+}
+
+impl ReplaySource {
+    pub fn new(clock: MockClock) -> Self {
+        ReplaySource {
+            clock,
+            traces: HashMap::new(),
+        }
+    }
+
+    pub fn clock(&self) -> &MockClock {
+        &self.clock
+    }
+
+    /// Add a sample of a kind's pressure taken `at` the given clock time
+    pub fn push(&mut self, at: Duration, kind: PsiKind, psi: Psi) {
+        let trace = self.traces.entry((kind, psi.line)).or_default();
+        let index = trace.partition_point(|(time, _)| *time <= at);
+        trace.insert(index, (at, psi));
+    }
+
+    /// Add samples of both lines of a kind's pressure
+    pub fn push_all(&mut self, at: Duration, kind: PsiKind, all: AllPsiStats) {
+        self.push(at, kind, all.some);
+        self.push(at, kind, all.full);
+    }
+
+    /// Add a synthetic sample of only the stall total, with zero averages
+    pub fn push_total(&mut self, at: Duration, kind: PsiKind, line: PsiLine, total: Duration) {
+        self.push(
+            at,
+            kind,
+            Psi {
+                line,
+                avg10: PsiPercent::ZERO,
+                avg60: PsiPercent::ZERO,
+                avg300: PsiPercent::ZERO,
+                total,
+            },
+        );
+    }
+}
+
+impl PsiSource for ReplaySource {
+    /// Fails with [`ParseError::MissingLine`] before the first sample
+    fn read_psi_line(&mut self, kind: PsiKind, line: PsiLine) -> Result<Psi> {
+        let now = self.clock.now();
+        let trace = self
+            .traces
+            .get(&(kind, line))
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        let index = trace.partition_point(|(time, _)| *time <= now);
+        let (then, mut psi) = *trace.get(index.wrapping_sub(1)).ok_or(MissingLine(line))?;
+        if let Some((next_time, next)) = trace.get(index) {
+            let span = (*next_time - then).as_micros();
+            let growth = next.total.saturating_sub(psi.total).as_micros();
+            let elapsed = (now - then).as_micros();
+            psi.total += Duration::from_micros((growth * elapsed / span) as u64);
+        }
+        Ok(psi)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{pressure, write_pressure};
+    use tempfile::TempDir;
+
+    #[test]
+    fn should_interpolate_replayed_totals() {
+        let clock = MockClock::new();
+        let mut source = ReplaySource::new(clock.clone());
+        let secs = Duration::from_secs;
+        source.push_total(secs(1), PsiKind::IO, PsiLine::Some, secs(0));
+        source.push_total(secs(3), PsiKind::IO, PsiLine::Some, secs(1));
+
+        let total = |source: &mut ReplaySource| {
+            source
+                .read_psi_line(PsiKind::IO, PsiLine::Some)
+                .map(|psi| psi.total)
+        };
+        assert!(total(&mut source).is_err());
+        clock.advance(secs(2));
+        assert_eq!(total(&mut source).unwrap(), Duration::from_millis(500));
+        clock.advance(secs(5));
+        assert_eq!(total(&mut source).unwrap(), secs(1));
+        assert!(source.read_psi(PsiKind::IO).is_err());
+    }
+
+    #[test]
+    fn should_read_real_files_as_source() {
+        let dir = TempDir::new().unwrap();
+        for kind in &[PsiKind::CPU, PsiKind::IO, PsiKind::Memory] {
+            write_pressure(dir.path(), *kind, &pressure("1.00", 7, "0.00", 3));
+        }
+        let mut source = PsiSnapshotReader::cgroup(dir.path()).unwrap();
+        let all = source.read_psi(PsiKind::Memory).unwrap();
+        assert_eq!(all.some.total, Duration::from_micros(7));
+        assert_eq!(all.full.total, Duration::from_micros(3));
+        assert!(source.read_psi_line(PsiKind::IRQ, PsiLine::Full).is_err());
+    }
+}
@@ -0,0 +1,337 @@
+//! Hysteresis and debouncing for trigger events
+//!
+//! The kernel fires a trigger at most once per window while its threshold
+//! holds, and says nothing when pressure goes away. [`PressureState`] turns
+//! those raw [`PsiEvent`]s into [`PressureTransition`]s, synthesising
+//! [`PressureTransition::Cleared`] once a trigger has been quiet for a number
+//! of windows.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use log::*;
+
+use crate::error::*;
+use crate::monitor::*;
+use crate::psi::*;
+use crate::trigger::*;
+
+/// Change in pressure state for a trigger
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PressureTransition {
+    /// The trigger fired after being clear
+    Entered,
+    /// The trigger fired again while already under pressure
+    Sustained,
+    /// The trigger has not fired for the configured number of windows
+    Cleared,
+}
+
+impl fmt::Display for PressureTransition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PressureTransition::Entered => write!(f, "entered"),
+            PressureTransition::Sustained => write!(f, "sustained"),
+            PressureTransition::Cleared => write!(f, "cleared"),
+        }
+    }
+}
+
+/// Debounced pressure event
+#[derive(Debug, Clone)]
+pub struct PressureEvent {
+    pub transition: PressureTransition,
+    /// PSI stats read when the trigger fired; `None` for synthesised
+    /// [`PressureTransition::Cleared`] events
+    pub stats: Option<Psi>,
+    pub trigger: Trigger,
+    pub id: TriggerId,
+}
+
+impl fmt::Display for PressureEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "pressure {}, trigger: {}", self.transition, self.trigger)?;
+        if let Some(stats) = &self.stats {
+            write!(f, ", stats: {}", stats)?;
+        }
+        Ok(())
+    }
+}
+
+/// Pressure state configuration
+#[derive(Debug, Clone)]
+pub struct PressureStateConfig {
+    /// Number of trigger windows without a firing before pressure is cleared
+    pub quiet_windows: u32,
+    /// Time after clearing during which firings are ignored rather than
+    /// reported as [`PressureTransition::Entered`]
+    pub cooldown: Duration,
+}
+
+impl Default for PressureStateConfig {
+    fn default() -> Self {
+        PressureStateConfig {
+            quiet_windows: 2,
+            cooldown: Duration::from_secs(0),
+        }
+    }
+}
+
+struct TriggerState {
+    trigger: Trigger,
+    active: bool,
+    last_fired: Instant,
+    cleared_at: Option<Instant>,
+}
+
+impl TriggerState {
+    fn clear_deadline(&self, quiet_windows: u32) -> Option<Instant> {
+        if self.active {
+            Some(self.last_fired + self.trigger.threshold.window * quiet_windows)
+        } else {
+            None
+        }
+    }
+}
+
+/// Per-trigger pressure state machine
+pub struct PressureState {
+    config: PressureStateConfig,
+    triggers: HashMap<TriggerId, TriggerState>,
+    pending: VecDeque<PressureEvent>,
+}
+
+impl PressureState {
+    pub fn new(config: PressureStateConfig) -> Self {
+        PressureState {
+            config,
+            triggers: HashMap::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Whether the trigger is currently considered under pressure
+    pub fn is_active(&self, id: TriggerId) -> bool {
+        self.triggers.get(&id).is_some_and(|state| state.active)
+    }
+
+    /// Feed a raw trigger event, returning the resulting transition if any
+    pub fn on_event(&mut self, event: &PsiEvent, now: Instant) -> Option<PressureEvent> {
+        let cooldown = self.config.cooldown;
+        let state = self
+            .triggers
+            .entry(event.id)
+            .or_insert_with(|| TriggerState {
+                trigger: event.trigger.clone(),
+                active: false,
+                last_fired: now,
+                cleared_at: None,
+            });
+        let transition = if state.active {
+            PressureTransition::Sustained
+        } else {
+            if let Some(cleared_at) = state.cleared_at {
+                if now.saturating_duration_since(cleared_at) < cooldown {
+                    debug!("ignoring event within cooldown: {}", event);
+                    return None;
+                }
+            }
+            state.active = true;
+            PressureTransition::Entered
+        };
+        state.last_fired = now;
+        Some(PressureEvent {
+            transition,
+            stats: Some(event.stats),
+            trigger: event.trigger.clone(),
+            id: event.id,
+        })
+    }
+
+    /// Synthesise [`PressureTransition::Cleared`] for every trigger that has
+    /// been quiet long enough
+    pub fn poll(&mut self, now: Instant) -> Vec<PressureEvent> {
+        let quiet_windows = self.config.quiet_windows;
+        let mut cleared = Vec::new();
+        for (id, state) in self.triggers.iter_mut() {
+            match state.clear_deadline(quiet_windows) {
+                Some(deadline) if deadline <= now => {
+                    state.active = false;
+                    state.cleared_at = Some(now);
+                    cleared.push(PressureEvent {
+                        transition: PressureTransition::Cleared,
+                        stats: None,
+                        trigger: state.trigger.clone(),
+                        id: *id,
+                    });
+                }
+                _ => {}
+            }
+        }
+        cleared
+    }
+
+    /// Earliest time at which [`PressureState::poll`] may clear a trigger
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.triggers
+            .values()
+            .filter_map(|state| state.clear_deadline(self.config.quiet_windows))
+            .min()
+    }
+
+    /// Wait for the next transition from the monitor's triggers
+    ///
+    /// Time is taken from [`TriggerMonitor::now`], so triggers are cleared on
+    /// the monitor's clock rather than the wall clock.
+    pub fn wait<M: TriggerMonitor>(&mut self, monitor: &mut M) -> Result<PressureEvent> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Ok(event);
+            }
+            let raw = match self.next_deadline() {
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(monitor.now());
+                    monitor.wait_timeout(timeout)?
+                }
+                None => Some(monitor.wait_single()?),
+            };
+            let now = monitor.now();
+            let cleared = self.poll(now);
+            self.pending.extend(cleared);
+            if let Some(raw) = raw {
+                if let Some(event) = self.on_event(&raw, now) {
+                    self.pending.push_back(event);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(raw_fd: i32) -> PsiEvent {
+        PsiEvent {
+            stats: Psi {
+                line: PsiLine::Some,
+                avg10: PsiPercent::from_hundredths(100),
+                avg60: PsiPercent::ZERO,
+                avg300: PsiPercent::ZERO,
+                total: Duration::from_micros(0),
+            },
+            trigger: Trigger::new_builder()
+                .memory()
+                .some()
+                .stall(Duration::from_millis(100))
+                .window(Duration::from_secs(1))
+                .build(),
+            id: TriggerId { raw_fd },
+        }
+    }
+
+    fn transition(event: Option<PressureEvent>) -> Option<PressureTransition> {
+        event.map(|e| e.transition)
+    }
+
+    #[test]
+    fn should_enter_sustain_and_clear() {
+        let mut state = PressureState::new(PressureStateConfig {
+            quiet_windows: 3,
+            cooldown: Duration::from_secs(0),
+        });
+        let start = Instant::now();
+        let secs = |n| start + Duration::from_secs(n);
+
+        assert_eq!(
+            transition(state.on_event(&event(5), start)),
+            Some(PressureTransition::Entered)
+        );
+        assert_eq!(
+            transition(state.on_event(&event(5), secs(1))),
+            Some(PressureTransition::Sustained)
+        );
+        assert_eq!(state.next_deadline(), Some(secs(4)));
+        assert!(state.poll(secs(3)).is_empty());
+
+        let cleared = state.poll(secs(4));
+        assert_eq!(cleared.len(), 1);
+        assert_eq!(cleared[0].transition, PressureTransition::Cleared);
+        assert!(cleared[0].stats.is_none());
+        assert!(!state.is_active(TriggerId { raw_fd: 5 }));
+        assert_eq!(state.next_deadline(), None);
+    }
+
+    #[test]
+    fn should_ignore_events_within_cooldown() {
+        let mut state = PressureState::new(PressureStateConfig {
+            quiet_windows: 1,
+            cooldown: Duration::from_secs(10),
+        });
+        let start = Instant::now();
+        let secs = |n| start + Duration::from_secs(n);
+
+        state.on_event(&event(5), start);
+        assert_eq!(state.poll(secs(1)).len(), 1);
+        assert!(state.on_event(&event(5), secs(5)).is_none());
+        assert_eq!(
+            transition(state.on_event(&event(5), secs(11))),
+            Some(PressureTransition::Entered)
+        );
+    }
+
+    #[test]
+    fn should_track_triggers_independently() {
+        let mut state = PressureState::new(PressureStateConfig::default());
+        let start = Instant::now();
+
+        state.on_event(&event(5), start);
+        assert_eq!(
+            transition(state.on_event(&event(6), start)),
+            Some(PressureTransition::Entered)
+        );
+        assert!(state.is_active(TriggerId { raw_fd: 5 }));
+        assert!(state.is_active(TriggerId { raw_fd: 6 }));
+    }
+
+    #[test]
+    fn should_wait_on_simulated_monitor() {
+        use crate::simulate::SimulatedMonitor;
+        use crate::source::{MockClock, ReplaySource};
+
+        let clock = MockClock::new();
+        let mut source = ReplaySource::new(clock.clone());
+        source.push_total(
+            Duration::from_secs(0),
+            PsiKind::Memory,
+            PsiLine::Some,
+            Duration::from_secs(0),
+        );
+        source.push_total(
+            Duration::from_secs(5),
+            PsiKind::Memory,
+            PsiLine::Some,
+            Duration::from_secs(1),
+        );
+        let mut monitor = SimulatedMonitor::new(source, clock);
+        let id = monitor.add_trigger(event(0).trigger).unwrap();
+
+        let mut state = PressureState::new(PressureStateConfig::default());
+        let entered = state.wait(&mut monitor).unwrap();
+        assert_eq!(entered.id, id);
+        assert_eq!(entered.transition, PressureTransition::Entered);
+        assert!(state.is_active(id));
+
+        // stall stops growing after five seconds
+        let cleared = loop {
+            let event = state.wait(&mut monitor).unwrap();
+            if event.transition != PressureTransition::Sustained {
+                break event;
+            }
+        };
+        assert_eq!(cleared.transition, PressureTransition::Cleared);
+        assert!(!state.is_active(id));
+        assert!(monitor.clock().now() >= Duration::from_secs(5));
+    }
+}
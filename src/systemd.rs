@@ -0,0 +1,335 @@
+//! systemd integration
+//!
+//! [`Notifier`] speaks the `sd_notify(3)` datagram protocol over
+//! `$NOTIFY_SOCKET`, so daemons built on this crate can report readiness and
+//! keep the service watchdog fed while blocked waiting for pressure events.
+//!
+//! [`unit_cgroup`] resolves a unit name such as `nginx.service` to its
+//! cgroup2 directory, which [`TriggerBuilderKind::unit`] uses to build
+//! triggers on a unit's pressure.
+//!
+//! [`TriggerBuilderKind::unit`]: crate::trigger::TriggerBuilderKind::unit
+
+use std::collections::VecDeque;
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::io::{self, ErrorKind};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{Duration, Instant};
+
+use log::*;
+
+use crate::capabilities::{find_cgroup2_mount_in, PROC_ROOT};
+use crate::error::*;
+use crate::monitor::{PsiEvent, PsiMonitor};
+use crate::walker::push_children;
+
+const NOTIFY_SOCKET_ENV: &str = "NOTIFY_SOCKET";
+const WATCHDOG_USEC_ENV: &str = "WATCHDOG_USEC";
+const WATCHDOG_PID_ENV: &str = "WATCHDOG_PID";
+
+/// Sends state changes to the service manager
+#[derive(Debug)]
+pub struct Notifier {
+    socket: UnixDatagram,
+    addr: SocketAddr,
+    watchdog: Option<Duration>,
+    last_ping: Option<Instant>,
+}
+
+impl Notifier {
+    /// Connect to `$NOTIFY_SOCKET`, or `None` when not run by systemd
+    ///
+    /// The watchdog is enabled if `$WATCHDOG_USEC` is set and
+    /// `$WATCHDOG_PID`, if set, is this process.
+    pub fn from_env() -> Result<Option<Self>> {
+        Self::from_vars(
+            env::var_os(NOTIFY_SOCKET_ENV),
+            env::var(WATCHDOG_USEC_ENV).ok(),
+            env::var(WATCHDOG_PID_ENV).ok(),
+            process::id(),
+        )
+    }
+
+    fn from_vars(
+        socket: Option<OsString>,
+        watchdog_usec: Option<String>,
+        watchdog_pid: Option<String>,
+        pid: u32,
+    ) -> Result<Option<Self>> {
+        let socket = match socket {
+            Some(socket) if !socket.is_empty() => socket,
+            _ => return Ok(None),
+        };
+        // WATCHDOG_PID is only set when the watchdog is meant for a particular process
+        let for_us = match watchdog_pid {
+            Some(watchdog_pid) => watchdog_pid == pid.to_string(),
+            None => true,
+        };
+        let watchdog = watchdog_usec
+            .filter(|_| for_us)
+            .and_then(|usec| usec.parse().ok())
+            .filter(|usec| *usec > 0)
+            .map(Duration::from_micros);
+        let mut notifier = Notifier::new(Path::new(&socket))?;
+        notifier.watchdog = watchdog;
+        Ok(Some(notifier))
+    }
+
+    /// Send to a notification socket path; a leading `@` names an abstract
+    /// socket
+    pub fn new<P: AsRef<Path>>(socket: P) -> Result<Self> {
+        let path = socket.as_ref();
+        let bytes = path.as_os_str().as_bytes();
+        let addr = match bytes.strip_prefix(b"@") {
+            Some(name) => SocketAddr::from_abstract_name(name),
+            None => SocketAddr::from_pathname(path),
+        }
+        .context(Operation::Open, path)?;
+        let socket = UnixDatagram::unbound().context(Operation::Open, path)?;
+        Ok(Notifier {
+            socket,
+            addr,
+            watchdog: None,
+            last_ping: None,
+        })
+    }
+
+    /// The watchdog timeout systemd expects pings within, if enabled
+    pub fn watchdog_timeout(&self) -> Option<Duration> {
+        self.watchdog
+    }
+
+    /// Enable the watchdog with the given timeout
+    pub fn set_watchdog_timeout(&mut self, timeout: Duration) {
+        self.watchdog = Some(timeout);
+    }
+
+    /// Send newline-separated `KEY=value` assignments
+    pub fn notify(&self, state: &str) -> Result<()> {
+        debug!("sd_notify: {}", state.replace('\n', " "));
+        self.socket
+            .send_to_addr(state.as_bytes(), &self.addr)
+            .map(drop)
+            .map_err(|source| {
+                let path = self.addr.as_pathname().map(Path::to_path_buf);
+                PsiError::io_at(Operation::Write, path, source)
+            })
+    }
+
+    /// Tell systemd startup has finished
+    pub fn ready(&self) -> Result<()> {
+        self.notify("READY=1")
+    }
+
+    /// Set the status shown by `systemctl status`
+    pub fn status(&self, status: &str) -> Result<()> {
+        self.notify(&format!("STATUS={}", status))
+    }
+
+    /// Tell systemd shutdown has begun
+    pub fn stopping(&self) -> Result<()> {
+        self.notify("STOPPING=1")
+    }
+
+    /// Ping the watchdog unconditionally
+    pub fn watchdog(&mut self) -> Result<()> {
+        self.last_ping = Some(Instant::now());
+        self.notify("WATCHDOG=1")
+    }
+
+    /// Ping the watchdog if half its timeout has passed since the last ping
+    ///
+    /// Does nothing if the watchdog isn't enabled. Call this on every
+    /// iteration of a loop to keep the service alive.
+    pub fn keep_alive(&mut self) -> Result<()> {
+        match (self.watchdog, self.last_ping) {
+            (None, _) => Ok(()),
+            (Some(timeout), Some(last)) if last.elapsed() < timeout / 2 => Ok(()),
+            (Some(_), _) => self.watchdog(),
+        }
+    }
+
+    /// How long a loop may block before it must call [`keep_alive`](Self::keep_alive)
+    pub fn keep_alive_interval(&self) -> Option<Duration> {
+        self.watchdog.map(|timeout| timeout / 2)
+    }
+}
+
+impl PsiMonitor {
+    /// Wait for a pressure event while keeping the systemd watchdog fed
+    ///
+    /// Waits in slices of half the watchdog timeout, pinging in between.
+    pub fn wait_with_watchdog(&mut self, notifier: &mut Notifier) -> Result<PsiEvent> {
+        loop {
+            notifier.keep_alive()?;
+            let event = match notifier.keep_alive_interval() {
+                Some(interval) => self.wait_timeout(interval)?,
+                None => Some(self.wait_single()?),
+            };
+            if let Some(event) = event {
+                return Ok(event);
+            }
+        }
+    }
+}
+
+/// cgroup2 directory of a systemd unit, e.g. `nginx.service` or `user.slice`
+pub fn unit_cgroup(unit: &str) -> Result<PathBuf> {
+    let root = find_cgroup2_mount_in(Path::new(PROC_ROOT))?.ok_or_else(|| {
+        PsiError::io(
+            Operation::ReadDir,
+            Path::new(PROC_ROOT),
+            io::Error::new(ErrorKind::NotFound, "cgroup2 is not mounted"),
+        )
+    })?;
+    unit_cgroup_in(root, unit)
+}
+
+/// cgroup2 directory of a systemd unit beneath `root`
+///
+/// Slices are found by their place in the hierarchy, e.g. `a-b.slice` lives
+/// at `a.slice/a-b.slice`. Other units are searched for breadth-first, in
+/// `system.slice` first and then in the rest of the hierarchy, so a system
+/// service wins over a user's unit of the same name.
+pub fn unit_cgroup_in<P: AsRef<Path>>(root: P, unit: &str) -> Result<PathBuf> {
+    let root = root.as_ref();
+    if unit.is_empty() || unit.contains('/') {
+        return Err(UnknownUnit(unit.to_string()));
+    }
+    if let Some(name) = unit.strip_suffix(".slice") {
+        let path = slice_path(root, name);
+        return if path.is_dir() {
+            Ok(path)
+        } else {
+            Err(UnknownUnit(unit.to_string()))
+        };
+    }
+    let system = root.join("system.slice");
+    if let Some(path) = find_unit(&system, unit, None)? {
+        return Ok(path);
+    }
+    find_unit(root, unit, Some(&system))?.ok_or_else(|| UnknownUnit(unit.to_string()))
+}
+
+/// Shallowest directory named `unit` beneath `start`, not descending into
+/// `skip`
+///
+/// Directories which disappear during the search are passed over.
+fn find_unit(start: &Path, unit: &str, skip: Option<&Path>) -> Result<Option<PathBuf>> {
+    let mut pending = VecDeque::new();
+    pending.push_back(start.to_path_buf());
+    let mut children = Vec::new();
+    while let Some(dir) = pending.pop_front() {
+        push_children(&dir, &mut children)?;
+        children.sort();
+        for child in children.drain(..) {
+            if child.file_name() == Some(OsStr::new(unit)) {
+                return Ok(Some(child));
+            }
+            if Some(child.as_path()) != skip {
+                pending.push_back(child);
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// `a-b-c` slice under `a.slice/a-b.slice/a-b-c.slice`; `-` is the root slice
+fn slice_path(root: &Path, name: &str) -> PathBuf {
+    let mut path = root.to_path_buf();
+    if name == "-" {
+        return path;
+    }
+    let mut prefix = String::new();
+    for part in name.split('-') {
+        if !prefix.is_empty() {
+            prefix.push('-');
+        }
+        prefix.push_str(part);
+        path.push(format!("{}.slice", prefix));
+    }
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn should_send_notifications() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("notify");
+        let listener = UnixDatagram::bind(&path).unwrap();
+        let mut notifier = Notifier::from_vars(
+            Some(path.into_os_string()),
+            Some("2000000".to_string()),
+            Some("42".to_string()),
+            42,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(notifier.watchdog_timeout(), Some(Duration::from_secs(2)));
+
+        let mut buf = [0u8; 64];
+        let mut recv = || {
+            let n = listener.recv(&mut buf).unwrap();
+            String::from_utf8(buf[..n].to_vec()).unwrap()
+        };
+        notifier.ready().unwrap();
+        assert_eq!(recv(), "READY=1");
+        notifier.keep_alive().unwrap();
+        assert_eq!(recv(), "WATCHDOG=1");
+        // within half the timeout of the last ping
+        notifier.keep_alive().unwrap();
+        notifier.status("ok").unwrap();
+        assert_eq!(recv(), "STATUS=ok");
+    }
+
+    #[test]
+    fn should_ignore_missing_socket_and_foreign_watchdog() {
+        assert!(Notifier::from_vars(None, None, None, 1).unwrap().is_none());
+        let notifier = Notifier::from_vars(
+            Some("@psi-test".into()),
+            Some("2000000".to_string()),
+            Some("7".to_string()),
+            1,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(notifier.watchdog_timeout(), None);
+    }
+
+    #[test]
+    fn should_resolve_unit_cgroups() {
+        let root = TempDir::new().unwrap();
+        let root = root.path();
+        let nginx = root.join("system.slice/nginx.service");
+        let nested =
+            root.join("user.slice/user-1000.slice/user@1000.service/app.slice/nginx.service");
+        let user = root.join("user.slice/user-1000.slice/user@1000.service");
+        // found before system.slice in a plain breadth-first search
+        let shallow = root.join("init.scope/nginx.service");
+        fs::create_dir_all(&nginx).unwrap();
+        fs::create_dir_all(&nested).unwrap();
+        fs::create_dir_all(&shallow).unwrap();
+
+        assert_eq!(unit_cgroup_in(root, "nginx.service").unwrap(), nginx);
+        assert_eq!(unit_cgroup_in(root, "user@1000.service").unwrap(), user);
+        assert_eq!(
+            unit_cgroup_in(root, "user-1000.slice").unwrap(),
+            root.join("user.slice/user-1000.slice")
+        );
+        assert_eq!(unit_cgroup_in(root, "-.slice").unwrap(), root);
+        assert!(matches!(
+            unit_cgroup_in(root, "missing.service"),
+            Err(UnknownUnit(_))
+        ));
+    }
+}
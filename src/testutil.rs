@@ -0,0 +1,116 @@
+//! Fake cgroup2 directories for tests
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::psi::*;
+#[cfg(feature = "monitor")]
+use crate::{
+    error::*,
+    monitor::{PsiEvent, TriggerId, TriggerMonitor},
+    trigger::Trigger,
+};
+#[cfg(feature = "monitor")]
+use std::collections::VecDeque;
+#[cfg(feature = "monitor")]
+use std::time::Duration;
+
+/// Contents of a pressure file with nothing stalled
+pub(crate) const IDLE_PRESSURE: &str = "some avg10=0.00 avg60=0.00 avg300=0.00 total=0\n\
+                                        full avg10=0.00 avg60=0.00 avg300=0.00 total=0\n";
+
+/// A single pressure file line with zero `avg60` and `avg300`
+pub(crate) fn pressure_line(line: PsiLine, avg10: &str, total: u64) -> String {
+    format!(
+        "{} avg10={} avg60=0.00 avg300=0.00 total={}\n",
+        line, avg10, total
+    )
+}
+
+/// Contents of a pressure file with both lines
+pub(crate) fn pressure(
+    some_avg10: &str,
+    some_total: u64,
+    full_avg10: &str,
+    full_total: u64,
+) -> String {
+    pressure_line(PsiLine::Some, some_avg10, some_total)
+        + &pressure_line(PsiLine::Full, full_avg10, full_total)
+}
+
+/// Overwrite a kind's pressure file in `cgroup`
+pub(crate) fn write_pressure(cgroup: &Path, kind: PsiKind, contents: &str) {
+    fs::write(kind.cgroup_file_path(cgroup), contents).unwrap();
+}
+
+/// Create `name` beneath `root` with idle CPU, IO and memory pressure
+pub(crate) fn fake_cgroup(root: &Path, name: &str) -> PathBuf {
+    let path = root.join(name);
+    fs::create_dir_all(&path).unwrap();
+    for kind in &[PsiKind::CPU, PsiKind::IO, PsiKind::Memory] {
+        write_pressure(&path, *kind, IDLE_PRESSURE);
+    }
+    path
+}
+
+/// What a [`ScriptedMonitor`] does next, for the trigger registered at index
+#[cfg(feature = "monitor")]
+pub(crate) enum Step {
+    /// Report an event
+    Pressure(usize),
+    /// Fail as the kernel does once the trigger's cgroup is removed
+    Gone(usize),
+}
+
+/// Monitor replaying a fixed sequence of events, failing once it runs out
+#[cfg(feature = "monitor")]
+pub(crate) struct ScriptedMonitor {
+    triggers: Vec<Trigger>,
+    script: VecDeque<Step>,
+}
+
+#[cfg(feature = "monitor")]
+impl ScriptedMonitor {
+    pub(crate) fn new(script: Vec<Step>) -> Self {
+        ScriptedMonitor {
+            triggers: Vec::new(),
+            script: script.into(),
+        }
+    }
+}
+
+#[cfg(feature = "monitor")]
+impl TriggerMonitor for ScriptedMonitor {
+    fn add_trigger(&mut self, trigger: Trigger) -> Result<TriggerId> {
+        self.triggers.push(trigger);
+        Ok(TriggerId {
+            raw_fd: self.triggers.len() as i32 - 1,
+        })
+    }
+
+    fn remove_trigger(&mut self, id: TriggerId) -> Result<Trigger> {
+        Ok(self.triggers[id.raw_fd as usize].clone())
+    }
+
+    fn wait_timeout(&mut self, _timeout: Duration) -> Result<Option<PsiEvent>> {
+        match self.script.pop_front() {
+            Some(Step::Pressure(index)) => Ok(Some(PsiEvent {
+                stats: Psi {
+                    line: PsiLine::Some,
+                    avg10: PsiPercent::ZERO,
+                    avg60: PsiPercent::ZERO,
+                    avg300: PsiPercent::ZERO,
+                    total: Duration::from_micros(0),
+                },
+                trigger: self.triggers[index].clone(),
+                id: TriggerId {
+                    raw_fd: index as i32,
+                },
+            })),
+            Some(Step::Gone(index)) => Err(PsiError::trigger_file(&self.triggers[index])),
+            None => {
+                Err(std::io::Error::from(std::io::ErrorKind::Other)).op_context(Operation::Epoll)
+            }
+        }
+    }
+}
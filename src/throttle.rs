@@ -0,0 +1,1340 @@
+//! Pressure-driven throttling of batch cgroups
+//!
+//! [`CpuThrottler`] registers CPU `some` triggers on latency-sensitive
+//! cgroups. Each time one fires, designated batch cgroups have their
+//! `cpu.weight` or `cpu.max` lowered by a bounded step. Once no trigger has
+//! fired for a while the original values are written back. Every adjustment
+//! is logged.
+//!
+//! [`IoThrottler`] does the same for IO pressure, lowering `io.weight` or
+//! setting per-device `io.max` limits on noisy neighbours.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::*;
+
+use crate::error::*;
+use crate::monitor::{PsiMonitor, TriggerMonitor};
+use crate::psi::PsiKind;
+use crate::trigger::*;
+
+const CPU_WEIGHT_FILE: &str = "cpu.weight";
+const CPU_MAX_FILE: &str = "cpu.max";
+const IO_WEIGHT_FILE: &str = "io.weight";
+const IO_MAX_FILE: &str = "io.max";
+const IO_STAT_FILE: &str = "io.stat";
+/// Smallest `cpu.max` quota the kernel accepts
+const MIN_CPU_QUOTA_US: u64 = 1000;
+
+/// Which CPU control file to adjust
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CpuControl {
+    /// Lower `cpu.weight`, which only matters while CPUs are contended
+    Weight,
+    /// Lower the `cpu.max` quota, a hard limit on CPU time per period
+    Max,
+}
+
+impl CpuControl {
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            CpuControl::Weight => CPU_WEIGHT_FILE,
+            CpuControl::Max => CPU_MAX_FILE,
+        }
+    }
+}
+
+/// Contents of `cpu.weight` or `cpu.max`
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CpuLimit {
+    Weight(u32),
+    /// Quota in microseconds per period, `None` if unlimited
+    Max {
+        quota_us: Option<u64>,
+        period_us: u64,
+    },
+}
+
+impl fmt::Display for CpuLimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CpuLimit::Weight(weight) => write!(f, "{}", weight),
+            CpuLimit::Max {
+                quota_us: Some(quota_us),
+                period_us,
+            } => write!(f, "{} {}", quota_us, period_us),
+            CpuLimit::Max {
+                quota_us: None,
+                period_us,
+            } => write!(f, "max {}", period_us),
+        }
+    }
+}
+
+impl CpuLimit {
+    fn parse(control: CpuControl, contents: &str) -> Result<Self> {
+        let contents = contents.trim();
+        match control {
+            CpuControl::Weight => Ok(CpuLimit::Weight(contents.parse()?)),
+            CpuControl::Max => {
+                let mut fields = contents.split_whitespace();
+                let quota_us = match fields.next() {
+                    Some("max") => None,
+                    Some(quota) => Some(quota.parse()?),
+                    None => {
+                        return Err(UnexpectedTerm {
+                            offset: 0,
+                            term: contents.to_string(),
+                        }
+                        .into())
+                    }
+                };
+                let period_us = match fields.next() {
+                    Some(period) => period.parse()?,
+                    None => 100_000,
+                };
+                Ok(CpuLimit::Max {
+                    quota_us,
+                    period_us,
+                })
+            }
+        }
+    }
+}
+
+/// A change written to a cgroup's CPU control file
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CpuAdjustment {
+    pub cgroup: PathBuf,
+    pub from: CpuLimit,
+    pub to: CpuLimit,
+}
+
+impl fmt::Display for CpuAdjustment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let file = match self.to {
+            CpuLimit::Weight(_) => CPU_WEIGHT_FILE,
+            CpuLimit::Max { .. } => CPU_MAX_FILE,
+        };
+        write!(
+            f,
+            "{}/{}: {} -> {}",
+            self.cgroup.display(),
+            file,
+            self.from,
+            self.to
+        )
+    }
+}
+
+/// CPU throttler configuration
+#[derive(Debug, Clone)]
+pub struct CpuThrottleConfig {
+    /// Latency-sensitive cgroups to register CPU `some` triggers on
+    pub protected: Vec<PathBuf>,
+    /// Batch cgroups which are throttled while a protected cgroup stalls
+    pub throttled: Vec<PathBuf>,
+    /// Threshold used for the trigger on each protected cgroup
+    pub threshold: TriggerThreshold,
+    pub control: CpuControl,
+    /// Fraction of the current value taken away per trigger event
+    pub step: f64,
+    /// Lowest `cpu.weight` to set
+    pub min_weight: u32,
+    /// Lowest share of a CPU to leave via `cpu.max`, e.g. 0.1
+    pub min_cpus: f64,
+    /// Time without trigger events after which original values are restored
+    pub restore_after: Duration,
+    /// Log adjustments without writing them
+    pub dry_run: bool,
+}
+
+impl Default for CpuThrottleConfig {
+    fn default() -> Self {
+        CpuThrottleConfig {
+            protected: Vec::new(),
+            throttled: Vec::new(),
+            threshold: TriggerThreshold {
+                stall: Duration::from_millis(50),
+                window: Duration::from_secs(1),
+            },
+            control: CpuControl::Weight,
+            step: 0.5,
+            min_weight: 1,
+            min_cpus: 0.1,
+            restore_after: Duration::from_secs(30),
+            dry_run: false,
+        }
+    }
+}
+
+impl CpuThrottleConfig {
+    pub fn protect<P: Into<PathBuf>>(mut self, cgroup: P) -> Self {
+        self.protected.push(cgroup.into());
+        self
+    }
+
+    pub fn throttle<P: Into<PathBuf>>(mut self, cgroup: P) -> Self {
+        self.throttled.push(cgroup.into());
+        self
+    }
+
+    pub fn threshold(mut self, threshold: TriggerThreshold) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    pub fn control(mut self, control: CpuControl) -> Self {
+        self.control = control;
+        self
+    }
+
+    pub fn step(mut self, step: f64) -> Self {
+        self.step = step;
+        self
+    }
+
+    pub fn min_weight(mut self, min_weight: u32) -> Self {
+        self.min_weight = min_weight;
+        self
+    }
+
+    pub fn min_cpus(mut self, min_cpus: f64) -> Self {
+        self.min_cpus = min_cpus;
+        self
+    }
+
+    pub fn restore_after(mut self, restore_after: Duration) -> Self {
+        self.restore_after = restore_after;
+        self
+    }
+
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+}
+
+/// Throttles batch cgroups while latency-sensitive cgroups see CPU pressure
+pub struct CpuThrottler {
+    config: CpuThrottleConfig,
+    /// Values of throttled cgroups from before they were first adjusted
+    originals: HashMap<PathBuf, CpuLimit>,
+}
+
+impl CpuThrottler {
+    pub fn new(config: CpuThrottleConfig) -> Self {
+        CpuThrottler {
+            config,
+            originals: HashMap::new(),
+        }
+    }
+
+    pub fn config(&self) -> &CpuThrottleConfig {
+        &self.config
+    }
+
+    /// Whether any cgroup is currently throttled
+    pub fn is_throttling(&self) -> bool {
+        !self.originals.is_empty()
+    }
+
+    /// Lower the CPU control of every throttled cgroup by one step
+    ///
+    /// cgroups which have gone away are skipped.
+    pub fn throttle(&mut self) -> Result<Vec<CpuAdjustment>> {
+        let mut adjustments = Vec::new();
+        for cgroup in self.config.throttled.clone() {
+            let from = match self.read(&cgroup) {
+                Ok(limit) => limit,
+                Err(ref e) if e.is_not_found() => {
+                    debug!("throttled cgroup {} went away", cgroup.display());
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            let to = self.lowered(from);
+            if to == from {
+                debug!("{} already at its lowest limit", cgroup.display());
+                continue;
+            }
+            self.originals.entry(cgroup.clone()).or_insert(from);
+            let adjustment = CpuAdjustment { cgroup, from, to };
+            self.apply(&adjustment)?;
+            adjustments.push(adjustment);
+        }
+        Ok(adjustments)
+    }
+
+    /// Write back the original values of every throttled cgroup
+    ///
+    /// Every cgroup is tried even if an earlier one fails. An original value
+    /// is only forgotten once it has been written back or its cgroup is gone,
+    /// so the cgroups which failed are restored by the next call.
+    pub fn restore(&mut self) -> Result<Vec<CpuAdjustment>> {
+        let mut adjustments = Vec::new();
+        let originals: Vec<_> = self.originals.clone().into_iter().collect();
+        let mut result = Ok(());
+        for (cgroup, original) in originals {
+            // the current value is only reported, the original is written
+            // back whatever it is
+            let from = self.read(&cgroup).unwrap_or_else(|e| {
+                debug!(
+                    "unable to read current limit of {}: {}",
+                    cgroup.display(),
+                    e
+                );
+                original
+            });
+            let adjustment = CpuAdjustment {
+                cgroup,
+                from,
+                to: original,
+            };
+            match self.apply(&adjustment) {
+                Ok(()) => {
+                    self.originals.remove(&adjustment.cgroup);
+                    adjustments.push(adjustment);
+                }
+                Err(ref e) if e.is_not_found() => {
+                    debug!("throttled cgroup {} went away", adjustment.cgroup.display());
+                    self.originals.remove(&adjustment.cgroup);
+                }
+                Err(e) => {
+                    warn!("failed to restore {}: {}", adjustment.cgroup.display(), e);
+                    result = result.and(Err(e));
+                }
+            }
+        }
+        result.map(|()| adjustments)
+    }
+
+    /// Register a CPU `some` trigger on each protected cgroup, throttling on
+    /// every event and restoring once events stop, until no protected cgroup
+    /// is left
+    pub fn run(&mut self) -> Result<()> {
+        self.run_with_monitor(&mut PsiMonitor::new()?)
+    }
+
+    /// [`run`](Self::run), registering the triggers with `monitor`
+    pub fn run_with_monitor<M: TriggerMonitor>(&mut self, monitor: &mut M) -> Result<()> {
+        let config = self.config.clone();
+        run_policy(
+            self,
+            monitor,
+            PsiKind::CPU,
+            &config.protected,
+            &config.threshold,
+            config.restore_after,
+        )
+    }
+
+    fn lowered(&self, limit: CpuLimit) -> CpuLimit {
+        let keep = (1.0 - self.config.step).max(0.0);
+        match limit {
+            CpuLimit::Weight(weight) => {
+                CpuLimit::Weight(((weight as f64 * keep) as u32).max(self.config.min_weight))
+            }
+            CpuLimit::Max {
+                quota_us,
+                period_us,
+            } => {
+                // an unlimited cgroup may use every CPU
+                let cpus = thread::available_parallelism().map_or(1, |n| n.get()) as u64;
+                let quota = quota_us.unwrap_or(period_us * cpus);
+                let min = ((period_us as f64 * self.config.min_cpus) as u64)
+                    .max(MIN_CPU_QUOTA_US)
+                    .min(quota);
+                CpuLimit::Max {
+                    quota_us: Some(((quota as f64 * keep) as u64).max(min)),
+                    period_us,
+                }
+            }
+        }
+    }
+
+    fn read(&self, cgroup: &Path) -> Result<CpuLimit> {
+        let path = cgroup.join(self.config.control.file_name());
+        let contents = fs::read_to_string(&path).context(Operation::Read, &path)?;
+        CpuLimit::parse(self.config.control, &contents)
+    }
+
+    fn apply(&self, adjustment: &CpuAdjustment) -> Result<()> {
+        if self.config.dry_run {
+            warn!("dry run: would set {}", adjustment);
+            return Ok(());
+        }
+        info!("setting {}", adjustment);
+        let path = adjustment.cgroup.join(self.config.control.file_name());
+        OpenOptions::new()
+            .write(true)
+            .truncate(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(adjustment.to.to_string().as_bytes()))
+            .context(Operation::Write, &path)
+    }
+}
+
+impl Policy for CpuThrottler {
+    fn is_throttling(&self) -> bool {
+        CpuThrottler::is_throttling(self)
+    }
+
+    fn on_pressure(&mut self) -> Result<()> {
+        self.throttle().map(drop)
+    }
+
+    fn on_clear(&mut self) -> Result<()> {
+        self.restore().map(drop)
+    }
+}
+
+/// Major and minor number of a block device, as in `8:16`
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct DeviceNumber {
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl fmt::Display for DeviceNumber {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.major, self.minor)
+    }
+}
+
+impl FromStr for DeviceNumber {
+    type Err = PsiError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (major, minor) = s.split_once(':').ok_or_else(|| UnexpectedTerm {
+            offset: 0,
+            term: s.to_string(),
+        })?;
+        Ok(DeviceNumber {
+            major: major.parse()?,
+            minor: minor.parse()?,
+        })
+    }
+}
+
+/// Cumulative IO of a cgroup on one device, a line of `io.stat`
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct IoStat {
+    pub device: DeviceNumber,
+    pub rbytes: u64,
+    pub wbytes: u64,
+    pub rios: u64,
+    pub wios: u64,
+}
+
+impl IoStat {
+    /// Read `io.stat` of a cgroup2 directory
+    pub fn read<P: AsRef<Path>>(cgroup: P) -> Result<Vec<IoStat>> {
+        let path = cgroup.as_ref().join(IO_STAT_FILE);
+        let contents = fs::read_to_string(&path).context(Operation::Read, &path)?;
+        contents.lines().map(IoStat::parse).collect()
+    }
+
+    fn parse(line: &str) -> Result<IoStat> {
+        let (device, fields) = split_device(line)?;
+        let mut stat = IoStat {
+            device,
+            rbytes: 0,
+            wbytes: 0,
+            rios: 0,
+            wios: 0,
+        };
+        for (key, value) in fields {
+            let field = match key {
+                "rbytes" => &mut stat.rbytes,
+                "wbytes" => &mut stat.wbytes,
+                "rios" => &mut stat.rios,
+                "wios" => &mut stat.wios,
+                // discards and anything added by later kernels
+                _ => continue,
+            };
+            *field = value.parse()?;
+        }
+        Ok(stat)
+    }
+}
+
+/// Limits of a cgroup on one device, a line of `io.max`; `None` is unlimited
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct IoMax {
+    pub device: DeviceNumber,
+    pub rbps: Option<u64>,
+    pub wbps: Option<u64>,
+    pub riops: Option<u64>,
+    pub wiops: Option<u64>,
+}
+
+impl IoMax {
+    pub fn unlimited(device: DeviceNumber) -> Self {
+        IoMax {
+            device,
+            rbps: None,
+            wbps: None,
+            riops: None,
+            wiops: None,
+        }
+    }
+
+    /// Read `io.max` of a cgroup2 directory; devices without limits are omitted
+    pub fn read<P: AsRef<Path>>(cgroup: P) -> Result<Vec<IoMax>> {
+        let path = cgroup.as_ref().join(IO_MAX_FILE);
+        let contents = fs::read_to_string(&path).context(Operation::Read, &path)?;
+        contents.lines().map(IoMax::parse).collect()
+    }
+
+    fn parse(line: &str) -> Result<IoMax> {
+        let (device, fields) = split_device(line)?;
+        let mut max = IoMax::unlimited(device);
+        for (key, value) in fields {
+            let field = match key {
+                "rbps" => &mut max.rbps,
+                "wbps" => &mut max.wbps,
+                "riops" => &mut max.riops,
+                "wiops" => &mut max.wiops,
+                _ => continue,
+            };
+            *field = match value {
+                "max" => None,
+                value => Some(value.parse()?),
+            };
+        }
+        Ok(max)
+    }
+}
+
+impl fmt::Display for IoMax {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let limit = |limit: Option<u64>| limit.map_or("max".to_string(), |l| l.to_string());
+        write!(
+            f,
+            "{} rbps={} wbps={} riops={} wiops={}",
+            self.device,
+            limit(self.rbps),
+            limit(self.wbps),
+            limit(self.riops),
+            limit(self.wiops)
+        )
+    }
+}
+
+/// `8:16 key=value ...` into the device and its fields
+fn split_device(line: &str) -> Result<(DeviceNumber, impl Iterator<Item = (&str, &str)>)> {
+    let mut terms = line.split_whitespace();
+    let device = terms.next().unwrap_or_default().parse()?;
+    let fields = terms.filter_map(|term| term.split_once('='));
+    Ok((device, fields))
+}
+
+/// Which IO control file to adjust
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum IoControl {
+    /// Lower the default `io.weight`, which only matters under contention
+    Weight,
+    /// Set `io.max` limits on each device the cgroup has used
+    Max,
+}
+
+/// Contents of `io.weight` or a line of `io.max`
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum IoLimit {
+    /// Default weight across devices
+    Weight(u32),
+    Max(IoMax),
+}
+
+impl fmt::Display for IoLimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IoLimit::Weight(weight) => write!(f, "default {}", weight),
+            IoLimit::Max(max) => write!(f, "{}", max),
+        }
+    }
+}
+
+/// A change written to a cgroup's IO control file
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct IoAdjustment {
+    pub cgroup: PathBuf,
+    pub from: IoLimit,
+    pub to: IoLimit,
+}
+
+impl IoAdjustment {
+    fn file_name(&self) -> &'static str {
+        match self.to {
+            IoLimit::Weight(_) => IO_WEIGHT_FILE,
+            IoLimit::Max(_) => IO_MAX_FILE,
+        }
+    }
+}
+
+impl fmt::Display for IoAdjustment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}/{}: {} -> {}",
+            self.cgroup.display(),
+            self.file_name(),
+            self.from,
+            self.to
+        )
+    }
+}
+
+/// IO throttler configuration
+#[derive(Debug, Clone)]
+pub struct IoThrottleConfig {
+    /// Latency-sensitive cgroups to register IO `some` triggers on
+    pub protected: Vec<PathBuf>,
+    /// Noisy neighbours which are throttled while a protected cgroup stalls
+    pub throttled: Vec<PathBuf>,
+    /// Threshold used for the trigger on each protected cgroup
+    pub threshold: TriggerThreshold,
+    pub control: IoControl,
+    /// Fraction of the current value taken away per trigger event
+    pub step: f64,
+    /// Lowest default `io.weight` to set
+    pub min_weight: u32,
+    /// Lowest `rbps` and `wbps` to set, in bytes per second
+    pub min_bps: u64,
+    /// Lowest `riops` and `wiops` to set
+    pub min_iops: u64,
+    /// How often [`run`](IoThrottler::run) samples `io.stat`, bounding the
+    /// window over which the rate of an unlimited device is measured
+    pub sample_interval: Duration,
+    /// Time without trigger events after which original values are restored
+    pub restore_after: Duration,
+    /// Log adjustments without writing them
+    pub dry_run: bool,
+}
+
+impl Default for IoThrottleConfig {
+    fn default() -> Self {
+        IoThrottleConfig {
+            protected: Vec::new(),
+            throttled: Vec::new(),
+            threshold: TriggerThreshold {
+                stall: Duration::from_millis(50),
+                window: Duration::from_secs(1),
+            },
+            control: IoControl::Weight,
+            step: 0.5,
+            min_weight: 1,
+            min_bps: 1 << 20,
+            min_iops: 10,
+            sample_interval: Duration::from_secs(5),
+            restore_after: Duration::from_secs(30),
+            dry_run: false,
+        }
+    }
+}
+
+impl IoThrottleConfig {
+    pub fn protect<P: Into<PathBuf>>(mut self, cgroup: P) -> Self {
+        self.protected.push(cgroup.into());
+        self
+    }
+
+    pub fn throttle<P: Into<PathBuf>>(mut self, cgroup: P) -> Self {
+        self.throttled.push(cgroup.into());
+        self
+    }
+
+    pub fn threshold(mut self, threshold: TriggerThreshold) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    pub fn control(mut self, control: IoControl) -> Self {
+        self.control = control;
+        self
+    }
+
+    pub fn step(mut self, step: f64) -> Self {
+        self.step = step;
+        self
+    }
+
+    pub fn min_weight(mut self, min_weight: u32) -> Self {
+        self.min_weight = min_weight;
+        self
+    }
+
+    pub fn min_bps(mut self, min_bps: u64) -> Self {
+        self.min_bps = min_bps;
+        self
+    }
+
+    pub fn min_iops(mut self, min_iops: u64) -> Self {
+        self.min_iops = min_iops;
+        self
+    }
+
+    pub fn sample_interval(mut self, sample_interval: Duration) -> Self {
+        self.sample_interval = sample_interval;
+        self
+    }
+
+    pub fn restore_after(mut self, restore_after: Duration) -> Self {
+        self.restore_after = restore_after;
+        self
+    }
+
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+}
+
+/// Throttles noisy neighbours while latency-sensitive cgroups see IO pressure
+///
+/// With [`IoControl::Max`], devices are taken from `io.stat`. A device
+/// without a limit is first limited to a step below the rate observed since
+/// the previous [`sample`](Self::sample), so a cgroup must have been sampled
+/// once before it can be limited. [`run`](Self::run) samples every
+/// [`sample_interval`](IoThrottleConfig::sample_interval).
+pub struct IoThrottler {
+    config: IoThrottleConfig,
+    /// Values of throttled cgroups from before they were first adjusted,
+    /// per device for `io.max`
+    originals: HashMap<(PathBuf, Option<DeviceNumber>), IoLimit>,
+    samples: HashMap<PathBuf, (Instant, Vec<IoStat>)>,
+}
+
+impl IoThrottler {
+    pub fn new(config: IoThrottleConfig) -> Self {
+        IoThrottler {
+            config,
+            originals: HashMap::new(),
+            samples: HashMap::new(),
+        }
+    }
+
+    pub fn config(&self) -> &IoThrottleConfig {
+        &self.config
+    }
+
+    /// Whether any cgroup is currently throttled
+    pub fn is_throttling(&self) -> bool {
+        !self.originals.is_empty()
+    }
+
+    /// Record `io.stat` of every throttled cgroup as read at `now`
+    pub fn sample_at(&mut self, now: Instant) -> Result<()> {
+        for cgroup in &self.config.throttled {
+            match IoStat::read(cgroup) {
+                Ok(stats) => {
+                    self.samples.insert(cgroup.clone(), (now, stats));
+                }
+                Err(ref e) if e.is_not_found() => {
+                    self.samples.remove(cgroup);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn sample(&mut self) -> Result<()> {
+        self.sample_at(Instant::now())
+    }
+
+    /// Lower the IO control of every throttled cgroup by one step
+    ///
+    /// cgroups which have gone away are skipped.
+    pub fn throttle(&mut self) -> Result<Vec<IoAdjustment>> {
+        self.throttle_at(Instant::now())
+    }
+
+    /// Lower the IO control of every throttled cgroup by one step, measuring
+    /// rates up to `now`
+    pub fn throttle_at(&mut self, now: Instant) -> Result<Vec<IoAdjustment>> {
+        let mut adjustments = Vec::new();
+        for cgroup in self.config.throttled.clone() {
+            let result = match self.config.control {
+                IoControl::Weight => self
+                    .lowered_weight(&cgroup)
+                    .map(|a| a.into_iter().collect()),
+                IoControl::Max => self.lowered_max(&cgroup, now),
+            };
+            let lowered: Vec<IoAdjustment> = match result {
+                Ok(lowered) => lowered,
+                Err(ref e) if e.is_not_found() => {
+                    debug!("throttled cgroup {} went away", cgroup.display());
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            for adjustment in lowered {
+                self.originals
+                    .entry((adjustment.cgroup.clone(), device(&adjustment.from)))
+                    .or_insert(adjustment.from);
+                self.apply(&adjustment)?;
+                adjustments.push(adjustment);
+            }
+        }
+        self.sample_at(now)?;
+        Ok(adjustments)
+    }
+
+    /// Write back the original values of every throttled cgroup
+    ///
+    /// Every cgroup is tried even if an earlier one fails. An original value
+    /// is only forgotten once it has been written back or its cgroup is gone,
+    /// so the cgroups which failed are restored by the next call.
+    pub fn restore(&mut self) -> Result<Vec<IoAdjustment>> {
+        let mut adjustments = Vec::new();
+        let mut result = Ok(());
+        let originals: Vec<_> = self.originals.clone().into_iter().collect();
+        for ((cgroup, device), original) in originals {
+            // the current value is only reported, the original is written
+            // back whatever it is
+            let from = match device {
+                None => read_weight(&cgroup).map(IoLimit::Weight),
+                Some(device) => IoMax::read(&cgroup).map(|limits| {
+                    let max = limits.into_iter().find(|max| max.device == device);
+                    IoLimit::Max(max.unwrap_or_else(|| IoMax::unlimited(device)))
+                }),
+            };
+            let from = from.unwrap_or_else(|e| {
+                debug!(
+                    "unable to read current limit of {}: {}",
+                    cgroup.display(),
+                    e
+                );
+                original
+            });
+            let key = (cgroup, device);
+            let adjustment = IoAdjustment {
+                cgroup: key.0.clone(),
+                from,
+                to: original,
+            };
+            match self.apply(&adjustment) {
+                Ok(()) => {
+                    self.originals.remove(&key);
+                    adjustments.push(adjustment);
+                }
+                Err(ref e) if e.is_not_found() => {
+                    debug!("throttled cgroup {} went away", key.0.display());
+                    self.originals.remove(&key);
+                }
+                Err(e) => {
+                    warn!("failed to restore {}: {}", key.0.display(), e);
+                    result = result.and(Err(e));
+                }
+            }
+        }
+        result.map(|()| adjustments)
+    }
+
+    /// Register an IO `some` trigger on each protected cgroup, throttling on
+    /// every event and restoring once events stop, until no protected cgroup
+    /// is left
+    pub fn run(&mut self) -> Result<()> {
+        self.run_with_monitor(&mut PsiMonitor::new()?)
+    }
+
+    /// [`run`](Self::run), registering the triggers with `monitor`
+    pub fn run_with_monitor<M: TriggerMonitor>(&mut self, monitor: &mut M) -> Result<()> {
+        self.sample()?;
+        let config = self.config.clone();
+        run_policy(
+            self,
+            monitor,
+            PsiKind::IO,
+            &config.protected,
+            &config.threshold,
+            config.restore_after,
+        )
+    }
+
+    fn keep(&self) -> f64 {
+        (1.0 - self.config.step).max(0.0)
+    }
+
+    fn lowered_weight(&self, cgroup: &Path) -> Result<Option<IoAdjustment>> {
+        let weight = read_weight(cgroup)?;
+        let lowered = ((weight as f64 * self.keep()) as u32).max(self.config.min_weight);
+        if lowered == weight {
+            debug!("{} already at its lowest weight", cgroup.display());
+            return Ok(None);
+        }
+        Ok(Some(IoAdjustment {
+            cgroup: cgroup.to_path_buf(),
+            from: IoLimit::Weight(weight),
+            to: IoLimit::Weight(lowered),
+        }))
+    }
+
+    fn lowered_max(&self, cgroup: &Path, now: Instant) -> Result<Vec<IoAdjustment>> {
+        let stats = IoStat::read(cgroup)?;
+        let limits = IoMax::read(cgroup)?;
+        let previous = self.samples.get(cgroup);
+        let mut adjustments = Vec::new();
+        for stat in &stats {
+            let from = limits
+                .iter()
+                .find(|max| max.device == stat.device)
+                .copied()
+                .unwrap_or_else(|| IoMax::unlimited(stat.device));
+            // rates since the previous sample, for devices without a limit
+            let rates = previous.and_then(|(then, stats)| {
+                let elapsed = now.checked_duration_since(*then)?.as_secs_f64();
+                let before = stats.iter().find(|before| before.device == stat.device)?;
+                let rate = |now: u64, before: u64| {
+                    (elapsed > 0.0).then(|| (now.saturating_sub(before) as f64 / elapsed) as u64)
+                };
+                Some([
+                    rate(stat.rbytes, before.rbytes),
+                    rate(stat.wbytes, before.wbytes),
+                    rate(stat.rios, before.rios),
+                    rate(stat.wios, before.wios),
+                ])
+            });
+            let rates = rates.unwrap_or_default();
+            let lower = |limit: Option<u64>, rate: Option<u64>, min: u64| {
+                let base = limit.or(rate.filter(|rate| *rate > 0))?;
+                Some(((base as f64 * self.keep()) as u64).max(min.min(base)))
+            };
+            let to = IoMax {
+                device: stat.device,
+                rbps: lower(from.rbps, rates[0], self.config.min_bps),
+                wbps: lower(from.wbps, rates[1], self.config.min_bps),
+                riops: lower(from.riops, rates[2], self.config.min_iops),
+                wiops: lower(from.wiops, rates[3], self.config.min_iops),
+            };
+            if to == from {
+                debug!(
+                    "{} already at its lowest limit on {}",
+                    cgroup.display(),
+                    stat.device
+                );
+                continue;
+            }
+            adjustments.push(IoAdjustment {
+                cgroup: cgroup.to_path_buf(),
+                from: IoLimit::Max(from),
+                to: IoLimit::Max(to),
+            });
+        }
+        Ok(adjustments)
+    }
+
+    fn apply(&self, adjustment: &IoAdjustment) -> Result<()> {
+        if self.config.dry_run {
+            warn!("dry run: would set {}", adjustment);
+            return Ok(());
+        }
+        info!("setting {}", adjustment);
+        let path = adjustment.cgroup.join(adjustment.file_name());
+        OpenOptions::new()
+            .write(true)
+            .truncate(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(adjustment.to.to_string().as_bytes()))
+            .context(Operation::Write, &path)
+    }
+}
+
+impl Policy for IoThrottler {
+    fn is_throttling(&self) -> bool {
+        IoThrottler::is_throttling(self)
+    }
+
+    fn idle_interval(&self) -> Option<Duration> {
+        match self.config.control {
+            IoControl::Max => Some(self.config.sample_interval),
+            IoControl::Weight => None,
+        }
+    }
+
+    fn on_idle(&mut self) -> Result<()> {
+        self.sample()
+    }
+
+    fn on_pressure(&mut self) -> Result<()> {
+        self.throttle().map(drop)
+    }
+
+    fn on_clear(&mut self) -> Result<()> {
+        self.restore().map(drop)
+    }
+}
+
+fn device(limit: &IoLimit) -> Option<DeviceNumber> {
+    match limit {
+        IoLimit::Weight(_) => None,
+        IoLimit::Max(max) => Some(max.device),
+    }
+}
+
+/// The default weight from `io.weight`
+fn read_weight(cgroup: &Path) -> Result<u32> {
+    let path = cgroup.join(IO_WEIGHT_FILE);
+    let contents = fs::read_to_string(&path).context(Operation::Read, &path)?;
+    let weight = contents
+        .lines()
+        .find_map(|line| line.strip_prefix("default "))
+        .ok_or_else(|| UnexpectedTerm {
+            offset: 0,
+            term: contents.trim().to_string(),
+        })?;
+    Ok(weight.trim().parse()?)
+}
+
+/// A throttler driven by [`run_policy`]
+trait Policy {
+    fn is_throttling(&self) -> bool;
+    fn on_pressure(&mut self) -> Result<()>;
+    fn on_clear(&mut self) -> Result<()>;
+
+    /// Longest time to wait for an event before calling
+    /// [`on_idle`](Policy::on_idle)
+    fn idle_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    fn on_idle(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Register `kind` `some` triggers on each protected cgroup, applying the
+/// policy on every event and clearing it once events stop
+///
+/// Errors applying the policy are logged and the loop carries on. Protected
+/// cgroups whose triggers fail are dropped, and once none are left, or the
+/// monitor itself fails, the policy is cleared before returning.
+fn run_policy<P: Policy, M: TriggerMonitor>(
+    policy: &mut P,
+    monitor: &mut M,
+    kind: PsiKind,
+    protected: &[PathBuf],
+    threshold: &TriggerThreshold,
+    restore_after: Duration,
+) -> Result<()> {
+    let mut triggers = Vec::with_capacity(protected.len());
+    for cgroup in protected {
+        let id = monitor.add_trigger(
+            Trigger::new_builder()
+                .kind(kind)
+                .cgroup(cgroup)
+                .some()
+                .threshold(threshold.clone())
+                .build(),
+        )?;
+        triggers.push((id, cgroup.clone()));
+    }
+    let mut last_event = Instant::now();
+    let result = loop {
+        let mut timeout = if policy.is_throttling() {
+            (last_event + restore_after).saturating_duration_since(Instant::now())
+        } else {
+            Duration::from_secs(u64::from(u32::MAX))
+        };
+        if let Some(interval) = policy.idle_interval() {
+            timeout = timeout.min(interval);
+        }
+        let applied = match monitor.wait_timeout(timeout) {
+            Ok(Some(event)) => {
+                info!("{}", event);
+                last_event = Instant::now();
+                policy.on_pressure()
+            }
+            Ok(None) if policy.is_throttling() && last_event.elapsed() >= restore_after => {
+                info!(
+                    "no {} pressure for {:?}; restoring throttled cgroups",
+                    kind, restore_after
+                );
+                policy.on_clear()
+            }
+            Ok(None) => policy.on_idle(),
+            // the kernel signals an error on triggers of a removed cgroup
+            Err(PsiError::PsiTriggerFileError { trigger, kind }) => {
+                let cgroup = trigger.target_file_path.parent();
+                match triggers
+                    .iter()
+                    .position(|(_, c)| Some(c.as_path()) == cgroup)
+                {
+                    Some(index) => {
+                        let (id, cgroup) = triggers.remove(index);
+                        warn!("protected cgroup {} went away", cgroup.display());
+                        monitor.remove_trigger(id).map(drop)
+                    }
+                    None => break Err(PsiError::PsiTriggerFileError { trigger, kind }),
+                }
+            }
+            Err(e) => break Err(e),
+        };
+        if let Err(e) = applied {
+            warn!("{} throttling failed: {}", kind, e);
+        }
+        if triggers.is_empty() && !protected.is_empty() {
+            info!("no protected cgroups left");
+            break Ok(());
+        }
+    };
+    if policy.is_throttling() {
+        if let Err(e) = policy.on_clear() {
+            warn!("failed to restore throttled cgroups: {}", e);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{fake_cgroup, ScriptedMonitor, Step};
+    use tempfile::TempDir;
+
+    fn fake_cgroups(file: &str, contents: &str) -> TempDir {
+        let dir = TempDir::new().unwrap();
+        for name in &["batch-a", "batch-b"] {
+            let path = fake_cgroup(dir.path(), name);
+            fs::write(path.join(file), contents).unwrap();
+        }
+        dir
+    }
+
+    fn read(dir: &TempDir, name: &str, file: &str) -> String {
+        fs::read_to_string(dir.path().join(name).join(file)).unwrap()
+    }
+
+    #[test]
+    fn should_step_weight_down_and_restore() {
+        let dir = fake_cgroups(CPU_WEIGHT_FILE, "100\n");
+        let config = CpuThrottleConfig::default()
+            .throttle(dir.path().join("batch-a"))
+            .throttle(dir.path().join("batch-b"))
+            .throttle(dir.path().join("gone"))
+            .min_weight(30);
+        let mut throttler = CpuThrottler::new(config);
+
+        assert_eq!(throttler.throttle().unwrap().len(), 2);
+        assert_eq!(read(&dir, "batch-a", CPU_WEIGHT_FILE), "50");
+        let adjustments = throttler.throttle().unwrap();
+        assert_eq!(adjustments[0].from, CpuLimit::Weight(50));
+        assert_eq!(adjustments[0].to, CpuLimit::Weight(30));
+        assert!(throttler.throttle().unwrap().is_empty());
+
+        let restored = throttler.restore().unwrap();
+        assert_eq!(restored.len(), 2);
+        assert_eq!(read(&dir, "batch-b", CPU_WEIGHT_FILE), "100");
+        assert!(!throttler.is_throttling());
+    }
+
+    #[test]
+    fn should_keep_originals_when_restore_fails() {
+        let dir = fake_cgroups(CPU_WEIGHT_FILE, "100\n");
+        let config = CpuThrottleConfig::default()
+            .throttle(dir.path().join("batch-a"))
+            .throttle(dir.path().join("batch-b"));
+        let mut throttler = CpuThrottler::new(config);
+        throttler.throttle().unwrap();
+
+        // readable but not writable, even as root
+        let weight = dir.path().join("batch-a").join(CPU_WEIGHT_FILE);
+        fs::remove_file(&weight).unwrap();
+        std::os::unix::fs::symlink("/proc/self/oom_score", &weight).unwrap();
+        assert!(throttler.restore().is_err());
+        assert!(throttler.is_throttling());
+
+        fs::remove_file(&weight).unwrap();
+        fs::write(&weight, "50").unwrap();
+        throttler.restore().unwrap();
+        assert_eq!(read(&dir, "batch-a", CPU_WEIGHT_FILE), "100");
+        assert_eq!(read(&dir, "batch-b", CPU_WEIGHT_FILE), "100");
+        assert!(!throttler.is_throttling());
+    }
+
+    #[test]
+    fn should_lower_cpu_max_quota() {
+        let dir = fake_cgroups(CPU_MAX_FILE, "200000 100000\n");
+        let config = CpuThrottleConfig::default()
+            .throttle(dir.path().join("batch-a"))
+            .control(CpuControl::Max)
+            .step(0.75)
+            .min_cpus(0.25);
+        let mut throttler = CpuThrottler::new(config);
+
+        throttler.throttle().unwrap();
+        assert_eq!(read(&dir, "batch-a", CPU_MAX_FILE), "50000 100000");
+        throttler.throttle().unwrap();
+        assert_eq!(read(&dir, "batch-a", CPU_MAX_FILE), "25000 100000");
+        throttler.restore().unwrap();
+        assert_eq!(read(&dir, "batch-a", CPU_MAX_FILE), "200000 100000");
+    }
+
+    #[test]
+    fn should_not_write_in_dry_run() {
+        let dir = fake_cgroups(CPU_MAX_FILE, "max 100000\n");
+        let config = CpuThrottleConfig::default()
+            .throttle(dir.path().join("batch-a"))
+            .control(CpuControl::Max)
+            .dry_run(true);
+        let mut throttler = CpuThrottler::new(config);
+        let adjustments = throttler.throttle().unwrap();
+        assert_eq!(
+            adjustments[0].from,
+            CpuLimit::Max {
+                quota_us: None,
+                period_us: 100_000
+            }
+        );
+        assert_eq!(read(&dir, "batch-a", CPU_MAX_FILE), "max 100000\n");
+    }
+
+    #[test]
+    fn should_restore_once_protected_cgroups_are_gone() {
+        let dir = fake_cgroups(CPU_WEIGHT_FILE, "100\n");
+        let config = CpuThrottleConfig::default()
+            .protect(dir.path().join("web"))
+            .throttle(dir.path().join("batch-a"));
+        let mut throttler = CpuThrottler::new(config);
+        let mut monitor = ScriptedMonitor::new(vec![Step::Pressure(0), Step::Gone(0)]);
+        throttler.run_with_monitor(&mut monitor).unwrap();
+        assert_eq!(read(&dir, "batch-a", CPU_WEIGHT_FILE), "100");
+        assert!(!throttler.is_throttling());
+    }
+
+    #[test]
+    fn should_keep_throttling_after_errors_and_restore_on_failure() {
+        let dir = fake_cgroups(CPU_WEIGHT_FILE, "100\n");
+        fs::write(dir.path().join("batch-b").join(CPU_WEIGHT_FILE), "garbage").unwrap();
+        let config = CpuThrottleConfig::default()
+            .protect(dir.path().join("web"))
+            .throttle(dir.path().join("batch-a"))
+            .throttle(dir.path().join("batch-b"));
+        let mut throttler = CpuThrottler::new(config);
+        let mut monitor = ScriptedMonitor::new(vec![Step::Pressure(0), Step::Pressure(0)]);
+        let err = throttler.run_with_monitor(&mut monitor).unwrap_err();
+        assert!(matches!(
+            err,
+            PsiError::IoError {
+                op: Operation::Epoll,
+                ..
+            }
+        ));
+        assert_eq!(read(&dir, "batch-a", CPU_WEIGHT_FILE), "100");
+        assert!(!throttler.is_throttling());
+    }
+
+    #[test]
+    fn should_parse_io_files() {
+        let stat =
+            IoStat::parse("8:16 rbytes=1459200 wbytes=314773504 rios=192 wios=353 dbytes=0 dios=0")
+                .unwrap();
+        assert_eq!(
+            stat.device,
+            DeviceNumber {
+                major: 8,
+                minor: 16
+            }
+        );
+        assert_eq!(stat.wbytes, 314_773_504);
+        let max = IoMax::parse("259:0 rbps=2097152 wbps=max riops=max wiops=120").unwrap();
+        assert_eq!(max.rbps, Some(2_097_152));
+        assert_eq!(max.wbps, None);
+        assert_eq!(
+            max.to_string(),
+            "259:0 rbps=2097152 wbps=max riops=max wiops=120"
+        );
+        assert!(IoMax::parse("sda rbps=1").is_err());
+    }
+
+    #[test]
+    fn should_limit_io_below_observed_rate() {
+        let dir = fake_cgroups(IO_MAX_FILE, "");
+        let cgroup = dir.path().join("batch-a");
+        let write_stat = |wbytes: u64| {
+            fs::write(
+                cgroup.join(IO_STAT_FILE),
+                format!(
+                    "8:0 rbytes=0 wbytes={} rios=0 wios={}\n",
+                    wbytes,
+                    wbytes / 4096
+                ),
+            )
+            .unwrap()
+        };
+        write_stat(0);
+        let config = IoThrottleConfig::default()
+            .throttle(&cgroup)
+            .control(IoControl::Max)
+            .min_iops(1000);
+        let mut throttler = IoThrottler::new(config);
+        let start = Instant::now();
+        throttler.sample_at(start).unwrap();
+
+        // 400MiB written in 2s
+        write_stat(400 << 20);
+        let adjustments = throttler
+            .throttle_at(start + Duration::from_secs(2))
+            .unwrap();
+        assert_eq!(adjustments.len(), 1);
+        assert_eq!(
+            fs::read_to_string(cgroup.join(IO_MAX_FILE)).unwrap(),
+            "8:0 rbps=max wbps=104857600 riops=max wiops=25600"
+        );
+
+        throttler.restore().unwrap();
+        assert_eq!(
+            fs::read_to_string(cgroup.join(IO_MAX_FILE)).unwrap(),
+            "8:0 rbps=max wbps=max riops=max wiops=max"
+        );
+    }
+
+    #[test]
+    fn should_lower_default_io_weight() {
+        let dir = fake_cgroups(IO_WEIGHT_FILE, "default 100\n8:0 200\n");
+        let config = IoThrottleConfig::default()
+            .throttle(dir.path().join("batch-a"))
+            .min_weight(40);
+        let mut throttler = IoThrottler::new(config);
+        throttler.throttle().unwrap();
+        assert_eq!(read(&dir, "batch-a", IO_WEIGHT_FILE), "default 50");
+        throttler.throttle().unwrap();
+        assert_eq!(read(&dir, "batch-a", IO_WEIGHT_FILE), "default 40");
+        assert_eq!(throttler.restore().unwrap().len(), 1);
+        assert_eq!(read(&dir, "batch-a", IO_WEIGHT_FILE), "default 100");
+    }
+
+    #[test]
+    fn should_keep_io_originals_when_restore_fails() {
+        let dir = fake_cgroups(IO_WEIGHT_FILE, "default 100\n");
+        let config = IoThrottleConfig::default()
+            .throttle(dir.path().join("batch-a"))
+            .throttle(dir.path().join("batch-b"));
+        let mut throttler = IoThrottler::new(config);
+        throttler.throttle().unwrap();
+
+        // readable but not writable, even as root
+        let weight = dir.path().join("batch-a").join(IO_WEIGHT_FILE);
+        fs::remove_file(&weight).unwrap();
+        std::os::unix::fs::symlink("/proc/self/oom_score", &weight).unwrap();
+        assert!(throttler.restore().is_err());
+        assert!(throttler.is_throttling());
+        assert_eq!(read(&dir, "batch-b", IO_WEIGHT_FILE), "default 100");
+
+        fs::remove_file(&weight).unwrap();
+        fs::write(&weight, "garbage").unwrap();
+        throttler.restore().unwrap();
+        assert_eq!(read(&dir, "batch-a", IO_WEIGHT_FILE), "default 100");
+        assert_eq!(read(&dir, "batch-b", IO_WEIGHT_FILE), "default 100");
+        assert!(!throttler.is_throttling());
+    }
+}
@@ -6,9 +6,10 @@ use std::time::Duration;
 
 use crate::*;
 
-const CPU_PRESSURE_FILEPATH: &'static str = "/proc/pressure/cpu";
-const IO_PRESSURE_FILEPATH: &'static str = "/proc/pressure/io";
-const MEMORY_PRESSURE_FILEPATH: &'static str = "/proc/pressure/memory";
+const CPU_PRESSURE_FILEPATH: &str = "/proc/pressure/cpu";
+const IO_PRESSURE_FILEPATH: &str = "/proc/pressure/io";
+const MEMORY_PRESSURE_FILEPATH: &str = "/proc/pressure/memory";
+const IRQ_PRESSURE_FILEPATH: &str = "/proc/pressure/irq";
 
 /// PSI trigger
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -25,6 +26,14 @@ impl Trigger {
         TriggerBuilder
     }
 
+    /// Copy of this trigger targeting the pressure file of a cgroup2 directory
+    pub fn for_cgroup<P: AsRef<Path>>(&self, cgroup: P) -> Trigger {
+        Trigger {
+            target_file_path: self.kind.cgroup_file_path(cgroup),
+            ..self.clone()
+        }
+    }
+
     pub(crate) fn generate_trigger(&self) -> CString {
         let mut buf = Vec::<u8>::with_capacity(32);
         match self.line {
@@ -55,6 +64,7 @@ impl TriggerBuilder {
             PsiKind::CPU => self.cpu(),
             PsiKind::IO => self.io(),
             PsiKind::Memory => self.memory(),
+            PsiKind::IRQ => self.irq(),
         }
     }
 
@@ -78,6 +88,13 @@ impl TriggerBuilder {
             target_file_path: Path::new(MEMORY_PRESSURE_FILEPATH).to_path_buf(),
         }
     }
+
+    pub fn irq(self) -> TriggerBuilderKind {
+        TriggerBuilderKind {
+            kind: PsiKind::IRQ,
+            target_file_path: Path::new(IRQ_PRESSURE_FILEPATH).to_path_buf(),
+        }
+    }
 }
 
 pub struct TriggerBuilderKind {
@@ -86,6 +103,20 @@ pub struct TriggerBuilderKind {
 }
 
 impl TriggerBuilderKind {
+    /// Target the pressure file of a cgroup2 directory instead of the system-wide one
+    pub fn cgroup<P: AsRef<Path>>(self, cgroup: P) -> TriggerBuilderKind {
+        TriggerBuilderKind {
+            kind: self.kind,
+            target_file_path: self.kind.cgroup_file_path(cgroup),
+        }
+    }
+
+    /// Target the pressure file of a systemd unit's cgroup, e.g. `nginx.service`
+    #[cfg(feature = "systemd")]
+    pub fn unit(self, unit: &str) -> Result<TriggerBuilderKind> {
+        Ok(self.cgroup(crate::systemd::unit_cgroup(unit)?))
+    }
+
     pub fn line(self, line: PsiLine) -> TriggerBuilderLine {
         TriggerBuilderLine {
             line,
@@ -184,3 +215,9 @@ impl fmt::Display for TriggerThreshold {
         )
     }
 }
+
+/// Stall time amounting to `share` of `window`
+pub(crate) fn stall_for(share: PsiPercent, window: Duration) -> Duration {
+    let share = u128::from(share.min(PsiPercent::MAX).hundredths());
+    Duration::from_micros((window.as_micros() * share / 10_000) as u64)
+}
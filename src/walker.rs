@@ -0,0 +1,246 @@
+//! Per-cgroup pressure across a cgroup2 hierarchy
+//!
+//! [`CgroupPressureWalker`] snapshots every cgroup beneath a root and ranks
+//! them by a [`WalkMetric`], answering "which cgroup is causing the pressure".
+//! Snapshots are kept between walks so cgroups can also be ranked by how much
+//! stall time they accrued since the previous walk.
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use log::*;
+
+use crate::error::*;
+use crate::psi::*;
+use crate::snapshot::PsiSnapshot;
+
+/// What to rank cgroups by, highest first
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum WalkMetric {
+    Avg10(PsiKind, PsiLine),
+    Avg60(PsiKind, PsiLine),
+    Avg300(PsiKind, PsiLine),
+    /// Stall time accrued since the previous walk; cgroups first seen in this
+    /// walk rank last
+    StallDelta(PsiKind, PsiLine),
+}
+
+impl Default for WalkMetric {
+    fn default() -> Self {
+        WalkMetric::Avg10(PsiKind::Memory, PsiLine::Full)
+    }
+}
+
+/// Pressure of a single cgroup found by a walk
+#[derive(Debug, Clone, PartialEq)]
+pub struct CgroupPressure {
+    pub cgroup: PathBuf,
+    pub snapshot: PsiSnapshot,
+    /// Snapshot of the same cgroup from the previous walk, if it existed then
+    pub previous: Option<PsiSnapshot>,
+}
+
+impl CgroupPressure {
+    /// Stall time of a kind and line accrued since the previous walk
+    pub fn stall_delta(&self, kind: PsiKind, line: PsiLine) -> Option<Duration> {
+        let now = self.snapshot.line(kind, line)?;
+        let then = self.previous.as_ref()?.line(kind, line)?;
+        // a recreated cgroup starts counting from zero again
+        Some(now.total.checked_sub(then.total).unwrap_or(now.total))
+    }
+
+    fn sort_key(&self, metric: WalkMetric) -> Option<u128> {
+        let avg = |kind, line, f: fn(&Psi) -> PsiPercent| {
+            self.snapshot
+                .line(kind, line)
+                .map(|psi| f(psi).hundredths() as u128)
+        };
+        match metric {
+            WalkMetric::Avg10(kind, line) => avg(kind, line, |psi| psi.avg10),
+            WalkMetric::Avg60(kind, line) => avg(kind, line, |psi| psi.avg60),
+            WalkMetric::Avg300(kind, line) => avg(kind, line, |psi| psi.avg300),
+            WalkMetric::StallDelta(kind, line) => {
+                self.stall_delta(kind, line).map(|delta| delta.as_micros())
+            }
+        }
+    }
+}
+
+/// Walks a cgroup2 hierarchy reading the pressure of every cgroup
+#[derive(Debug)]
+pub struct CgroupPressureWalker {
+    root: PathBuf,
+    metric: WalkMetric,
+    previous: HashMap<PathBuf, PsiSnapshot>,
+}
+
+impl CgroupPressureWalker {
+    /// Walk `root` and every cgroup beneath it
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        CgroupPressureWalker {
+            root: root.as_ref().to_path_buf(),
+            metric: WalkMetric::default(),
+            previous: HashMap::new(),
+        }
+    }
+
+    /// Rank cgroups by `metric`, memory `full` avg10 by default
+    pub fn metric(mut self, metric: WalkMetric) -> Self {
+        self.metric = metric;
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Read every cgroup's pressure, highest `metric` first
+    ///
+    /// cgroups which disappear mid-walk or have PSI accounting disabled are
+    /// left out; the descendants of the latter are still visited.
+    pub fn walk(&mut self) -> Result<Vec<CgroupPressure>> {
+        let mut results = Vec::new();
+        let mut pending = vec![self.root.clone()];
+        while let Some(cgroup) = pending.pop() {
+            match PsiSnapshot::read_cgroup(&cgroup) {
+                Ok(snapshot) => results.push(CgroupPressure {
+                    previous: self.previous.get(&cgroup).copied(),
+                    cgroup: cgroup.clone(),
+                    snapshot,
+                }),
+                Err(ref e) if e.is_not_found() => {
+                    debug!("no pressure for {}", cgroup.display());
+                }
+                Err(CgroupPressureDisabled(_)) => {
+                    debug!("psi accounting disabled for {}", cgroup.display());
+                }
+                Err(e) => return Err(e),
+            }
+            push_children(&cgroup, &mut pending)?;
+        }
+
+        self.previous = results
+            .iter()
+            .map(|result| (result.cgroup.clone(), result.snapshot))
+            .collect();
+        let metric = self.metric;
+        results.sort_by_key(|result| Reverse(result.sort_key(metric)));
+        Ok(results)
+    }
+}
+
+pub(crate) fn push_children(cgroup: &Path, pending: &mut Vec<PathBuf>) -> Result<()> {
+    let entries = match fs::read_dir(cgroup) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(PsiError::io(Operation::ReadDir, cgroup, e)),
+    };
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(PsiError::io(Operation::ReadDir, cgroup, e)),
+        };
+        match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => pending.push(entry.path()),
+            Ok(_) => {}
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(PsiError::io(Operation::ReadDir, &entry.path(), e)),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{self, pressure, pressure_line, write_pressure};
+    use tempfile::TempDir;
+
+    fn fake_cgroup(root: &Path, name: &str, memory_full_avg10: &str, total: u64) -> PathBuf {
+        let path = testutil::fake_cgroup(root, name);
+        let memory = pressure("0.00", 0, memory_full_avg10, total);
+        write_pressure(&path, PsiKind::Memory, &memory);
+        path
+    }
+
+    fn cgroups(results: &[CgroupPressure]) -> Vec<&Path> {
+        results.iter().map(|r| r.cgroup.as_path()).collect()
+    }
+
+    #[test]
+    fn should_rank_by_avg10() {
+        let root = TempDir::new().unwrap();
+        fake_cgroup(root.path(), "", "0.00", 0);
+        let quiet = fake_cgroup(root.path(), "a.slice/quiet.service", "1.00", 0);
+        let busy = fake_cgroup(root.path(), "b.slice/busy.service", "20.00", 0);
+        // no pressure files, e.g. mid-creation
+        fs::create_dir_all(root.path().join("c.slice")).unwrap();
+        let a = root.path().join("a.slice");
+        let b = root.path().join("b.slice");
+        fake_cgroup(root.path(), "a.slice", "0.50", 0);
+        fake_cgroup(root.path(), "b.slice", "10.00", 0);
+
+        let results = CgroupPressureWalker::new(root.path()).walk().unwrap();
+        assert_eq!(
+            cgroups(&results),
+            vec![
+                busy.as_path(),
+                b.as_path(),
+                quiet.as_path(),
+                a.as_path(),
+                root.path()
+            ]
+        );
+    }
+
+    #[test]
+    fn should_rank_by_stall_delta() {
+        let root = TempDir::new().unwrap();
+        let steady = fake_cgroup(root.path(), "steady.service", "50.00", 1_000);
+        let spiking = fake_cgroup(root.path(), "spiking.service", "0.00", 0);
+        let gone = fake_cgroup(root.path(), "gone.service", "0.00", 0);
+
+        let mut walker = CgroupPressureWalker::new(root.path())
+            .metric(WalkMetric::StallDelta(PsiKind::Memory, PsiLine::Full));
+        walker.walk().unwrap();
+
+        fake_cgroup(root.path(), "steady.service", "50.00", 1_100);
+        fake_cgroup(root.path(), "spiking.service", "0.00", 5_000);
+        fs::remove_dir_all(&gone).unwrap();
+        let fresh = fake_cgroup(root.path(), "fresh.service", "0.00", 9_000);
+
+        let results = walker.walk().unwrap();
+        assert_eq!(
+            cgroups(&results),
+            vec![spiking.as_path(), steady.as_path(), fresh.as_path()]
+        );
+        assert_eq!(
+            results[0].stall_delta(PsiKind::Memory, PsiLine::Full),
+            Some(Duration::from_micros(5_000))
+        );
+        assert_eq!(results[2].stall_delta(PsiKind::Memory, PsiLine::Full), None);
+    }
+
+    #[test]
+    fn should_walk_cgroups_without_cpu_full_line() {
+        let root = TempDir::new().unwrap();
+        let old = fake_cgroup(root.path(), "old.service", "5.00", 0);
+        let new = fake_cgroup(root.path(), "new.service", "1.00", 0);
+        // kernels before 5.13 report only a cpu some line
+        write_pressure(&old, PsiKind::CPU, &pressure_line(PsiLine::Some, "3.00", 0));
+
+        let results = CgroupPressureWalker::new(root.path()).walk().unwrap();
+        assert_eq!(cgroups(&results), vec![old.as_path(), new.as_path()]);
+
+        let results = CgroupPressureWalker::new(root.path())
+            .metric(WalkMetric::Avg10(PsiKind::CPU, PsiLine::Full))
+            .walk()
+            .unwrap();
+        assert_eq!(cgroups(&results), vec![new.as_path(), old.as_path()]);
+    }
+}
@@ -0,0 +1,657 @@
+//! Triggers for cgroups that come and go
+//!
+//! [`CgroupWatcher`] watches a cgroup2 subtree with inotify. When a cgroup
+//! matching a glob pattern appears, copies of that pattern's trigger templates
+//! are registered on it, and they are removed again when it goes away. The
+//! changes are reported as [`WatchEvent`]s interleaved with pressure events. If
+//! inotify's queue overflows, the subtree is rescanned to catch up.
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::io::ErrorKind;
+use std::os::unix::io::*;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use epoll::{ControlOptions, Event, Events};
+use glob::{MatchOptions, Pattern};
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+use log::*;
+
+use crate::error::*;
+use crate::monitor::*;
+use crate::trigger::*;
+use crate::walker::push_children;
+
+const MONITOR_READY: u64 = 0;
+const INOTIFY_READY: u64 = 1;
+
+/// Event produced by a [`CgroupWatcher`]
+pub enum WatchEvent {
+    /// A cgroup matching a pattern appeared and triggers were registered on it
+    CgroupAdded {
+        cgroup: PathBuf,
+        triggers: Vec<TriggerId>,
+    },
+    /// A cgroup with triggers went away and its triggers were removed
+    CgroupRemoved {
+        cgroup: PathBuf,
+        triggers: Vec<TriggerId>,
+    },
+    Pressure(PsiEvent),
+}
+
+impl fmt::Display for WatchEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WatchEvent::CgroupAdded { cgroup, triggers } => write!(
+                f,
+                "cgroup {} added with {} trigger(s)",
+                cgroup.display(),
+                triggers.len()
+            ),
+            WatchEvent::CgroupRemoved { cgroup, triggers } => write!(
+                f,
+                "cgroup {} removed with {} trigger(s)",
+                cgroup.display(),
+                triggers.len()
+            ),
+            WatchEvent::Pressure(event) => write!(f, "{}", event),
+        }
+    }
+}
+
+struct WatchRule {
+    pattern: Pattern,
+    template: Trigger,
+}
+
+impl WatchRule {
+    fn matches(&self, cgroup: &Path) -> bool {
+        let options = MatchOptions {
+            require_literal_separator: true,
+            ..MatchOptions::new()
+        };
+        self.pattern.matches_path_with(cgroup, options)
+    }
+}
+
+/// Change to the set of directories beneath the watched root
+#[derive(Debug, PartialEq)]
+enum Change {
+    Added(PathBuf),
+    Removed(PathBuf),
+    /// The tree was rescanned after inotify events were lost
+    Rescanned,
+}
+
+/// Inotify watches on every directory of a subtree
+struct CgroupTree {
+    inotify: Inotify,
+    root: PathBuf,
+    dirs: HashMap<WatchDescriptor, PathBuf>,
+    watched: HashSet<PathBuf>,
+}
+
+impl CgroupTree {
+    fn new(root: &Path) -> Result<Self> {
+        let mut tree = CgroupTree {
+            inotify: Inotify::init().context(Operation::Inotify, root)?,
+            root: root.to_path_buf(),
+            dirs: HashMap::new(),
+            watched: HashSet::new(),
+        };
+        tree.add(root, &mut Vec::new())?;
+        Ok(tree)
+    }
+
+    /// Watch `dir` and everything beneath it, recording each newly watched
+    /// directory as added
+    fn add(&mut self, dir: &Path, changes: &mut Vec<Change>) -> Result<()> {
+        if self.watched.contains(dir) {
+            return Ok(());
+        }
+        let mask = WatchMask::CREATE
+            | WatchMask::DELETE
+            | WatchMask::MOVED_FROM
+            | WatchMask::MOVED_TO
+            | WatchMask::ONLYDIR;
+        let wd = match self.inotify.watches().add(dir, mask) {
+            Ok(wd) => wd,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(PsiError::io(Operation::Inotify, dir, e)),
+        };
+        self.dirs.insert(wd, dir.to_path_buf());
+        self.watched.insert(dir.to_path_buf());
+        changes.push(Change::Added(dir.to_path_buf()));
+
+        // children created before the watch was in place raise no events
+        let mut children = Vec::new();
+        push_children(dir, &mut children)?;
+        for child in children {
+            self.add(&child, changes)?;
+        }
+        Ok(())
+    }
+
+    /// Bring the watched set back in line with the filesystem, recording
+    /// what changed
+    fn rescan(&mut self, changes: &mut Vec<Change>) -> Result<()> {
+        let gone: HashSet<PathBuf> = self
+            .watched
+            .iter()
+            .filter(|dir| !dir.is_dir())
+            .cloned()
+            .collect();
+        self.watched.retain(|dir| !gone.contains(dir));
+        self.dirs.retain(|_, dir| !gone.contains(dir));
+        let mut gone: Vec<PathBuf> = gone.into_iter().collect();
+        gone.sort();
+        changes.extend(gone.into_iter().map(Change::Removed));
+        let root = self.root.clone();
+        self.add_missing(&root, changes)?;
+        changes.push(Change::Rescanned);
+        Ok(())
+    }
+
+    /// Watch every directory beneath `dir` which is not watched yet
+    fn add_missing(&mut self, dir: &Path, changes: &mut Vec<Change>) -> Result<()> {
+        if !self.watched.contains(dir) {
+            return self.add(dir, changes);
+        }
+        let mut children = Vec::new();
+        push_children(dir, &mut children)?;
+        for child in children {
+            self.add_missing(&child, changes)?;
+        }
+        Ok(())
+    }
+
+    fn paths(&self) -> impl Iterator<Item = &Path> {
+        self.watched.iter().map(PathBuf::as_path)
+    }
+
+    /// Drain pending inotify events without blocking
+    fn read_changes(&mut self) -> Result<Vec<Change>> {
+        let mut changes = Vec::new();
+        let mut buf = [0u8; 4096];
+        loop {
+            let events = match self.inotify.read_events(&mut buf) {
+                Ok(events) => events,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e).op_context(Operation::Inotify),
+            };
+            let events: Vec<_> = events.map(|event| event.to_owned()).collect();
+            for event in events {
+                if event.mask.contains(EventMask::IGNORED) {
+                    self.dirs.remove(&event.wd);
+                    continue;
+                }
+                if event.mask.contains(EventMask::Q_OVERFLOW) {
+                    warn!(
+                        "inotify queue overflowed; rescanning {}",
+                        self.root.display()
+                    );
+                    self.rescan(&mut changes)?;
+                    continue;
+                }
+                if !event.mask.contains(EventMask::ISDIR) {
+                    continue;
+                }
+                let (parent, name) = match (self.dirs.get(&event.wd), &event.name) {
+                    (Some(parent), Some(name)) => (parent, name),
+                    _ => continue,
+                };
+                let path = parent.join(name);
+                if event
+                    .mask
+                    .intersects(EventMask::CREATE | EventMask::MOVED_TO)
+                {
+                    self.add(&path, &mut changes)?;
+                } else if event
+                    .mask
+                    .intersects(EventMask::DELETE | EventMask::MOVED_FROM)
+                {
+                    let before = self.watched.len();
+                    self.watched.retain(|dir| !dir.starts_with(&path));
+                    if self.watched.len() != before {
+                        changes.push(Change::Removed(path));
+                    }
+                }
+            }
+        }
+        Ok(changes)
+    }
+}
+
+/// Watches a cgroup2 subtree and registers triggers on matching cgroups
+pub struct CgroupWatcher<M = PsiMonitor> {
+    epoll_fd: RawFd,
+    monitor: M,
+    tree: CgroupTree,
+    rules: Vec<WatchRule>,
+    attached: BTreeMap<PathBuf, Vec<TriggerId>>,
+    pending: VecDeque<WatchEvent>,
+}
+
+impl CgroupWatcher {
+    /// Watch `root` and every cgroup beneath it
+    pub fn new<P: AsRef<Path>>(root: P) -> Result<Self> {
+        Self::with_monitor(root, PsiMonitor::new()?)
+    }
+}
+
+impl<M: TriggerMonitor + AsRawFd> CgroupWatcher<M> {
+    /// [`new`](CgroupWatcher::new), registering triggers with `monitor`
+    ///
+    /// `monitor`'s file descriptor must become readable when an event is
+    /// pending, as [`PsiMonitor`]'s does.
+    pub fn with_monitor<P: AsRef<Path>>(root: P, monitor: M) -> Result<Self> {
+        let tree = CgroupTree::new(root.as_ref())?;
+        let epoll_fd = epoll::create(true).op_context(Operation::Epoll)?;
+        let watcher = CgroupWatcher {
+            epoll_fd,
+            monitor,
+            tree,
+            rules: Vec::new(),
+            attached: BTreeMap::new(),
+            pending: VecDeque::new(),
+        };
+        for (fd, data) in &[
+            (watcher.monitor.as_raw_fd(), MONITOR_READY),
+            (watcher.tree.inotify.as_raw_fd(), INOTIFY_READY),
+        ] {
+            let event = Event::new(Events::EPOLLIN, *data);
+            epoll::ctl(epoll_fd, ControlOptions::EPOLL_CTL_ADD, *fd, event)
+                .op_context(Operation::Epoll)?;
+        }
+        Ok(watcher)
+    }
+
+    /// Register `template` on every cgroup whose path matches `pattern`, e.g.
+    /// `/sys/fs/cgroup/system.slice/*.service`
+    ///
+    /// The template's target file is ignored; it is retargeted at each
+    /// matching cgroup. cgroups which already exist are reported as added by
+    /// the next call to [`wait`](Self::wait).
+    pub fn add_rule(&mut self, pattern: &str, template: Trigger) -> Result<()> {
+        let pattern = Pattern::new(pattern).map_err(|e| InvalidPattern(e.to_string()))?;
+        let rule = WatchRule { pattern, template };
+        let mut existing: Vec<PathBuf> = self
+            .tree
+            .paths()
+            .filter(|cgroup| rule.matches(cgroup))
+            .map(Path::to_path_buf)
+            .collect();
+        existing.sort();
+        self.rules.push(rule);
+        let rule = self.rules.len() - 1;
+        for cgroup in existing {
+            if let Some(id) = self.attach(&cgroup, rule)? {
+                self.attached.entry(cgroup.clone()).or_default().push(id);
+                self.pending.push_back(WatchEvent::CgroupAdded {
+                    cgroup,
+                    triggers: vec![id],
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// The underlying monitor, for registering triggers on fixed paths
+    pub fn monitor_mut(&mut self) -> &mut M {
+        &mut self.monitor
+    }
+
+    /// cgroups which currently have triggers registered
+    pub fn cgroups(&self) -> impl Iterator<Item = (&Path, &[TriggerId])> {
+        self.attached
+            .iter()
+            .map(|(cgroup, ids)| (cgroup.as_path(), ids.as_slice()))
+    }
+
+    /// Wait for a cgroup to be added or removed, or for a pressure event
+    pub fn wait(&mut self) -> Result<WatchEvent> {
+        loop {
+            if let Some(event) = self.wait_event(-1)? {
+                return Ok(event);
+            }
+        }
+    }
+
+    /// Wait up to `timeout` for a cgroup change or pressure event
+    ///
+    /// Returns `None` if nothing happened before the timeout elapsed.
+    pub fn wait_timeout(&mut self, timeout: Duration) -> Result<Option<WatchEvent>> {
+        let timeout_ms = timeout.as_millis().min(i32::MAX as u128) as i32;
+        self.wait_event(timeout_ms)
+    }
+
+    fn wait_event(&mut self, timeout_ms: i32) -> Result<Option<WatchEvent>> {
+        if let Some(event) = self.pending.pop_front() {
+            return Ok(Some(event));
+        }
+        let mut events = [Event { events: 0, data: 0 }; 2];
+        let epoll_fd = self.epoll_fd;
+        let n = wait_retrying(timeout_ms, &mut events, |timeout_ms, events| {
+            epoll::wait(epoll_fd, timeout_ms, events)
+        })?;
+        let ready = |data| events[..n].iter().any(|event| event.data == data);
+        // handle removals first so triggers on removed cgroups are gone
+        // before their files are read
+        if ready(INOTIFY_READY) {
+            self.handle_changes()?;
+        }
+        if ready(MONITOR_READY) {
+            loop {
+                match self.monitor.wait_timeout(Duration::from_secs(0)) {
+                    Ok(Some(event)) => self.pending.push_back(WatchEvent::Pressure(event)),
+                    Ok(None) => break,
+                    // the kernel signals an error on triggers of a removed
+                    // cgroup, possibly before inotify reports the removal
+                    Err(PsiTriggerFileError { trigger, kind }) => {
+                        let cgroup = trigger.target_file_path.parent().map(Path::to_path_buf);
+                        match cgroup.filter(|cgroup| self.attached.contains_key(cgroup)) {
+                            Some(cgroup) => {
+                                self.detach(cgroup.clone())?;
+                                // removed and created again while events
+                                // were lost; the triggers were on the old one
+                                if self.tree.watched.contains(&cgroup) && cgroup.is_dir() {
+                                    self.attach_rules(cgroup)?;
+                                }
+                            }
+                            None => return Err(PsiTriggerFileError { trigger, kind }),
+                        }
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Ok(self.pending.pop_front())
+    }
+
+    fn handle_changes(&mut self) -> Result<()> {
+        let changes = self.tree.read_changes()?;
+        self.apply_changes(changes)
+    }
+
+    fn apply_changes(&mut self, changes: Vec<Change>) -> Result<()> {
+        for change in changes {
+            match change {
+                Change::Added(cgroup) => self.attach_rules(cgroup)?,
+                Change::Removed(removed) => {
+                    let gone: Vec<PathBuf> = self
+                        .attached
+                        .keys()
+                        .filter(|cgroup| cgroup.starts_with(&removed))
+                        .cloned()
+                        .collect();
+                    for cgroup in gone {
+                        self.detach(cgroup)?;
+                    }
+                }
+                // cgroups whose triggers went away with lost events
+                Change::Rescanned => {
+                    let mut unattached: Vec<PathBuf> = self
+                        .tree
+                        .paths()
+                        .filter(|cgroup| !self.attached.contains_key(*cgroup))
+                        .map(Path::to_path_buf)
+                        .collect();
+                    unattached.sort();
+                    for cgroup in unattached {
+                        self.attach_rules(cgroup)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Register every matching rule's template on a cgroup, reporting it as
+    /// added if any were
+    fn attach_rules(&mut self, cgroup: PathBuf) -> Result<()> {
+        let mut triggers = Vec::new();
+        for rule in 0..self.rules.len() {
+            if self.rules[rule].matches(&cgroup) {
+                triggers.extend(self.attach(&cgroup, rule)?);
+            }
+        }
+        if !triggers.is_empty() {
+            self.attached.insert(cgroup.clone(), triggers.clone());
+            self.pending
+                .push_back(WatchEvent::CgroupAdded { cgroup, triggers });
+        }
+        Ok(())
+    }
+
+    /// Remove the triggers registered on a cgroup, reporting it as removed
+    fn detach(&mut self, cgroup: PathBuf) -> Result<()> {
+        let triggers = self.attached.remove(&cgroup).unwrap_or_default();
+        for id in &triggers {
+            self.monitor.remove_trigger(*id)?;
+        }
+        self.pending
+            .push_back(WatchEvent::CgroupRemoved { cgroup, triggers });
+        Ok(())
+    }
+
+    /// Register a rule's template on a cgroup; `None` if it went away first
+    fn attach(&mut self, cgroup: &Path, rule: usize) -> Result<Option<TriggerId>> {
+        let trigger = self.rules[rule].template.for_cgroup(cgroup);
+        match self.monitor.add_trigger(trigger) {
+            Ok(id) => Ok(Some(id)),
+            Err(ref e) if e.is_not_found() => {
+                debug!("cgroup {} went away before registering", cgroup.display());
+                Ok(None)
+            }
+            Err(e) => {
+                error!("failed to register trigger on {}: {}", cgroup.display(), e);
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<M> Drop for CgroupWatcher<M> {
+    fn drop(&mut self) {
+        let _ = epoll::close(self.epoll_fd);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::psi::PsiKind;
+    use std::fs;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    /// Keeps triggers without opening them; its epoll fd never becomes
+    /// readable
+    struct FakeMonitor {
+        epoll_fd: RawFd,
+        triggers: BTreeMap<TriggerId, Trigger>,
+        next_fd: RawFd,
+    }
+
+    impl FakeMonitor {
+        fn new() -> Self {
+            FakeMonitor {
+                epoll_fd: epoll::create(true).unwrap(),
+                triggers: BTreeMap::new(),
+                next_fd: 0,
+            }
+        }
+    }
+
+    impl AsRawFd for FakeMonitor {
+        fn as_raw_fd(&self) -> RawFd {
+            self.epoll_fd
+        }
+    }
+
+    impl Drop for FakeMonitor {
+        fn drop(&mut self) {
+            let _ = epoll::close(self.epoll_fd);
+        }
+    }
+
+    impl TriggerMonitor for FakeMonitor {
+        fn add_trigger(&mut self, trigger: Trigger) -> Result<TriggerId> {
+            let id = TriggerId {
+                raw_fd: self.next_fd,
+            };
+            self.next_fd += 1;
+            self.triggers.insert(id, trigger);
+            Ok(id)
+        }
+
+        fn remove_trigger(&mut self, id: TriggerId) -> Result<Trigger> {
+            Ok(self.triggers.remove(&id).unwrap())
+        }
+
+        fn wait_timeout(&mut self, _timeout: Duration) -> Result<Option<PsiEvent>> {
+            Ok(None)
+        }
+    }
+
+    fn template() -> Trigger {
+        Trigger::new_builder()
+            .memory()
+            .some()
+            .stall(Duration::from_millis(100))
+            .window(Duration::from_secs(1))
+            .build()
+    }
+
+    fn rule(pattern: &str) -> WatchRule {
+        WatchRule {
+            pattern: Pattern::new(pattern).unwrap(),
+            template: template(),
+        }
+    }
+
+    /// Watcher of `root` with a rule for its `.service` children
+    fn watcher(root: &Path) -> CgroupWatcher<FakeMonitor> {
+        let mut watcher = CgroupWatcher::with_monitor(root, FakeMonitor::new()).unwrap();
+        let pattern = format!("{}/*.service", root.display());
+        watcher.add_rule(&pattern, template()).unwrap();
+        watcher
+    }
+
+    fn next(watcher: &mut CgroupWatcher<FakeMonitor>) -> Option<WatchEvent> {
+        watcher.wait_timeout(Duration::from_millis(100)).unwrap()
+    }
+
+    fn added(event: Option<WatchEvent>) -> (PathBuf, Vec<TriggerId>) {
+        match event {
+            Some(WatchEvent::CgroupAdded { cgroup, triggers }) => (cgroup, triggers),
+            Some(event) => panic!("unexpected event {}", event),
+            None => panic!("no event"),
+        }
+    }
+
+    fn removed(event: Option<WatchEvent>) -> (PathBuf, Vec<TriggerId>) {
+        match event {
+            Some(WatchEvent::CgroupRemoved { cgroup, triggers }) => (cgroup, triggers),
+            Some(event) => panic!("unexpected event {}", event),
+            None => panic!("no event"),
+        }
+    }
+
+    fn cgroups(watcher: &CgroupWatcher<FakeMonitor>) -> Vec<&Path> {
+        watcher.cgroups().map(|(cgroup, _)| cgroup).collect()
+    }
+
+    #[test]
+    fn should_match_direct_children_only() {
+        let rule = rule("/sys/fs/cgroup/system.slice/*.service");
+        assert!(rule.matches(Path::new("/sys/fs/cgroup/system.slice/sshd.service")));
+        assert!(!rule.matches(Path::new(
+            "/sys/fs/cgroup/system.slice/sshd.service/child.service"
+        )));
+        assert!(!rule.matches(Path::new("/sys/fs/cgroup/system.slice/user.slice")));
+    }
+
+    #[test]
+    fn should_track_added_and_removed_cgroups() {
+        let root = TempDir::new().unwrap();
+        let existing = root.path().join("existing.slice");
+        fs::create_dir(&existing).unwrap();
+        let mut tree = CgroupTree::new(root.path()).unwrap();
+        assert!(tree.paths().any(|path| path == existing));
+
+        // created together, so the child predates the watch on its parent
+        let child = root.path().join("new.slice/app.service");
+        fs::create_dir_all(&child).unwrap();
+        let changes = tree.read_changes().unwrap();
+        assert_eq!(
+            changes,
+            vec![
+                Change::Added(root.path().join("new.slice")),
+                Change::Added(child.clone()),
+            ]
+        );
+
+        fs::remove_dir(&child).unwrap();
+        fs::remove_dir(&existing).unwrap();
+        let changes = tree.read_changes().unwrap();
+        assert_eq!(
+            changes,
+            vec![Change::Removed(child), Change::Removed(existing)]
+        );
+        assert!(tree.read_changes().unwrap().is_empty());
+    }
+
+    #[test]
+    fn should_attach_and_detach_matching_cgroups() {
+        let root = TempDir::new().unwrap();
+        let existing = root.path().join("existing.service");
+        fs::create_dir(&existing).unwrap();
+        let mut watcher = watcher(root.path());
+        let (cgroup, existing_triggers) = added(next(&mut watcher));
+        assert_eq!(cgroup, existing);
+        assert_eq!(existing_triggers.len(), 1);
+
+        let new = root.path().join("new.service");
+        fs::create_dir(&new).unwrap();
+        fs::create_dir(root.path().join("other.slice")).unwrap();
+        let (cgroup, triggers) = added(next(&mut watcher));
+        assert_eq!(cgroup, new);
+        assert_eq!(
+            watcher.monitor_mut().triggers[&triggers[0]].target_file_path,
+            PsiKind::Memory.cgroup_file_path(&new)
+        );
+        assert!(next(&mut watcher).is_none());
+
+        fs::remove_dir(&existing).unwrap();
+        assert_eq!(removed(next(&mut watcher)), (existing, existing_triggers));
+        assert_eq!(watcher.monitor_mut().triggers.len(), 1);
+        assert_eq!(cgroups(&watcher), vec![new.as_path()]);
+    }
+
+    #[test]
+    fn should_reconcile_triggers_after_rescan() {
+        let root = TempDir::new().unwrap();
+        let existing = root.path().join("existing.service");
+        fs::create_dir(&existing).unwrap();
+        let mut watcher = watcher(root.path());
+        added(next(&mut watcher));
+
+        // rescan as on queue overflow, before the events are read
+        let new = root.path().join("new.service");
+        fs::remove_dir(&existing).unwrap();
+        fs::create_dir(&new).unwrap();
+        let mut changes = Vec::new();
+        watcher.tree.rescan(&mut changes).unwrap();
+        watcher.apply_changes(changes).unwrap();
+        assert_eq!(removed(next(&mut watcher)).0, existing);
+        assert_eq!(added(next(&mut watcher)).0, new);
+
+        // the queued events describe changes which were already handled
+        assert!(next(&mut watcher).is_none());
+        assert_eq!(cgroups(&watcher), vec![new.as_path()]);
+        assert_eq!(watcher.monitor_mut().triggers.len(), 1);
+    }
+}
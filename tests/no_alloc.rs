@@ -0,0 +1,44 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fs;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use psi::{PsiKind, PsiLine, PsiReader};
+use tempfile::TempDir;
+
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+#[test]
+fn psi_reader_should_not_allocate() {
+    let dir = TempDir::new().unwrap();
+    fs::write(
+        PsiKind::Memory.cgroup_file_path(dir.path()),
+        "some avg10=0.16 avg60=0.05 avg300=0.01 total=27787674\n\
+         full avg10=0.02 avg60=0.00 avg300=0.00 total=1234567\n",
+    )
+    .unwrap();
+    let reader = PsiReader::cgroup(PsiKind::Memory, dir.path()).unwrap();
+
+    let before = ALLOCATIONS.load(Ordering::SeqCst);
+    for _ in 0..100 {
+        let all = reader.read().unwrap();
+        assert_eq!(all.full.total.as_micros(), 1234567);
+        reader.read_line(PsiLine::Some).unwrap();
+    }
+    assert_eq!(ALLOCATIONS.load(Ordering::SeqCst), before);
+}